@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use gskits::gsbam::{
+    bam_record_ext::BamRecord,
+    cigar_ext::parse_cigar_string,
+    plp_counts_from_records::PlpCnts,
+    query_locus_blacklist_gen::{
+        get_query_locus_blacklist_intervals, LongInsBlacklist, LowIdentityBlacklist,
+        TQueryLocusBlacklist,
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// only get_blacklist_locus is implemented, so the locus go through a HashSet
+/// like the blacklists before QueryLocusIntervals
+struct HashSetBlacklist(Box<dyn TQueryLocusBlacklist>);
+
+impl TQueryLocusBlacklist for HashSetBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.0.get_blacklist_locus(record)
+    }
+}
+
+fn build_blacklist_gen() -> Vec<Box<dyn TQueryLocusBlacklist>> {
+    vec![
+        Box::new(LongInsBlacklist::new(10)),
+        Box::new(LowIdentityBlacklist::new(0.95, 20, 10)),
+    ]
+}
+
+/// long reads with ~10% errors and some long insertions
+fn build_records(n_records: usize, ref_len: usize) -> Vec<BamRecord> {
+    let mut rng = StdRng::seed_from_u64(35);
+    (0..n_records)
+        .map(|_| {
+            let mut cigar = String::new();
+            let mut qlen = 0;
+            let mut rlen = 0;
+            while rlen < ref_len {
+                let n = rng.gen_range(5..30).min(ref_len - rlen);
+                cigar.push_str(&format!("{}=", n));
+                qlen += n;
+                rlen += n;
+                match rng.gen_range(0..10) {
+                    0 => {
+                        cigar.push_str("1X");
+                        qlen += 1;
+                        rlen += 1;
+                    }
+                    1 => {
+                        let n = if rng.gen_bool(0.05) { 20 } else { 1 };
+                        cigar.push_str(&format!("{}I", n));
+                        qlen += n;
+                    }
+                    2 => {
+                        cigar.push_str("1D");
+                        rlen += 1;
+                    }
+                    _ => {}
+                }
+                // make sure the alignment ends with a match
+                if rlen >= ref_len {
+                    cigar.push_str("1=");
+                    qlen += 1;
+                }
+            }
+            let seq = (0..qlen)
+                .map(|_| b"ACGT"[rng.gen_range(0..4)])
+                .collect::<Vec<_>>();
+            let mut record = BamRecord::new();
+            record.set(
+                b"qname",
+                Some(&parse_cigar_string(&cigar).unwrap()),
+                &seq,
+                &vec![30; seq.len()],
+            );
+            record.set_pos(0);
+            record.unset_unmapped();
+            record
+        })
+        .collect()
+}
+
+fn plp_with_blacklist_benchmark(c: &mut Criterion) {
+    let records = build_records(20, 20000);
+    let blacklist_gen = build_blacklist_gen();
+    let hashset_blacklist_gen = build_blacklist_gen()
+        .into_iter()
+        .map(|v| Box::new(HashSetBlacklist(v)) as Box<dyn TQueryLocusBlacklist>)
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("plp_with_blacklist");
+    group.sample_size(10);
+    group.bench_function("no_blacklist", |b| {
+        b.iter(|| PlpCnts::from_records(black_box(&records), None, None, None))
+    });
+    group.bench_function("blacklist", |b| {
+        b.iter(|| PlpCnts::from_records(black_box(&records), None, None, Some(&blacklist_gen)))
+    });
+    group.bench_function("blacklist_hashset", |b| {
+        b.iter(|| {
+            PlpCnts::from_records(black_box(&records), None, None, Some(&hashset_blacklist_gen))
+        })
+    });
+    group.finish();
+
+    // membership queries of every query position, HashSet vs interval cursor
+    let mut group = c.benchmark_group("blacklist_lookup");
+    group.sample_size(10);
+    group.bench_function("hashset", |b| {
+        b.iter(|| {
+            let mut n = 0;
+            for record in &records {
+                let locus = blacklist_gen
+                    .iter()
+                    .flat_map(|gen| gen.get_blacklist_locus(record))
+                    .collect::<HashSet<_>>();
+                n += (0..record.seq_len()).filter(|pos| locus.contains(pos)).count();
+            }
+            n
+        })
+    });
+    group.bench_function("intervals", |b| {
+        b.iter(|| {
+            let mut n = 0;
+            for record in &records {
+                let intervals = get_query_locus_blacklist_intervals(record, Some(&blacklist_gen));
+                let mut cursor = intervals.cursor();
+                n += (0..record.seq_len()).filter(|&pos| cursor.contains(pos)).count();
+            }
+            n
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, plp_with_blacklist_benchmark);
+criterion_main!(benches);
@@ -1,25 +1,51 @@
-use std::mem::MaybeUninit;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    hash::{BuildHasherDefault, Hasher},
+    mem::MaybeUninit,
+    thread,
+};
+
+/// IUPAC complement, case preserved. U is complemented to A, use RNA_COMPLEMENT_TABLE for RNA.
+/// - and * are kept. any other byte is 0
+pub static COMPLEMENT_TABLE: [u8; 256] = complement_table(false);
+
+/// same as COMPLEMENT_TABLE but A is complemented to U
+pub static RNA_COMPLEMENT_TABLE: [u8; 256] = complement_table(true);
+
+const IUPAC_PAIRS: [(u8, u8); 15] = [
+    (b'A', b'T'),
+    (b'T', b'A'),
+    (b'U', b'A'),
+    (b'C', b'G'),
+    (b'G', b'C'),
+    (b'R', b'Y'),
+    (b'Y', b'R'),
+    (b'K', b'M'),
+    (b'M', b'K'),
+    (b'S', b'S'),
+    (b'W', b'W'),
+    (b'B', b'V'),
+    (b'V', b'B'),
+    (b'D', b'H'),
+    (b'H', b'D'),
+];
 
-pub static COMPLEMENT_TABLE: [u8; 256] = {
+const fn complement_table(rna: bool) -> [u8; 256] {
     let mut table = [0u8; 256];
-    table[b'A' as usize] = b'T';
-    table[b'T' as usize] = b'A';
-    table[b'C' as usize] = b'G';
-    table[b'G' as usize] = b'C';
-    table[b'-' as usize] = b'-';
-    table[b'*' as usize] = b'*';
+    let mut i = 0;
+    while i < IUPAC_PAIRS.len() {
+        let (base, complement) = IUPAC_PAIRS[i];
+        let complement = if rna && complement == b'T' { b'U' } else { complement };
+        table[base as usize] = complement;
+        table[base.to_ascii_lowercase() as usize] = complement.to_ascii_lowercase();
+        i += 1;
+    }
     table[b'N' as usize] = b'N';
-
-    table[b'a' as usize] = b't';
-    table[b't' as usize] = b'a';
-    table[b'c' as usize] = b'g';
-    table[b'g' as usize] = b'c';
+    table[b'n' as usize] = b'n';
     table[b'-' as usize] = b'-';
     table[b'*' as usize] = b'*';
-    table[b'n' as usize] = b'n';
-
     table
-};
+}
 
 /// acgt ACGT 0 1 2 3
 pub const SEQ_NT4_TABLE: [u8; 256] = [
@@ -46,9 +72,503 @@ pub fn reverse_complement(dna: &[u8]) -> Vec<u8> {
     unsafe { std::mem::transmute(result) }
 }
 
+/// in place version of reverse_complement
+pub fn reverse_complement_mut(dna: &mut [u8]) {
+    reverse_complement_mut_with(dna, &COMPLEMENT_TABLE);
+}
+
+/// b"ACGUAA" -> b"UUACGU"
+pub fn reverse_complement_rna(rna: &[u8]) -> Vec<u8> {
+    rna.iter().rev().map(|&base| RNA_COMPLEMENT_TABLE[base as usize]).collect()
+}
+
+pub fn reverse_complement_rna_mut(rna: &mut [u8]) {
+    reverse_complement_mut_with(rna, &RNA_COMPLEMENT_TABLE);
+}
+
+fn reverse_complement_mut_with(seq: &mut [u8], table: &[u8; 256]) {
+    seq.reverse();
+    seq.iter_mut().for_each(|base| *base = table[*base as usize]);
+}
+
+/// reverse_complement, but fails on the bytes that are not IUPAC codes or - *
+pub fn try_reverse_complement(dna: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let invalid = dna
+        .iter()
+        .enumerate()
+        .filter(|(_, &base)| COMPLEMENT_TABLE[base as usize] == 0)
+        .map(|(idx, &base)| (idx, base))
+        .collect::<Vec<_>>();
+    if !invalid.is_empty() {
+        anyhow::bail!("can't complement {}", describe_invalid_bases(&invalid));
+    }
+    Ok(reverse_complement(dna))
+}
+
+/// T -> U, case preserved
+pub fn dna_to_rna(dna: &[u8]) -> Vec<u8> {
+    dna.iter()
+        .map(|&base| match base {
+            b'T' => b'U',
+            b't' => b'u',
+            _ => base,
+        })
+        .collect()
+}
+
+/// U -> T, case preserved
+pub fn rna_to_dna(rna: &[u8]) -> Vec<u8> {
+    rna.iter()
+        .map(|&base| match base {
+            b'U' => b'T',
+            b'u' => b't',
+            _ => base,
+        })
+        .collect()
+}
+
+/// IUPAC nucleotide code, U included, either case. gaps are not
+pub fn is_iupac_base(base: u8) -> bool {
+    matches!(
+        base.to_ascii_uppercase(),
+        b'A' | b'C'
+            | b'G'
+            | b'T'
+            | b'U'
+            | b'R'
+            | b'Y'
+            | b'K'
+            | b'M'
+            | b'S'
+            | b'W'
+            | b'B'
+            | b'D'
+            | b'H'
+            | b'V'
+            | b'N'
+    )
+}
+
+/// (position, byte) of the bytes that are not IUPAC nucleotide codes
+pub fn non_iupac_bases(seq: &[u8]) -> Vec<(usize, u8)> {
+    seq.iter()
+        .enumerate()
+        .filter(|(_, &base)| !is_iupac_base(base))
+        .map(|(idx, &base)| (idx, base))
+        .collect()
+}
+
+/// Err lists the first few non IUPAC bytes
+pub fn validate_iupac(seq: &[u8]) -> anyhow::Result<()> {
+    let invalid = non_iupac_bases(seq);
+    if !invalid.is_empty() {
+        anyhow::bail!("non IUPAC {}", describe_invalid_bases(&invalid));
+    }
+    Ok(())
+}
+
+/// Err if any byte is not ACGT (either case)
+pub fn validate_acgt(seq: &[u8]) -> anyhow::Result<()> {
+    let invalid = seq
+        .iter()
+        .enumerate()
+        .filter(|(_, &base)| !matches!(base.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T'))
+        .map(|(idx, &base)| (idx, base))
+        .collect::<Vec<_>>();
+    if !invalid.is_empty() {
+        anyhow::bail!("non ACGT {}", describe_invalid_bases(&invalid));
+    }
+    Ok(())
+}
+
+fn describe_invalid_bases(invalid: &[(usize, u8)]) -> String {
+    let shown = invalid
+        .iter()
+        .take(5)
+        .map(|(idx, base)| format!("{:?}@{}", *base as char, idx))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let more = if invalid.len() > 5 { ", ..." } else { "" };
+    format!("bases ({} in total): {}{}", invalid.len(), shown, more)
+}
+
+/// 2-bit code -> base
+pub const NT4_BASES: [u8; 4] = *b"ACGT";
+
+/// SEQ_NT4_TABLE code of ACGT (either case), 4 for any other byte.
+/// SEQ_NT4_TABLE also maps the raw bytes 0..=3 to themselves, they are N here
+#[inline]
+pub fn nt4_code(base: u8) -> u8 {
+    match base {
+        b'A' | b'a' | b'C' | b'c' | b'G' | b'g' | b'T' | b't' => SEQ_NT4_TABLE[base as usize],
+        _ => 4,
+    }
+}
+
+/// mask of the low 2k bits
+pub fn kmer_mask(k: usize) -> u64 {
+    if k >= 32 {
+        u64::MAX
+    } else {
+        (1u64 << (2 * k)) - 1
+    }
+}
+
+/// invertible integer hash (Thomas Wang, as in minimap2). mask: kmer_mask(k)
+pub fn hash64(key: u64, mask: u64) -> u64 {
+    let mut key = (!key).wrapping_add(key << 21) & mask;
+    key ^= key >> 24;
+    key = (key.wrapping_add(key << 3)).wrapping_add(key << 8) & mask;
+    key ^= key >> 14;
+    key = (key.wrapping_add(key << 2)).wrapping_add(key << 4) & mask;
+    key ^= key >> 28;
+    key = key.wrapping_add(key << 31) & mask;
+    key
+}
+
+/// 2-bit code of an ACGT k-mer, first base in the high bits. None if k > 32 or any base not ACGT
+pub fn encode_kmer(kmer: &[u8]) -> Option<u64> {
+    if kmer.len() > 32 {
+        return None;
+    }
+    kmer.iter().try_fold(0u64, |acc, &base| {
+        let code = nt4_code(base);
+        (code < 4).then_some((acc << 2) | code as u64)
+    })
+}
+
+/// inverse of encode_kmer, upper case
+pub fn decode_kmer(kmer: u64, k: usize) -> Vec<u8> {
+    (0..k)
+        .map(|i| NT4_BASES[((kmer >> (2 * (k - 1 - i))) & 3) as usize])
+        .collect()
+}
+
+/// forward k-mers of an ASCII sequence, k in 1..=32.
+/// yields (start, 2-bit code of the k-mer). k-mers containing non ACGT bases are skipped
+pub struct ForwardKmerIter<'a> {
+    seq: &'a [u8],
+    k: usize,
+    mask: u64,
+    pos: usize,
+    kmer: u64,
+    /// number of valid bases at the end of kmer
+    valid: usize,
+}
+
+impl<'a> ForwardKmerIter<'a> {
+    pub fn new(seq: &'a [u8], k: usize) -> Self {
+        assert!((1..=32).contains(&k), "k must be in 1..=32, got {}", k);
+        Self {
+            seq,
+            k,
+            mask: kmer_mask(k),
+            pos: 0,
+            kmer: 0,
+            valid: 0,
+        }
+    }
+
+    /// push the next ACGT base into kmer, returns its position and code.
+    /// a non ACGT base resets the k-mer
+    fn roll(&mut self) -> Option<(usize, u64)> {
+        while self.pos < self.seq.len() {
+            let pos = self.pos;
+            self.pos += 1;
+            let code = nt4_code(self.seq[pos]) as u64;
+            if code > 3 {
+                self.valid = 0;
+                continue;
+            }
+            self.kmer = ((self.kmer << 2) | code) & self.mask;
+            self.valid += 1;
+            return Some((pos, code));
+        }
+        None
+    }
+}
+
+impl Iterator for ForwardKmerIter<'_> {
+    type Item = (usize, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((pos, _)) = self.roll() {
+            if self.valid >= self.k {
+                return Some((pos + 1 - self.k, self.kmer));
+            }
+        }
+        None
+    }
+}
+
+pub fn kmers(seq: &[u8], k: usize) -> ForwardKmerIter<'_> {
+    ForwardKmerIter::new(seq, k)
+}
+
+/// canonical k-mers of an ASCII sequence, k in 1..=32.
+/// yields (start, min(forward, reverse complement), reverse) where reverse is true if the
+/// reverse complement is the smaller one. k-mers containing non ACGT bases are skipped
+pub struct CanonicalKmerIter<'a> {
+    fwd: ForwardKmerIter<'a>,
+    shift: usize,
+    rev: u64,
+}
+
+impl<'a> CanonicalKmerIter<'a> {
+    pub fn new(seq: &'a [u8], k: usize) -> Self {
+        Self {
+            fwd: ForwardKmerIter::new(seq, k),
+            shift: 2 * (k - 1),
+            rev: 0,
+        }
+    }
+}
+
+impl Iterator for CanonicalKmerIter<'_> {
+    type Item = (usize, u64, bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let k = self.fwd.k;
+        while let Some((pos, code)) = self.fwd.roll() {
+            self.rev = (self.rev >> 2) | ((3 - code) << self.shift);
+            if self.fwd.valid >= k {
+                let (start, fwd) = (pos + 1 - k, self.fwd.kmer);
+                return Some(if self.rev < fwd {
+                    (start, self.rev, true)
+                } else {
+                    (start, fwd, false)
+                });
+            }
+        }
+        None
+    }
+}
+
+pub fn canonical_kmers(seq: &[u8], k: usize) -> CanonicalKmerIter<'_> {
+    CanonicalKmerIter::new(seq, k)
+}
+
+/// k-mer codes are mixed with hash64 before hashing, so the std HashMap can skip SipHash
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KmerHasher(u64);
+
+impl Hasher for KmerHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        bytes
+            .iter()
+            .for_each(|&b| self.0 = hash64(self.0 ^ b as u64, u64::MAX));
+    }
+
+    fn write_u64(&mut self, key: u64) {
+        self.0 = hash64(key, u64::MAX);
+    }
+}
+
+pub type KmerHashMap<V> = HashMap<u64, V, BuildHasherDefault<KmerHasher>>;
+
+/// k-mer code -> count
+#[derive(Debug, Clone)]
+pub struct KmerCounter {
+    pub k: usize,
+    /// count the canonical k-mers, so a k-mer and its reverse complement share a count
+    pub canonical: bool,
+    counts: KmerHashMap<u64>,
+}
+
+impl KmerCounter {
+    pub fn new(k: usize, canonical: bool) -> Self {
+        assert!((1..=32).contains(&k), "k must be in 1..=32, got {}", k);
+        Self {
+            k,
+            canonical,
+            counts: KmerHashMap::default(),
+        }
+    }
+
+    pub fn add_seq(&mut self, seq: &[u8]) {
+        if self.canonical {
+            canonical_kmers(seq, self.k).for_each(|(_, kmer, _)| self.increment(kmer));
+        } else {
+            kmers(seq, self.k).for_each(|(_, kmer)| self.increment(kmer));
+        }
+    }
+
+    pub fn increment(&mut self, kmer: u64) {
+        *self.counts.entry(kmer).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &KmerCounter) {
+        assert_eq!((self.k, self.canonical), (other.k, other.canonical));
+        other
+            .counts
+            .iter()
+            .for_each(|(kmer, cnt)| *self.counts.entry(*kmer).or_insert(0) += cnt);
+    }
+
+    pub fn get(&self, kmer: u64) -> u64 {
+        *self.counts.get(&kmer).unwrap_or(&0)
+    }
+
+    /// count of an ASCII k-mer, canonicalized if the counter is. 0 if it can't be encoded
+    pub fn get_ascii(&self, kmer: &[u8]) -> u64 {
+        if kmer.len() != self.k {
+            return 0;
+        }
+        let code = if self.canonical {
+            canonical_kmers(kmer, self.k).next().map(|(_, code, _)| code)
+        } else {
+            encode_kmer(kmer)
+        };
+        code.map(|code| self.get(code)).unwrap_or(0)
+    }
+
+    /// number of distinct k-mers
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// number of k-mers counted
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.counts.iter().map(|(kmer, cnt)| (*kmer, *cnt))
+    }
+
+    /// k-mer spectrum. count -> number of distinct k-mers with that count
+    pub fn spectrum(&self) -> BTreeMap<u64, usize> {
+        let mut res = BTreeMap::new();
+        self.counts
+            .values()
+            .for_each(|cnt| *res.entry(*cnt).or_insert(0) += 1);
+        res
+    }
+
+    /// the n most frequent k-mers, ties broken by k-mer code
+    pub fn most_common(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut res = self.iter().collect::<Vec<_>>();
+        res.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        res.truncate(n);
+        res
+    }
+}
+
+/// count the k-mers of seqs with `threads` threads, each counting a chunk of seqs
+pub fn count_kmers<S>(seqs: &[S], k: usize, canonical: bool, threads: usize) -> KmerCounter
+where
+    S: AsRef<[u8]> + Sync,
+{
+    let threads = threads.clamp(1, seqs.len().max(1));
+    let chunk_size = seqs.len().div_ceil(threads).max(1);
+    let mut counters = thread::scope(|s| {
+        let handles = seqs
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    let mut counter = KmerCounter::new(k, canonical);
+                    chunk.iter().for_each(|seq| counter.add_seq(seq.as_ref()));
+                    counter
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    // merge into the largest one
+    counters.sort_by_key(|counter| std::cmp::Reverse(counter.len()));
+    let mut counters = counters.into_iter();
+    let mut res = counters
+        .next()
+        .unwrap_or_else(|| KmerCounter::new(k, canonical));
+    counters.for_each(|counter| res.merge(&counter));
+    res
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Minimizer {
+    /// hash64 of the canonical k-mer
+    pub hash: u64,
+    /// canonical k-mer code
+    pub kmer: u64,
+    /// start of the k-mer in the sequence
+    pub pos: usize,
+    /// the k-mer in the sequence is the reverse complement of the canonical one
+    pub reverse: bool,
+}
+
+/// (w,k)-minimizers: the canonical k-mer with the smallest hash in every w consecutive k-mers,
+/// leftmost on ties. each minimizer is reported once, sorted by pos.
+/// a non ACGT base restarts the windows; a stretch shorter than w + k - 1 reports its minimum
+pub fn minimizers(seq: &[u8], w: usize, k: usize) -> Vec<Minimizer> {
+    assert!(w > 0, "w must be > 0");
+    let mask = kmer_mask(k);
+    let mut res: Vec<Minimizer> = vec![];
+    let mut window: VecDeque<(usize, Minimizer)> = VecDeque::new();
+    // (index of the k-mer in the current stretch, end of the previous k-mer)
+    let mut idx = 0;
+    let mut prev_end = usize::MAX;
+
+    let flush_partial = |window: &VecDeque<(usize, Minimizer)>, idx: usize, res: &mut Vec<_>| {
+        if idx > 0 && idx < w {
+            res.push(window.front().unwrap().1);
+        }
+    };
+
+    for (pos, kmer, reverse) in canonical_kmers(seq, k) {
+        if prev_end != pos + k - 1 {
+            flush_partial(&window, idx, &mut res);
+            window.clear();
+            idx = 0;
+        }
+        prev_end = pos + k;
+
+        let cur = Minimizer {
+            hash: hash64(kmer, mask),
+            kmer,
+            pos,
+            reverse,
+        };
+        while window.back().is_some_and(|(_, m)| m.hash > cur.hash) {
+            window.pop_back();
+        }
+        window.push_back((idx, cur));
+        while window.front().is_some_and(|(i, _)| i + w <= idx) {
+            window.pop_front();
+        }
+        if idx + 1 >= w {
+            let m = window.front().unwrap().1;
+            if res.last().is_none_or(|last| last.pos != m.pos) {
+                res.push(m);
+            }
+        }
+        idx += 1;
+    }
+    flush_partial(&window, idx, &mut res);
+    res
+}
+
 #[cfg(test)]
 mod test {
-    use crate::dna::reverse_complement;
+    use crate::dna::{
+        canonical_kmers, count_kmers, decode_kmer, dna_to_rna, encode_kmer, hash64, kmer_mask,
+        kmers, minimizers, non_iupac_bases, reverse_complement, reverse_complement_mut,
+        reverse_complement_rna, rna_to_dna, try_reverse_complement, validate_acgt, validate_iupac,
+        KmerCounter, COMPLEMENT_TABLE,
+    };
+    use crate::packed_seq::PackedSeq;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     #[test]
     fn test_reverse_complement() {
@@ -62,4 +582,167 @@ mod test {
         let iupac = bio::alphabets::dna::iupac_alphabet();
         println!("{:?}", iupac);
     }
+
+    #[test]
+    fn test_iupac_complement() {
+        assert_eq!(reverse_complement(b"ACGTRYKMSWBDHVN"), b"NBDHVWSKMRYACGT");
+        assert_eq!(reverse_complement(b"acgtrykmswbdhvn-*"), b"*-nbdhvwskmryacgt");
+        assert_eq!(reverse_complement(b"ACGU"), b"ACGT");
+        assert_eq!(reverse_complement_rna(b"ACGUAA"), b"UUACGU");
+        assert_eq!(COMPLEMENT_TABLE[b'X' as usize], 0);
+
+        for seq in [&b""[..], b"A", b"ACGTn", b"RYKMswbdhv"] {
+            let mut inplace = seq.to_vec();
+            reverse_complement_mut(&mut inplace);
+            assert_eq!(inplace, reverse_complement(seq));
+            reverse_complement_mut(&mut inplace);
+            assert_eq!(inplace, seq);
+        }
+
+        assert_eq!(try_reverse_complement(b"ACR").unwrap(), b"YGT");
+        let err = try_reverse_complement(b"ACXGZ").unwrap_err().to_string();
+        assert!(err.contains("'X'@2, 'Z'@4"), "{}", err);
+
+        assert_eq!(dna_to_rna(b"ACGTt"), b"ACGUu");
+        assert_eq!(rna_to_dna(b"ACGUu"), b"ACGTt");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate_iupac(b"ACGTUNRYacgtunry").is_ok());
+        assert_eq!(non_iupac_bases(b"AC-GE T"), vec![(2, b'-'), (4, b'E'), (5, b' ')]);
+        let err = validate_iupac(b"XXXXXXXA").unwrap_err().to_string();
+        assert!(err.contains("(7 in total)") && err.ends_with(", ..."), "{}", err);
+
+        assert!(validate_acgt(b"ACGTacgt").is_ok());
+        assert!(validate_acgt(b"ACGTN").is_err());
+        // SEQ_NT4_TABLE maps the raw bytes 0..=3 to codes, they are still not ACGT
+        assert!(validate_acgt(&[0]).is_err());
+    }
+
+    fn random_seq(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect()
+    }
+
+    #[test]
+    fn test_canonical_kmers() {
+        assert_eq!(encode_kmer(b"ACGT"), Some(0b00_01_10_11));
+        assert_eq!(encode_kmer(b"ACNT"), None);
+        assert_eq!(decode_kmer(0b00_01_10_11, 4), b"ACGT");
+        // the raw bytes 0..=3 are N, as in PackedSeq
+        assert_eq!(encode_kmer(&[0]), None);
+        let raw = [0, 1, 2, b'A', b'C', b'G'];
+        assert_eq!(
+            kmers(&raw, 3).collect::<Vec<_>>(),
+            PackedSeq::from_ascii(&raw).kmers(3).collect::<Vec<_>>()
+        );
+        assert_eq!(kmers(&raw, 3).count(), 1);
+
+        // GGA < TCC: reverse. ACN skipped
+        let canonical = canonical_kmers(b"TCCANACGA", 3).collect::<Vec<_>>();
+        assert_eq!(
+            canonical,
+            vec![
+                (0, encode_kmer(b"GGA").unwrap(), true),
+                (1, encode_kmer(b"CCA").unwrap(), false),
+                (5, encode_kmer(b"ACG").unwrap(), false),
+                (6, encode_kmer(b"CGA").unwrap(), false),
+            ]
+        );
+        let fwd = kmers(b"TCCANACGA", 3).collect::<Vec<_>>();
+        assert_eq!(
+            fwd,
+            vec![
+                (0, encode_kmer(b"TCC").unwrap()),
+                (1, encode_kmer(b"CCA").unwrap()),
+                (5, encode_kmer(b"ACG").unwrap()),
+                (6, encode_kmer(b"CGA").unwrap()),
+            ]
+        );
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for k in [1, 5, 31, 32] {
+            let seq = random_seq(&mut rng, 100);
+            for (pos, kmer, reverse) in canonical_kmers(&seq, k) {
+                let fwd = &seq[pos..pos + k];
+                let rc = reverse_complement(fwd);
+                let expected = if reverse { &rc[..] } else { fwd };
+                assert_eq!(decode_kmer(kmer, k), expected);
+                assert!(decode_kmer(kmer, k) <= fwd.to_vec().max(rc.clone()));
+                assert_eq!(encode_kmer(fwd).min(encode_kmer(&rc)), Some(kmer));
+            }
+            let expected = (0..=seq.len() - k)
+                .map(|pos| (pos, encode_kmer(&seq[pos..pos + k]).unwrap()))
+                .collect::<Vec<_>>();
+            assert_eq!(kmers(&seq, k).collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn test_count_kmers() {
+        let mut counter = KmerCounter::new(2, false);
+        counter.add_seq(b"AAAC");
+        assert_eq!(counter.get_ascii(b"AA"), 2);
+        assert_eq!(counter.get_ascii(b"GT"), 0);
+
+        let mut counter = KmerCounter::new(2, true);
+        counter.add_seq(b"AAACNTT");
+        // TT is AA on the other strand
+        assert_eq!(counter.get_ascii(b"TT"), 3);
+        assert_eq!(counter.get_ascii(b"GT"), 1);
+        assert_eq!((counter.len(), counter.total()), (2, 4));
+        assert_eq!(counter.spectrum().into_iter().collect::<Vec<_>>(), vec![(1, 1), (3, 1)]);
+        assert_eq!(counter.most_common(1), vec![(encode_kmer(b"AA").unwrap(), 3)]);
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let seqs = (0..37)
+            .map(|_| random_seq(&mut rng, 200))
+            .collect::<Vec<_>>();
+        let mut single = KmerCounter::new(5, true);
+        seqs.iter().for_each(|seq| single.add_seq(seq));
+        for threads in [1, 4, 100] {
+            let counter = count_kmers(&seqs, 5, true, threads);
+            assert_eq!(counter.total(), 37 * 196);
+            assert_eq!(counter.spectrum(), single.spectrum());
+            assert!(single.iter().all(|(kmer, cnt)| counter.get(kmer) == cnt));
+        }
+        assert!(count_kmers::<Vec<u8>>(&[], 5, true, 4).is_empty());
+    }
+
+    #[test]
+    fn test_minimizers() {
+        let mut rng = StdRng::seed_from_u64(13);
+        for (w, k) in [(1, 5), (5, 7), (10, 15)] {
+            let seq = random_seq(&mut rng, 300);
+            let kmers = canonical_kmers(&seq, k).collect::<Vec<_>>();
+            let mut expected: Vec<usize> = vec![];
+            for window in kmers.windows(w) {
+                let min = window
+                    .iter()
+                    .min_by_key(|(pos, kmer, _)| (hash64(*kmer, kmer_mask(k)), *pos))
+                    .unwrap();
+                if expected.last() != Some(&min.0) {
+                    expected.push(min.0);
+                }
+            }
+            let res = minimizers(&seq, w, k);
+            assert_eq!(res.iter().map(|m| m.pos).collect::<Vec<_>>(), expected);
+            for m in &res {
+                let (_, kmer, reverse) = kmers[m.pos];
+                assert_eq!((m.kmer, m.reverse), (kmer, reverse));
+            }
+            // same minimizers on the other strand
+            let rc = minimizers(&reverse_complement(&seq), w, k);
+            let mut rc_hashes = rc.iter().map(|m| m.hash).collect::<Vec<_>>();
+            let mut hashes = res.iter().map(|m| m.hash).collect::<Vec<_>>();
+            rc_hashes.sort();
+            hashes.sort();
+            assert_eq!(hashes, rc_hashes);
+        }
+
+        // the stretch after N is shorter than a window, its minimum is reported
+        let res = minimizers(b"ACGTACGTACNTTGA", 4, 3);
+        assert!(res.last().unwrap().pos >= 11);
+        assert!(minimizers(b"ACNAC", 4, 3).is_empty());
+    }
 }
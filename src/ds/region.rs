@@ -1,6 +1,16 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    ops::{Deref, DerefMut},
+    str::FromStr,
+};
 
-#[derive(Debug, Clone)]
+use anyhow::{bail, Context};
+use rust_htslib::bam::{FetchDefinition, HeaderView};
+
+use crate::{gsbam::bam_header_ext::HeaderSQ, itertools::sliding_window};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Region {
     start: usize,
     end: usize,
@@ -13,6 +23,12 @@ impl Region {
     pub fn length(&self) -> usize {
         self.end - self.start
     }
+    pub fn start(&self) -> usize {
+        self.start
+    }
+    pub fn end(&self) -> usize {
+        self.end
+    }
 }
 
 pub struct Regions(Vec<Region>);
@@ -132,9 +148,624 @@ impl Regions {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Strand {
+    Forward,
+    Reverse,
+    #[default]
+    Unknown,
+}
+
+impl FromStr for Strand {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "+" => Ok(Strand::Forward),
+            "-" => Ok(Strand::Reverse),
+            "." | "?" => Ok(Strand::Unknown),
+            _ => anyhow::bail!("invalid strand '{}', expected one of +, -, .", s),
+        }
+    }
+}
+
+impl Display for Strand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+            Strand::Unknown => ".",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// a region on a contig. 0-based half open, end == usize::MAX means up to the contig end
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GenomicInterval {
+    pub contig: String,
+    pub start: usize,
+    pub end: usize,
+    pub strand: Strand,
+}
+
+impl GenomicInterval {
+    pub fn new(contig: &str, start: usize, end: usize, strand: Strand) -> Self {
+        assert!(start <= end, "invalid interval {}:{}-{}", contig, start, end);
+        Self {
+            contig: contig.to_string(),
+            start,
+            end,
+            strand,
+        }
+    }
+
+    pub fn whole_contig(contig: &str) -> Self {
+        Self::new(contig, 0, usize::MAX, Strand::Unknown)
+    }
+
+    pub fn from_region(contig: &str, region: &Region, strand: Strand) -> Self {
+        Self::new(contig, region.start, region.end, strand)
+    }
+
+    pub fn region(&self) -> Region {
+        Region::new(self.start, self.end)
+    }
+
+    pub fn length(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// half open overlap, a zero length interval overlaps [s, e) if s <= start < e
+    pub fn overlaps(&self, other: &GenomicInterval) -> bool {
+        self.contig == other.contig
+            && self.start < other.end.max(other.start + 1)
+            && other.start < self.end.max(self.start + 1)
+    }
+
+    pub fn contains(&self, contig: &str, pos: usize) -> bool {
+        self.contig == contig && self.start <= pos && pos < self.end
+    }
+
+    /// samtools style region, 1-based inclusive. e.g. chr1:1,000-2,000 chr1:1000 chr1:1000- chr1
+    /// an optional strand suffix (:+ / :-) is accepted
+    pub fn parse_one_based(s: &str) -> anyhow::Result<Self> {
+        let (contig, range, strand) = split_region_str(s)?;
+        let (start, end) = match range {
+            None => (0, usize::MAX),
+            Some((start, end)) => {
+                if start == 0 {
+                    bail!("invalid region '{}', 1-based start must be >= 1", s);
+                }
+                (start - 1, end.unwrap_or(start))
+            }
+        };
+        if start > end {
+            bail!("invalid region '{}', start > end", s);
+        }
+        Ok(Self::new(contig, start, end, strand))
+    }
+
+    /// same syntax as parse_one_based, but 0-based half open. chr1:1000 means [1000, 1001)
+    pub fn parse_zero_based(s: &str) -> anyhow::Result<Self> {
+        let (contig, range, strand) = split_region_str(s)?;
+        let (start, end) = match range {
+            None => (0, usize::MAX),
+            Some((start, end)) => (start, end.unwrap_or(start + 1)),
+        };
+        if start > end {
+            bail!("invalid region '{}', start > end", s);
+        }
+        Ok(Self::new(contig, start, end, strand))
+    }
+
+    pub fn to_zero_based_string(&self) -> String {
+        if self.end == usize::MAX {
+            format!("{}:{}-", self.contig, self.start)
+        } else {
+            format!("{}:{}-{}", self.contig, self.start, self.end)
+        }
+    }
+
+    /// fetch by contig name, for IndexedReader::fetch
+    pub fn to_fetch_definition(&self) -> FetchDefinition<'_> {
+        FetchDefinition::RegionString(
+            self.contig.as_bytes(),
+            self.start as i64,
+            self.end.min(i64::MAX as usize) as i64,
+        )
+    }
+
+    /// fetch by tid, None if the contig is not in the header
+    pub fn to_tid_fetch_definition(&self, header: &HeaderView) -> Option<FetchDefinition<'static>> {
+        header.tid(self.contig.as_bytes()).map(|tid| {
+            FetchDefinition::Region(
+                tid as i32,
+                self.start as i64,
+                self.end.min(i64::MAX as usize) as i64,
+            )
+        })
+    }
+}
+
+/// (start, end) as written in a region string
+type PosRange = (usize, Option<usize>);
+
+/// contig[:start[-[end]]][:strand]. the range is returned as written, commas removed
+fn split_region_str(s: &str) -> anyhow::Result<(&str, Option<PosRange>, Strand)> {
+    let s = s.trim();
+    let (s, strand) = match s.rsplit_once(':') {
+        Some((head, tail)) if matches!(tail, "+" | "-" | ".") => (head, tail.parse()?),
+        _ => (s, Strand::Unknown),
+    };
+
+    let parse_pos = |v: &str| -> anyhow::Result<usize> {
+        v.replace(',', "")
+            .parse::<usize>()
+            .with_context(|| format!("invalid position '{}' in region '{}'", v, s))
+    };
+
+    // contig names may contain ':', so only a trailing numeric range is taken as range
+    let (contig, range) = match s.rsplit_once(':') {
+        Some((contig, range))
+            if !range.is_empty()
+                && range.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '-') =>
+        {
+            let range = match range.split_once('-') {
+                None => (parse_pos(range)?, None),
+                Some((start, "")) => (parse_pos(start)?, Some(usize::MAX)),
+                Some((start, end)) => (parse_pos(start)?, Some(parse_pos(end)?)),
+            };
+            (contig, Some(range))
+        }
+        _ => (s, None),
+    };
+    if contig.is_empty() {
+        bail!("invalid region '{}', empty contig", s);
+    }
+    Ok((contig, range, strand))
+}
+
+impl FromStr for GenomicInterval {
+    type Err = anyhow::Error;
+    /// samtools style, 1-based inclusive
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        GenomicInterval::parse_one_based(s)
+    }
+}
+
+impl Display for GenomicInterval {
+    /// samtools style, 1-based inclusive
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.end == usize::MAX {
+            write!(f, "{}:{}-", self.contig, self.start + 1)?;
+        } else {
+            write!(f, "{}:{}-{}", self.contig, self.start + 1, self.end)?;
+        }
+        if self.strand != Strand::Unknown {
+            write!(f, ":{}", self.strand)?;
+        }
+        Ok(())
+    }
+}
+
+/// intervals sorted by (contig, start, end), with binary search overlap queries
+#[derive(Debug, Clone, Default)]
+pub struct GenomicIntervals {
+    intervals: Vec<GenomicInterval>,
+    // longest interval of each contig, bounds how far back an overlap query looks
+    max_len: BTreeMap<String, usize>,
+}
+
+impl GenomicIntervals {
+    pub fn new(mut intervals: Vec<GenomicInterval>) -> Self {
+        intervals.sort();
+        let mut max_len: BTreeMap<String, usize> = BTreeMap::new();
+        intervals.iter().for_each(|v| {
+            let len = max_len.entry(v.contig.clone()).or_default();
+            *len = (*len).max(v.length());
+        });
+        Self { intervals, max_len }
+    }
+
+    pub fn intervals(&self) -> &Vec<GenomicInterval> {
+        &self.intervals
+    }
+
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// intervals of one contig, still sorted
+    pub fn contig_intervals(&self, contig: &str) -> &[GenomicInterval] {
+        let lo = self.intervals.partition_point(|v| v.contig.as_str() < contig);
+        let hi = self.intervals.partition_point(|v| v.contig.as_str() <= contig);
+        &self.intervals[lo..hi]
+    }
+
+    /// intervals overlapping query, strand ignored. sorted by (start, end)
+    pub fn overlaps(&self, query: &GenomicInterval) -> Vec<&GenomicInterval> {
+        let max_len = match self.max_len.get(&query.contig) {
+            Some(len) => *len,
+            None => return vec![],
+        };
+        let intervals = self.contig_intervals(&query.contig);
+        let lo_start = query.start.saturating_sub(max_len);
+        let lo = intervals.partition_point(|v| v.start < lo_start);
+        let query_end = query.end.max(query.start + 1);
+        intervals[lo..]
+            .iter()
+            .take_while(|v| v.start < query_end)
+            .filter(|v| v.overlaps(query))
+            .collect()
+    }
+
+    pub fn contains(&self, contig: &str, pos: usize) -> bool {
+        !self
+            .overlaps(&GenomicInterval::new(contig, pos, pos + 1, Strand::Unknown))
+            .is_empty()
+    }
+}
+
+impl FromIterator<GenomicInterval> for GenomicIntervals {
+    fn from_iter<T: IntoIterator<Item = GenomicInterval>>(iter: T) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
+impl From<&GenomicIntervals> for GenomicRegions {
+    fn from(value: &GenomicIntervals) -> Self {
+        value
+            .intervals
+            .iter()
+            .map(|v| (v.contig.clone(), v.start, v.end))
+            .collect()
+    }
+}
+
+/// nearest region of another set. distance is the gap in bases, 0 for overlapping or book-ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosestHit {
+    pub chrom: String,
+    pub query: Region,
+    pub hit: Option<Region>,
+    pub distance: usize,
+}
+
+/// how many bases of a region are covered by another set
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageHit {
+    pub chrom: String,
+    pub query: Region,
+    pub n_hits: usize,
+    pub covered: usize,
+    pub fraction: f64,
+}
+
+/// chromosome aware region set, 0-based half open.
+/// regions of each chromosome are kept sorted by (start, end), overlaps are allowed until merge
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GenomicRegions {
+    regions: BTreeMap<String, Vec<Region>>,
+}
+
+impl GenomicRegions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the whole genome, one region per SQ
+    pub fn from_header_seqs(seqs: &[HeaderSQ]) -> Self {
+        seqs.iter()
+            .map(|seq| (seq.get_name().to_string(), 0, seq.get_len()))
+            .collect()
+    }
+
+    pub fn push(&mut self, chrom: &str, start: usize, end: usize) {
+        assert!(start <= end, "invalid region {}:{}-{}", chrom, start, end);
+        let regions = self.regions.entry(chrom.to_string()).or_default();
+        let idx = regions.partition_point(|r| (r.start, r.end) <= (start, end));
+        regions.insert(idx, Region::new(start, end));
+    }
+
+    pub fn chroms(&self) -> Vec<&str> {
+        self.regions.keys().map(|v| v.as_str()).collect()
+    }
+
+    pub fn get(&self, chrom: &str) -> &[Region] {
+        self.regions.get(chrom).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Region)> {
+        self.regions
+            .iter()
+            .flat_map(|(chrom, regions)| regions.iter().map(move |r| (chrom.as_str(), r)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.values().map(|v| v.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of bases covered, overlapping bases are counted once
+    pub fn total_length(&self) -> usize {
+        self.merge(0)
+            .regions
+            .values()
+            .map(|v| v.iter().map(|r| r.length()).sum::<usize>())
+            .sum()
+    }
+
+    pub fn to_intervals(&self) -> GenomicIntervals {
+        self.iter()
+            .map(|(chrom, r)| GenomicInterval::from_region(chrom, r, Strand::Unknown))
+            .collect()
+    }
+
+    pub fn to_regions(&self, chrom: &str) -> Regions {
+        Regions::new(self.get(chrom).to_vec())
+    }
+
+    /// merge regions that overlap or are at most distance bases apart
+    pub fn merge(&self, distance: usize) -> Self {
+        let regions = self
+            .regions
+            .iter()
+            .map(|(chrom, regions)| {
+                let mut merged: Vec<Region> = Vec::new();
+                for region in regions {
+                    match merged.last_mut() {
+                        Some(last) if region.start <= last.end.saturating_add(distance) => {
+                            last.end = last.end.max(region.end);
+                        }
+                        _ => merged.push(region.clone()),
+                    }
+                }
+                (chrom.clone(), merged)
+            })
+            .collect();
+        Self { regions }
+    }
+
+    /// bases covered by both sets
+    pub fn intersect(&self, other: &GenomicRegions) -> Self {
+        let (a, b) = (self.merge(0), other.merge(0));
+        let mut res = Self::new();
+        for (chrom, a_regions) in a.regions.iter() {
+            let b_regions = b.get(chrom);
+            let (mut i, mut j) = (0, 0);
+            let mut out = vec![];
+            while i < a_regions.len() && j < b_regions.len() {
+                let start = a_regions[i].start.max(b_regions[j].start);
+                let end = a_regions[i].end.min(b_regions[j].end);
+                if start < end {
+                    out.push(Region::new(start, end));
+                }
+                if a_regions[i].end < b_regions[j].end {
+                    i += 1;
+                } else {
+                    j += 1;
+                }
+            }
+            if !out.is_empty() {
+                res.regions.insert(chrom.clone(), out);
+            }
+        }
+        res
+    }
+
+    /// bases covered by self but not by other
+    pub fn subtract(&self, other: &GenomicRegions) -> Self {
+        let (a, b) = (self.merge(0), other.merge(0));
+        let mut res = Self::new();
+        for (chrom, a_regions) in a.regions.iter() {
+            let b_regions = b.get(chrom);
+            let mut j = 0;
+            let mut out = vec![];
+            for region in a_regions {
+                while j < b_regions.len() && b_regions[j].end <= region.start {
+                    j += 1;
+                }
+                let mut cur = region.start;
+                let mut k = j;
+                while k < b_regions.len() && b_regions[k].start < region.end {
+                    if b_regions[k].start > cur {
+                        out.push(Region::new(cur, b_regions[k].start));
+                    }
+                    cur = cur.max(b_regions[k].end);
+                    k += 1;
+                }
+                if cur < region.end {
+                    out.push(Region::new(cur, region.end));
+                }
+            }
+            if !out.is_empty() {
+                res.regions.insert(chrom.clone(), out);
+            }
+        }
+        res
+    }
+
+    /// bases of the genome not covered by self
+    pub fn complement(&self, seqs: &[HeaderSQ]) -> Self {
+        GenomicRegions::from_header_seqs(seqs).subtract(self)
+    }
+
+    /// extend each region left / right bases, clipped to the chromosome when seqs is given
+    pub fn slop(&self, left: usize, right: usize, seqs: Option<&[HeaderSQ]>) -> Self {
+        let chrom_lens = chrom_lens(seqs);
+        self.iter()
+            .map(|(chrom, region)| {
+                let mut end = region.end.saturating_add(right);
+                if let Some(len) = chrom_lens.as_ref().and_then(|v| v.get(chrom)) {
+                    end = end.min(*len);
+                }
+                (chrom.to_string(), region.start.saturating_sub(left), end)
+            })
+            .collect()
+    }
+
+    /// left / right bases just outside each region, clipped to the chromosome when seqs is given
+    pub fn flank(&self, left: usize, right: usize, seqs: Option<&[HeaderSQ]>) -> Self {
+        let chrom_lens = chrom_lens(seqs);
+        let mut res = Self::new();
+        self.iter().for_each(|(chrom, region)| {
+            let left_start = region.start.saturating_sub(left);
+            if left_start < region.start {
+                res.push(chrom, left_start, region.start);
+            }
+            let mut right_end = region.end.saturating_add(right);
+            if let Some(len) = chrom_lens.as_ref().and_then(|v| v.get(chrom)) {
+                right_end = right_end.min(*len);
+            }
+            if right_end > region.end {
+                res.push(chrom, region.end, right_end);
+            }
+        });
+        res
+    }
+
+    /// for each region of self, the nearest region of other on the same chromosome.
+    /// overlaps win, then the smaller gap, then the upstream one
+    pub fn closest(&self, other: &GenomicRegions) -> Vec<ClosestHit> {
+        let mut hits = vec![];
+        for (chrom, regions) in self.regions.iter() {
+            let candidates = other.get(chrom);
+            // idx of the region with the largest end in candidates[..=i]
+            let mut max_end_idx: Vec<usize> = Vec::with_capacity(candidates.len());
+            for (i, c) in candidates.iter().enumerate() {
+                match max_end_idx.last() {
+                    Some(&pre) if candidates[pre].end >= c.end => max_end_idx.push(pre),
+                    _ => max_end_idx.push(i),
+                }
+            }
+
+            for region in regions {
+                let idx = candidates.partition_point(|c| c.start < region.end);
+                let upstream = idx.checked_sub(1).map(|i| {
+                    let c = &candidates[max_end_idx[i]];
+                    (c, region.start.saturating_sub(c.end))
+                });
+                let downstream = candidates
+                    .get(idx)
+                    .map(|c| (c, c.start - region.end));
+
+                let best = match (upstream, downstream) {
+                    (Some(up), Some(down)) => Some(if up.1 <= down.1 { up } else { down }),
+                    (up, down) => up.or(down),
+                };
+                hits.push(ClosestHit {
+                    chrom: chrom.clone(),
+                    query: region.clone(),
+                    hit: best.map(|v| v.0.clone()),
+                    distance: best.map(|v| v.1).unwrap_or(usize::MAX),
+                });
+            }
+        }
+        hits
+    }
+
+    /// for each region of self, the number of overlapping regions of other and covered bases
+    pub fn coverage(&self, other: &GenomicRegions) -> Vec<CoverageHit> {
+        let merged_other = other.merge(0);
+        self.iter()
+            .map(|(chrom, region)| {
+                let n_hits = other
+                    .get(chrom)
+                    .iter()
+                    .take_while(|c| c.start < region.end)
+                    .filter(|c| c.end > region.start)
+                    .count();
+                let merged = merged_other.get(chrom);
+                let first = merged.partition_point(|c| c.end <= region.start);
+                let covered = merged[first..]
+                    .iter()
+                    .take_while(|c| c.start < region.end)
+                    .map(|c| c.end.min(region.end) - c.start.max(region.start))
+                    .sum::<usize>();
+                let fraction = if region.length() == 0 {
+                    0.0
+                } else {
+                    covered as f64 / region.length() as f64
+                };
+                CoverageHit {
+                    chrom: chrom.to_string(),
+                    query: region.clone(),
+                    n_hits,
+                    covered,
+                    fraction,
+                }
+            })
+            .collect()
+    }
+
+    /// sliding windows inside each region
+    pub fn windows(&self, win_len: usize, win_ovlp: usize, drop_last: bool) -> Self {
+        self.iter()
+            .flat_map(|(chrom, region)| {
+                sliding_window(region.length(), win_len, win_ovlp, drop_last).map(
+                    move |(s, e)| (chrom.to_string(), region.start + s, region.start + e),
+                )
+            })
+            .collect()
+    }
+
+    /// sliding windows over every chromosome of the genome
+    pub fn genome_windows(
+        seqs: &[HeaderSQ],
+        win_len: usize,
+        win_ovlp: usize,
+        drop_last: bool,
+    ) -> Self {
+        GenomicRegions::from_header_seqs(seqs).windows(win_len, win_ovlp, drop_last)
+    }
+}
+
+fn chrom_lens(seqs: Option<&[HeaderSQ]>) -> Option<BTreeMap<&str, usize>> {
+    seqs.map(|seqs| {
+        seqs.iter()
+            .map(|seq| (seq.get_name(), seq.get_len()))
+            .collect()
+    })
+}
+
+impl FromIterator<(String, usize, usize)> for GenomicRegions {
+    fn from_iter<T: IntoIterator<Item = (String, usize, usize)>>(iter: T) -> Self {
+        let mut regions: BTreeMap<String, Vec<Region>> = BTreeMap::new();
+        iter.into_iter().for_each(|(chrom, start, end)| {
+            assert!(start <= end, "invalid region {}:{}-{}", chrom, start, end);
+            regions.entry(chrom).or_default().push(Region::new(start, end));
+        });
+        regions
+            .values_mut()
+            .for_each(|v| v.sort_by_key(|r| (r.start, r.end)));
+        Self { regions }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::ds::region::Regions;
+    use crate::{
+        ds::region::{GenomicInterval, GenomicIntervals, GenomicRegions, Region, Regions, Strand},
+        gsbam::bam_header_ext::HeaderSQ,
+    };
+
+    fn genome() -> Vec<HeaderSQ> {
+        vec![
+            HeaderSQ::new(0, "chr1".to_string(), 100),
+            HeaderSQ::new(1, "chr2".to_string(), 50),
+        ]
+    }
+
+    fn regions(items: &[(&str, usize, usize)]) -> GenomicRegions {
+        items.iter().map(|&(c, s, e)| (c.to_string(), s, e)).collect()
+    }
 
 
     #[test]
@@ -175,4 +806,171 @@ mod test {
 
         println!("{:?}", res);
     }
+
+    #[test]
+    fn test_genomic_regions_set_ops() {
+        let a = regions(&[("chr1", 10, 20), ("chr1", 15, 30), ("chr1", 40, 50), ("chr2", 0, 10)]);
+        let b = regions(&[("chr1", 0, 12), ("chr1", 25, 45), ("chr3", 0, 5)]);
+
+        assert_eq!(a.len(), 4);
+        assert_eq!(a.total_length(), 40);
+        assert_eq!(
+            a.merge(0),
+            regions(&[("chr1", 10, 30), ("chr1", 40, 50), ("chr2", 0, 10)])
+        );
+        assert_eq!(a.merge(10), regions(&[("chr1", 10, 50), ("chr2", 0, 10)]));
+        assert_eq!(a.merge(usize::MAX), regions(&[("chr1", 10, 50), ("chr2", 0, 10)]));
+
+        assert_eq!(
+            a.intersect(&b),
+            regions(&[("chr1", 10, 12), ("chr1", 25, 30), ("chr1", 40, 45)])
+        );
+        assert_eq!(
+            a.subtract(&b),
+            regions(&[("chr1", 12, 25), ("chr1", 45, 50), ("chr2", 0, 10)])
+        );
+        assert_eq!(
+            a.complement(&genome()),
+            regions(&[("chr1", 0, 10), ("chr1", 30, 40), ("chr1", 50, 100), ("chr2", 10, 50)])
+        );
+        assert_eq!(a.intersect(&b).total_length() + a.subtract(&b).total_length(), 40);
+    }
+
+    #[test]
+    fn test_genomic_regions_slop_flank() {
+        let a = regions(&[("chr1", 5, 20), ("chr2", 40, 48)]);
+        assert_eq!(
+            a.slop(10, 5, Some(&genome())),
+            regions(&[("chr1", 0, 25), ("chr2", 30, 50)])
+        );
+        assert_eq!(a.slop(10, 5, None), regions(&[("chr1", 0, 25), ("chr2", 30, 53)]));
+        assert_eq!(
+            a.flank(3, 3, Some(&genome())),
+            regions(&[("chr1", 2, 5), ("chr1", 20, 23), ("chr2", 37, 40), ("chr2", 48, 50)])
+        );
+
+        // no overflow for huge extensions
+        assert_eq!(
+            a.slop(0, usize::MAX, None),
+            regions(&[("chr1", 5, usize::MAX), ("chr2", 40, usize::MAX)])
+        );
+        assert_eq!(
+            a.flank(0, usize::MAX, None),
+            regions(&[("chr1", 20, usize::MAX), ("chr2", 48, usize::MAX)])
+        );
+    }
+
+    #[test]
+    fn test_genomic_regions_closest_coverage() {
+        let a = regions(&[("chr1", 10, 20), ("chr1", 60, 70), ("chr2", 0, 10)]);
+        let b = regions(&[("chr1", 0, 50), ("chr1", 22, 25), ("chr1", 75, 80)]);
+
+        let hits = a.closest(&b);
+        assert_eq!(hits.len(), 3);
+        assert_eq!(hits[0].hit, Some(Region::new(0, 50)));
+        assert_eq!(hits[0].distance, 0);
+        assert_eq!(hits[1].hit, Some(Region::new(75, 80)));
+        assert_eq!(hits[1].distance, 5);
+        assert_eq!(hits[2].hit, None);
+
+        let cov = a.coverage(&b);
+        assert_eq!(cov[0].n_hits, 1);
+        assert_eq!(cov[0].covered, 10);
+        assert_eq!(cov[1].covered, 0);
+        let cov = regions(&[("chr1", 20, 30)]).coverage(&b);
+        assert_eq!(cov[0].n_hits, 2);
+        assert_eq!(cov[0].covered, 10);
+        assert!((cov[0].fraction - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_genomic_windows() {
+        let windows = GenomicRegions::genome_windows(&genome(), 40, 10, false);
+        assert_eq!(
+            windows,
+            regions(&[
+                ("chr1", 0, 40),
+                ("chr1", 30, 70),
+                ("chr1", 60, 100),
+                ("chr1", 90, 100),
+                ("chr2", 0, 40),
+                ("chr2", 30, 50),
+            ])
+        );
+        let windows = regions(&[("chr1", 10, 60)]).windows(20, 0, true);
+        assert_eq!(windows, regions(&[("chr1", 10, 30), ("chr1", 30, 50)]));
+    }
+
+    #[test]
+    fn test_genomic_interval_parse() {
+        let iv: GenomicInterval = "chr1:1,001-2,000".parse().unwrap();
+        assert_eq!(iv, GenomicInterval::new("chr1", 1000, 2000, Strand::Unknown));
+        assert_eq!(iv.to_string(), "chr1:1001-2000");
+        assert_eq!(iv.to_zero_based_string(), "chr1:1000-2000");
+
+        let iv = GenomicInterval::parse_zero_based("chr1:1000-2000:-").unwrap();
+        assert_eq!(iv, GenomicInterval::new("chr1", 1000, 2000, Strand::Reverse));
+        assert_eq!(iv.to_string(), "chr1:1001-2000:-");
+
+        assert_eq!(
+            GenomicInterval::parse_one_based("chr1:5").unwrap(),
+            GenomicInterval::new("chr1", 4, 5, Strand::Unknown)
+        );
+        assert_eq!(
+            GenomicInterval::parse_zero_based("chr1:5").unwrap(),
+            GenomicInterval::new("chr1", 5, 6, Strand::Unknown)
+        );
+        assert_eq!(
+            GenomicInterval::parse_one_based("chr1:5-").unwrap(),
+            GenomicInterval::new("chr1", 4, usize::MAX, Strand::Unknown)
+        );
+        assert_eq!(
+            GenomicInterval::parse_one_based("HLA-A*01:01:01:01").unwrap(),
+            GenomicInterval::new("HLA-A*01:01:01", 0, 1, Strand::Unknown)
+        );
+        assert_eq!(
+            GenomicInterval::parse_one_based("HLA-A*01:01:01:01:1-10").unwrap(),
+            GenomicInterval::new("HLA-A*01:01:01:01", 0, 10, Strand::Unknown)
+        );
+        assert_eq!(
+            "chrM".parse::<GenomicInterval>().unwrap(),
+            GenomicInterval::whole_contig("chrM")
+        );
+
+        assert!(GenomicInterval::parse_one_based("chr1:0-10").is_err());
+        assert!(GenomicInterval::parse_one_based("chr1:20-10").is_err());
+        assert!(GenomicInterval::parse_one_based(":1-10").is_err());
+    }
+
+    #[test]
+    fn test_genomic_intervals_overlaps() {
+        let intervals = vec![
+            GenomicInterval::new("chr2", 0, 10, Strand::Forward),
+            GenomicInterval::new("chr1", 100, 200, Strand::Forward),
+            GenomicInterval::new("chr1", 0, 1000, Strand::Reverse),
+            GenomicInterval::new("chr1", 150, 160, Strand::Unknown),
+            GenomicInterval::new("chr1", 300, 300, Strand::Unknown),
+        ]
+        .into_iter()
+        .collect::<GenomicIntervals>();
+
+        assert_eq!(intervals.contig_intervals("chr1").len(), 4);
+        assert_eq!(intervals.intervals()[0].contig, "chr1");
+
+        let query: GenomicInterval = "chr1:181-310".parse().unwrap();
+        let hits = intervals.overlaps(&query);
+        assert_eq!(
+            hits.iter().map(|v| (v.start, v.end)).collect::<Vec<_>>(),
+            vec![(0, 1000), (100, 200), (300, 300)]
+        );
+        assert!(intervals.contains("chr2", 9));
+        assert!(!intervals.contains("chr2", 10));
+        assert!(intervals.overlaps(&GenomicInterval::whole_contig("chr3")).is_empty());
+        assert_eq!(intervals.overlaps(&GenomicInterval::whole_contig("chr1")).len(), 4);
+
+        let mut regions: Regions = (&vec![(5_usize, 8_usize), (1, 3)]).into();
+        regions.sort();
+        assert_eq!(regions[0], Region::new(1, 3));
+        assert_eq!(GenomicRegions::from(&intervals).to_intervals().len(), 5);
+    }
 }
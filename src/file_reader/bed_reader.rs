@@ -1,4 +1,17 @@
-use std::{collections::HashMap, io::BufRead};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt::Display,
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use bio::data_structures::interval_tree::ArrayBackedIntervalTree;
+use rust_htslib::{bgzf, htslib, tbx, tbx::Read as TbxRead};
+
+use crate::ds::region::{GenomicRegions, Strand};
 
 
 #[derive(Debug, Clone)]
@@ -39,15 +52,21 @@ impl<'a> Iterator for BedFileReaderIter<'a>  {
     fn next(&mut self) -> Option<Self::Item> {
         
         let mut line = String::new();
-        if let Ok(n) = self.reader.read_line(&mut line) {
-            if n == 0 {
-                return None;
-            }
+        loop {
+            line.clear();
+            if let Ok(n) = self.reader.read_line(&mut line) {
+                if n == 0 {
+                    return None;
+                }
+                if is_bed_header_line(&line) {
+                    continue;
+                }
 
-            return Some(BedRowData::from_str(&line));
+                return Some(BedRowData::from_str(&line));
 
-        } else {
-            return None;
+            } else {
+                return None;
+            }
         }
 
     }
@@ -91,7 +110,7 @@ impl BedInfo {
         return match sorted_range.binary_search_by_key(&begin_end.0, |v| v.0) {
             Ok(n) => sorted_range[n].1 >= begin_end.1,
             Err(n) => {
-                if n == sorted_range.len() || n == 0{
+                if n == 0 {
                     false
                 } else {
                     begin_end.1 <= sorted_range[n-1].1
@@ -130,4 +149,582 @@ impl BedInfo {
 
     }
 
-}
\ No newline at end of file
+}
+
+/// empty, comment(#), track and browser lines carry no feature
+pub fn is_bed_header_line(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty()
+        || line.starts_with('#')
+        || line == "track"
+        || line == "browser"
+        || line.starts_with("track ")
+        || line.starts_with("track\t")
+        || line.starts_with("browser ")
+        || line.starts_with("browser\t")
+}
+
+/// one BED3 - BED12 row. all coordinates are 0-based, half open.
+/// columns that are not present in the file are None / empty
+#[derive(Debug, Clone, PartialEq)]
+pub struct BedRecord {
+    pub chrom: String,
+    pub start: usize,
+    pub end: usize,
+    pub name: Option<String>,
+    pub score: Option<f64>,
+    pub strand: Strand,
+    pub thick_start: Option<usize>,
+    pub thick_end: Option<usize>,
+    pub item_rgb: Option<(u8, u8, u8)>,
+    /// absolute (start, end) of each block, in block order
+    pub blocks: Vec<(usize, usize)>,
+    /// number of standard bed columns in the row, 3..=12
+    pub n_columns: usize,
+    /// columns after the 12th
+    pub extra: Vec<String>,
+}
+
+impl BedRecord {
+    pub fn new(chrom: String, start: usize, end: usize) -> Self {
+        Self {
+            chrom,
+            start,
+            end,
+            name: None,
+            score: None,
+            strand: Strand::Unknown,
+            thick_start: None,
+            thick_end: None,
+            item_rgb: None,
+            blocks: vec![],
+            n_columns: 3,
+            extra: vec![],
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// half open overlap, a zero length feature overlaps [s, e) if s <= start < e
+    pub fn overlaps(&self, chrom: &str, start: usize, end: usize) -> bool {
+        self.chrom == chrom
+            && self.start < end.max(start + 1)
+            && start < self.end.max(self.start + 1)
+    }
+
+    /// blocks if present, otherwise the whole feature as a single block
+    pub fn exons(&self) -> Vec<(usize, usize)> {
+        if self.blocks.is_empty() {
+            vec![(self.start, self.end)]
+        } else {
+            self.blocks.clone()
+        }
+    }
+
+    fn parse_usize_list(item: &str, col: &str) -> anyhow::Result<Vec<usize>> {
+        item.trim_end_matches(',')
+            .split(',')
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                v.trim()
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid {} '{}'", col, item))
+            })
+            .collect()
+    }
+}
+
+impl FromStr for BedRecord {
+    type Err = anyhow::Error;
+
+    /// chrom start end [name score strand thickStart thickEnd itemRgb
+    ///     blockCount blockSizes blockStarts]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let items = s.trim_end_matches(['\n', '\r']).split('\t').collect::<Vec<_>>();
+        if items.len() < 3 {
+            bail!("invalid bed line '{}', at least 3 columns expected", s.trim());
+        }
+
+        let start = items[1]
+            .trim()
+            .parse::<usize>()
+            .with_context(|| format!("invalid bed start '{}'", items[1]))?;
+        let end = items[2]
+            .trim()
+            .parse::<usize>()
+            .with_context(|| format!("invalid bed end '{}'", items[2]))?;
+        if start > end {
+            bail!("invalid bed line '{}', start > end", s.trim());
+        }
+
+        let mut record = BedRecord::new(items[0].trim().to_string(), start, end);
+        record.n_columns = items.len().min(12);
+        record.extra = items.iter().skip(12).map(|v| v.to_string()).collect();
+
+        if let Some(name) = items.get(3) {
+            record.name = Some(name.to_string());
+        }
+
+        if let Some(&score) = items.get(4) {
+            if score != "." {
+                record.score = Some(
+                    score
+                        .trim()
+                        .parse::<f64>()
+                        .with_context(|| format!("invalid bed score '{}'", score))?,
+                );
+            }
+        }
+
+        if let Some(strand) = items.get(5) {
+            record.strand = strand.trim().parse()?;
+        }
+
+        if items.len() == 7 {
+            bail!("invalid bed line '{}', thickStart without thickEnd", s.trim());
+        }
+        if items.len() >= 8 {
+            let thick_start = items[6]
+                .trim()
+                .parse::<usize>()
+                .with_context(|| format!("invalid bed thickStart '{}'", items[6]))?;
+            let thick_end = items[7]
+                .trim()
+                .parse::<usize>()
+                .with_context(|| format!("invalid bed thickEnd '{}'", items[7]))?;
+            if thick_start > thick_end || thick_start < start || thick_end > end {
+                bail!("invalid bed line '{}', thick region out of feature", s.trim());
+            }
+            record.thick_start = Some(thick_start);
+            record.thick_end = Some(thick_end);
+        }
+
+        if let Some(&rgb) = items.get(8) {
+            let rgb = rgb.trim();
+            if rgb != "0" && rgb != "." {
+                let channels = rgb
+                    .split(',')
+                    .map(|v| v.trim().parse::<u8>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("invalid bed itemRgb '{}'", rgb))?;
+                if channels.len() != 3 {
+                    bail!("invalid bed itemRgb '{}'", rgb);
+                }
+                record.item_rgb = Some((channels[0], channels[1], channels[2]));
+            }
+        }
+
+        if items.len() >= 10 {
+            if items.len() < 12 {
+                bail!(
+                    "invalid bed line '{}', blockCount/blockSizes/blockStarts must come together",
+                    s.trim()
+                );
+            }
+            let block_count = items[9]
+                .trim()
+                .parse::<usize>()
+                .with_context(|| format!("invalid bed blockCount '{}'", items[9]))?;
+            let sizes = BedRecord::parse_usize_list(items[10], "blockSizes")?;
+            let starts = BedRecord::parse_usize_list(items[11], "blockStarts")?;
+            if sizes.len() != block_count || starts.len() != block_count {
+                bail!("invalid bed line '{}', blockCount mismatch", s.trim());
+            }
+            record.blocks = starts
+                .iter()
+                .zip(sizes.iter())
+                .map(|(&b_start, &b_size)| (start + b_start, start + b_start + b_size))
+                .collect();
+            if record.blocks.iter().any(|&(_, b_end)| b_end > end) {
+                bail!("invalid bed line '{}', block out of feature", s.trim());
+            }
+        }
+
+        Ok(record)
+    }
+}
+
+impl Display for BedRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}\t{}", self.chrom, self.start, self.end)?;
+        let mut items = vec![];
+        if self.n_columns >= 4 {
+            items.push(self.name.clone().unwrap_or(".".to_string()));
+        }
+        if self.n_columns >= 5 {
+            items.push(self.score.map(|v| v.to_string()).unwrap_or(".".to_string()));
+        }
+        if self.n_columns >= 6 {
+            items.push(self.strand.to_string());
+        }
+        if self.n_columns >= 8 {
+            items.push(self.thick_start.unwrap_or(self.start).to_string());
+            items.push(self.thick_end.unwrap_or(self.end).to_string());
+        }
+        if self.n_columns >= 9 {
+            items.push(
+                self.item_rgb
+                    .map(|(r, g, b)| format!("{},{},{}", r, g, b))
+                    .unwrap_or("0".to_string()),
+            );
+        }
+        if self.n_columns >= 12 {
+            items.push(self.blocks.len().to_string());
+            let sizes = self.blocks.iter().map(|(s, e)| format!("{},", e - s)).collect::<String>();
+            let starts = self
+                .blocks
+                .iter()
+                .map(|(s, _)| format!("{},", s - self.start))
+                .collect::<String>();
+            items.push(sizes);
+            items.push(starts);
+        }
+        items.extend(self.extra.iter().cloned());
+        for item in items {
+            write!(f, "\t{}", item)?;
+        }
+        Ok(())
+    }
+}
+
+/// streaming bed reader. plain text and gzip/bgzip compressed files are both supported.
+/// track / browser / comment lines are collected into header
+pub struct BedReader {
+    reader: Box<dyn BufRead>,
+    header: Vec<String>,
+    line_no: usize,
+}
+
+impl BedReader {
+    pub fn new(reader: Box<dyn BufRead>) -> Self {
+        Self {
+            reader,
+            header: vec![],
+            line_no: 0,
+        }
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let reader = bgzf::Reader::from_path(path.as_ref())
+            .with_context(|| format!("open bed file {:?} error", path.as_ref()))?;
+        Ok(Self::new(Box::new(BufReader::new(reader))))
+    }
+
+    /// header lines seen so far
+    pub fn header(&self) -> &Vec<String> {
+        &self.header
+    }
+}
+
+impl Iterator for BedReader {
+    type Item = anyhow::Result<BedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    self.line_no += 1;
+                    if is_bed_header_line(&line) {
+                        if !line.trim().is_empty() {
+                            self.header.push(line.trim_end().to_string());
+                        }
+                        continue;
+                    }
+                    return Some(
+                        line.parse::<BedRecord>()
+                            .with_context(|| format!("bed line {}", self.line_no)),
+                    );
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+/// build a .tbi index for a bgzip compressed bed file.
+/// only '#' header lines are allowed, track / browser lines break the htslib bed parser
+pub fn build_bed_tabix_index<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
+    let path_str = path
+        .as_ref()
+        .to_str()
+        .with_context(|| format!("invalid path {:?}", path.as_ref()))?;
+    let c_path = CString::new(path_str)?;
+    let ret = unsafe { htslib::tbx_index_build(c_path.as_ptr(), 0, &htslib::tbx_conf_bed) };
+    if ret != 0 {
+        bail!("build tabix index for {} error, code: {}", path_str, ret);
+    }
+    Ok(())
+}
+
+/// random access over a bgzip compressed and tabix indexed bed file
+pub struct TabixBedReader {
+    reader: tbx::Reader,
+}
+
+impl TabixBedReader {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let reader = tbx::Reader::from_path(path.as_ref())
+            .with_context(|| format!("open tabix bed file {:?} error", path.as_ref()))?;
+        Ok(Self { reader })
+    }
+
+    pub fn seqnames(&self) -> Vec<String> {
+        self.reader.seqnames()
+    }
+
+    /// rows overlapping [start, end) of chrom. unknown chrom gives no rows
+    pub fn fetch(
+        &mut self,
+        chrom: &str,
+        start: usize,
+        end: usize,
+    ) -> anyhow::Result<Vec<BedRecord>> {
+        let tid = match self.reader.tid(chrom) {
+            Ok(tid) => tid,
+            Err(_) => return Ok(vec![]),
+        };
+        self.reader.fetch(tid, start as u64, end as u64)?;
+
+        let mut records = vec![];
+        for line in self.reader.records() {
+            let line = String::from_utf8(line?)?;
+            if is_bed_header_line(&line) {
+                continue;
+            }
+            records.push(line.parse::<BedRecord>()?);
+        }
+        Ok(records)
+    }
+}
+
+/// in memory bed rows with an interval tree per chromosome
+pub struct BedIndex {
+    records: Vec<BedRecord>,
+    trees: HashMap<String, ArrayBackedIntervalTree<usize, usize>>,
+}
+
+impl BedIndex {
+    pub fn new(records: Vec<BedRecord>) -> Self {
+        let mut trees: HashMap<String, ArrayBackedIntervalTree<usize, usize>> = HashMap::new();
+        records.iter().enumerate().for_each(|(idx, record)| {
+            // zero length features are stored as a single base so that point queries hit them
+            let end = record.end.max(record.start + 1);
+            trees
+                .entry(record.chrom.clone())
+                .or_default()
+                .insert(record.start..end, idx);
+        });
+        trees.values_mut().for_each(|tree| tree.index());
+        Self { records, trees }
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let records = BedReader::from_path(path)?.collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(records))
+    }
+
+    pub fn records(&self) -> &Vec<BedRecord> {
+        &self.records
+    }
+
+    pub fn chroms(&self) -> Vec<&str> {
+        let mut chroms = self.trees.keys().map(|v| v.as_str()).collect::<Vec<_>>();
+        chroms.sort();
+        chroms
+    }
+
+    /// rows overlapping [start, end) of chrom, sorted by (start, end)
+    pub fn overlaps(&self, chrom: &str, start: usize, end: usize) -> Vec<&BedRecord> {
+        let tree = match self.trees.get(chrom) {
+            Some(tree) => tree,
+            None => return vec![],
+        };
+        let mut hits = tree
+            .find(start..end.max(start + 1))
+            .into_iter()
+            .map(|entry| &self.records[*entry.data()])
+            .collect::<Vec<_>>();
+        hits.sort_by_key(|record| (record.start, record.end));
+        hits
+    }
+
+    pub fn contains_point(&self, chrom: &str, pos: usize) -> bool {
+        !self.overlaps(chrom, pos, pos + 1).is_empty()
+    }
+}
+
+impl BedInfo {
+    pub fn from_bed_records(records: &[BedRecord]) -> Self {
+        let mut info: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+        records.iter().for_each(|record| {
+            info.entry(record.chrom.clone())
+                .or_default()
+                .push((record.start, record.end));
+        });
+        info.iter_mut().for_each(|(_k, v)| {
+            v.sort_unstable_by_key(|v| v.0);
+        });
+        Self { regions: info }
+    }
+}
+
+impl From<&BedInfo> for GenomicRegions {
+    fn from(value: &BedInfo) -> Self {
+        value
+            .regions
+            .iter()
+            .flat_map(|(chrom, regions)| regions.iter().map(|&(s, e)| (chrom.clone(), s, e)))
+            .collect()
+    }
+}
+
+impl From<&[BedRecord]> for GenomicRegions {
+    fn from(value: &[BedRecord]) -> Self {
+        value
+            .iter()
+            .map(|record| (record.chrom.clone(), record.start, record.end))
+            .collect()
+    }
+}
+
+impl GenomicRegions {
+    pub fn from_bed_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        BedReader::from_path(path)?
+            .map(|record| record.map(|r| (r.chrom, r.start, r.end)))
+            .collect()
+    }
+
+    /// BED3 rows, ordered by chromosome name then position
+    pub fn to_bed_records(&self) -> Vec<BedRecord> {
+        self.iter()
+            .map(|(chrom, region)| BedRecord::new(chrom.to_string(), region.start(), region.end()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io::{Cursor, Write},
+    };
+
+    use rust_htslib::bgzf;
+
+    use crate::ds::region::{GenomicRegions, Strand};
+
+    use super::{
+        build_bed_tabix_index, BedIndex, BedInfo, BedReader, BedRecord, TabixBedReader,
+    };
+
+    const BED_CONTENT: &str = "browser position chr1:1-100
+track name=test description=\"test\"
+# comment
+chr1\t10\t20
+chr1\t15\t40\tgene1\t500\t+
+chr1\t30\t30\tins\t.\t-
+chr2\t100\t200\ttx1\t0\t-\t110\t190\t255,0,0\t2\t20,30,\t0,70,\textra
+";
+
+    #[test]
+    fn test_bed_record() {
+        let record: BedRecord = "chr2\t100\t200\ttx1\t0\t-\t110\t190\t255,0,0\t2\t20,30,\t0,70,"
+            .parse()
+            .unwrap();
+        assert_eq!(record.n_columns, 12);
+        assert_eq!(record.strand, Strand::Reverse);
+        assert_eq!(record.thick_start, Some(110));
+        assert_eq!(record.item_rgb, Some((255, 0, 0)));
+        assert_eq!(record.blocks, vec![(100, 120), (170, 200)]);
+        assert_eq!(
+            record.to_string(),
+            "chr2\t100\t200\ttx1\t0\t-\t110\t190\t255,0,0\t2\t20,30,\t0,70,"
+        );
+
+        assert!("chr1\t20\t10".parse::<BedRecord>().is_err());
+        assert!("chr1\t10".parse::<BedRecord>().is_err());
+        assert!("chr1\t10\t20\tn\t0\t*".parse::<BedRecord>().is_err());
+        assert!("chr1\t10\t20\tn\t0\t+\t5\t20".parse::<BedRecord>().is_err());
+        assert!("chr1\t10\t20\tn\t0\t+\t10\t20\t0\t1\t20,\t5,".parse::<BedRecord>().is_err());
+    }
+
+    #[test]
+    fn test_bed_reader_and_index() {
+        let mut reader = BedReader::new(Box::new(Cursor::new(BED_CONTENT.as_bytes().to_vec())));
+        let records = reader.by_ref().collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(reader.header().len(), 3);
+        assert_eq!(records[1].name.as_deref(), Some("gene1"));
+        assert_eq!(records[1].score, Some(500.0));
+        assert_eq!(records[3].extra, vec!["extra".to_string()]);
+
+        let index = BedIndex::new(records);
+        assert_eq!(index.chroms(), vec!["chr1", "chr2"]);
+        let hits = index.overlaps("chr1", 18, 31);
+        assert_eq!(
+            hits.iter().map(|v| (v.start, v.end)).collect::<Vec<_>>(),
+            vec![(10, 20), (15, 40), (30, 30)]
+        );
+        assert_eq!(index.overlaps("chr1", 20, 30).len(), 1);
+        assert!(index.contains_point("chr1", 30));
+        assert!(!index.contains_point("chr1", 40));
+        assert!(index.overlaps("chr3", 0, 100).is_empty());
+
+        let regions = GenomicRegions::from(index.records().as_slice());
+        assert_eq!(regions.len(), 4);
+        assert_eq!(regions.merge(0).to_bed_records()[0].to_string(), "chr1\t10\t40");
+    }
+
+    #[test]
+    fn test_tabix_bed_reader() {
+        let path = std::env::temp_dir().join(format!("gskits_test_{}.bed.gz", std::process::id()));
+        {
+            let mut writer = bgzf::Writer::from_path(&path).unwrap();
+            BED_CONTENT
+                .lines()
+                .filter(|line| !line.starts_with("track") && !line.starts_with("browser"))
+                .for_each(|line| writeln!(writer, "{}", line).unwrap());
+        }
+        build_bed_tabix_index(&path).unwrap();
+
+        let records = BedReader::from_path(&path)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 4);
+
+        let mut reader = TabixBedReader::from_path(&path).unwrap();
+        assert_eq!(reader.seqnames(), vec!["chr1".to_string(), "chr2".to_string()]);
+        let hits = reader.fetch("chr1", 18, 25).unwrap();
+        assert_eq!(
+            hits.iter().map(|v| (v.start, v.end)).collect::<Vec<_>>(),
+            vec![(10, 20), (15, 40)]
+        );
+        assert_eq!(reader.fetch("chr2", 150, 151).unwrap().len(), 1);
+        assert!(reader.fetch("chrX", 0, 100).unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.tbi", path.to_str().unwrap())).unwrap();
+    }
+
+    #[test]
+    fn test_within_the_range() {
+        let info = BedInfo::from_info(HashMap::from([(
+            "chr1".to_string(),
+            vec![(10, 20), (30, 40)],
+        )]));
+        assert!(info.within_the_range("chr1", &(12, 18)));
+        assert!(info.within_the_range("chr1", &(30, 40)));
+        // inside the last interval
+        assert!(info.within_the_range("chr1", &(35, 38)));
+        assert!(!info.within_the_range("chr1", &(35, 41)));
+        assert!(!info.within_the_range("chr1", &(15, 25)));
+        assert!(!info.within_the_range("chr1", &(5, 8)));
+        assert!(!info.within_the_range("chr2", &(12, 18)));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod bed_reader;
-pub mod vcf_reader;
\ No newline at end of file
+pub mod vcf_reader;
+pub mod vcf_concordance;
+pub mod vcf_writer;
\ No newline at end of file
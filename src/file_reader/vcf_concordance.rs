@@ -0,0 +1,497 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+};
+
+use anyhow::Context;
+
+use crate::{
+    fastx_reader::fasta_reader::FastaFileReader,
+    file_reader::{
+        bed_reader::BedInfo,
+        vcf_reader::{classify_variant, VariantType, VcfReader, VcfRecord},
+        vcf_writer::{normalize_alleles, trim_alleles},
+    },
+};
+
+#[derive(Debug, Clone)]
+pub struct ConcordanceParams {
+    /// a truth and a called indel of the same type and inserted / deleted bases (up to rotation)
+    /// match if their positions differ by no more than this
+    pub indel_pos_tolerance: usize,
+    /// skip records with a FILTER other than PASS
+    pub pass_only: bool,
+    /// homopolymer runs shorter than this are reported as hp_len 0
+    pub min_hp_len: usize,
+    /// runs longer than this are reported as max_hp_len
+    pub max_hp_len: usize,
+}
+
+impl Default for ConcordanceParams {
+    fn default() -> Self {
+        Self {
+            indel_pos_tolerance: 5,
+            pass_only: true,
+            min_hp_len: 3,
+            max_hp_len: 10,
+        }
+    }
+}
+
+/// one alt allele of a record, normalized. pos is 0-based
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConcordanceVariant {
+    pub chrom: String,
+    pub pos: usize,
+    pub ref_allele: String,
+    pub alt_allele: String,
+    pub variant_type: VariantType,
+    /// longest reference homopolymer overlapping the changed bases
+    pub hp_len: usize,
+}
+
+impl ConcordanceVariant {
+    fn length_delta(&self) -> isize {
+        self.alt_allele.len() as isize - self.ref_allele.len() as isize
+    }
+
+    fn is_indel(&self) -> bool {
+        matches!(self.variant_type, VariantType::Insertion | VariantType::Deletion)
+    }
+
+    /// inserted or deleted bases, the anchor base excluded
+    fn indel_bases(&self) -> &str {
+        match self.variant_type {
+            VariantType::Insertion => &self.alt_allele[self.ref_allele.len()..],
+            VariantType::Deletion => &self.ref_allele[self.alt_allele.len()..],
+            _ => "",
+        }
+    }
+
+    /// same type and the same inserted / deleted bases up to rotation, e.g. deleting GA and AG
+    /// of a GAGAGA repeat at different positions
+    fn same_indel(&self, other: &ConcordanceVariant) -> bool {
+        if self.variant_type != other.variant_type || self.length_delta() != other.length_delta() {
+            return false;
+        }
+        let (a, b) = (self.indel_bases(), other.indel_bases());
+        a.len() == b.len() && a.repeat(2).contains(b)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConcordanceStats {
+    pub tp: usize,
+    pub fp: usize,
+    pub fn_: usize,
+}
+
+impl ConcordanceStats {
+    /// 0 when there is no call
+    pub fn precision(&self) -> f64 {
+        if self.tp + self.fp == 0 {
+            0.0
+        } else {
+            self.tp as f64 / (self.tp + self.fp) as f64
+        }
+    }
+
+    /// 0 when there is no truth variant
+    pub fn recall(&self) -> f64 {
+        if self.tp + self.fn_ == 0 {
+            0.0
+        } else {
+            self.tp as f64 / (self.tp + self.fn_) as f64
+        }
+    }
+
+    pub fn f1(&self) -> f64 {
+        let (p, r) = (self.precision(), self.recall());
+        if p + r == 0.0 {
+            0.0
+        } else {
+            2.0 * p * r / (p + r)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Tp,
+    Fp,
+    Fn,
+}
+
+/// TP and FN are counted on the truth variant, FP on the called variant
+#[derive(Debug, Clone, Default)]
+pub struct ConcordanceReport {
+    pub total: ConcordanceStats,
+    pub by_type: BTreeMap<VariantType, ConcordanceStats>,
+    pub by_hp_len: BTreeMap<usize, ConcordanceStats>,
+    pub false_positives: Vec<ConcordanceVariant>,
+    pub false_negatives: Vec<ConcordanceVariant>,
+}
+
+impl ConcordanceReport {
+    fn add(&mut self, variant: &ConcordanceVariant, outcome: Outcome) {
+        let stats = [
+            &mut self.total,
+            self.by_type.entry(variant.variant_type).or_default(),
+            self.by_hp_len.entry(variant.hp_len).or_default(),
+        ];
+        for stat in stats {
+            match outcome {
+                Outcome::Tp => stat.tp += 1,
+                Outcome::Fp => stat.fp += 1,
+                Outcome::Fn => stat.fn_ += 1,
+            }
+        }
+        match outcome {
+            Outcome::Fp => self.false_positives.push(variant.clone()),
+            Outcome::Fn => self.false_negatives.push(variant.clone()),
+            Outcome::Tp => (),
+        }
+    }
+
+    /// stratum tp fp fn precision recall f1. hp_len 0 is "not in a homopolymer"
+    pub fn to_tsv(&self) -> String {
+        let mut rows = vec!["stratum\ttp\tfp\tfn\tprecision\trecall\tf1".to_string()];
+        let row = |name: String, stat: &ConcordanceStats| {
+            format!(
+                "{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}",
+                name,
+                stat.tp,
+                stat.fp,
+                stat.fn_,
+                stat.precision(),
+                stat.recall(),
+                stat.f1()
+            )
+        };
+        rows.push(row("all".to_string(), &self.total));
+        self.by_type
+            .iter()
+            .for_each(|(k, v)| rows.push(row(format!("type:{:?}", k), v)));
+        self.by_hp_len
+            .iter()
+            .for_each(|(k, v)| rows.push(row(format!("hp_len:{}", k), v)));
+        rows.join("\n") + "\n"
+    }
+}
+
+/// longest homopolymer in ref_seq overlapping [start, end)
+fn homopolymer_len(ref_seq: &[u8], start: usize, end: usize) -> usize {
+    let end = end.min(ref_seq.len());
+    let mut longest = 0;
+    let mut i = start;
+    while i < end {
+        let base = ref_seq[i].to_ascii_uppercase();
+        let mut s = i;
+        while s > 0 && ref_seq[s - 1].to_ascii_uppercase() == base {
+            s -= 1;
+        }
+        let mut e = i + 1;
+        while e < ref_seq.len() && ref_seq[e].to_ascii_uppercase() == base {
+            e += 1;
+        }
+        longest = longest.max(e - s);
+        i = e;
+    }
+    longest
+}
+
+/// the alt alleles carried by the first sample (all alts for site-only records),
+/// normalized against the reference and restricted to the confident region
+fn concordance_variants(
+    record: &VcfRecord,
+    ref_seqs: &HashMap<String, String>,
+    confident: Option<&BedInfo>,
+    params: &ConcordanceParams,
+) -> anyhow::Result<Vec<ConcordanceVariant>> {
+    if params.pass_only && !record.is_pass() {
+        return Ok(vec![]);
+    }
+    let gt = record.samples.first().and_then(|s| s.genotype.as_ref());
+    let carried = |allele: usize| match gt {
+        Some(gt) if !gt.is_missing() => gt.alleles.contains(&Some(allele)),
+        _ => true,
+    };
+    let ref_seq = ref_seqs.get(&record.chrom).map(|v| v.as_bytes());
+
+    let mut variants = vec![];
+    for (idx, alt) in record.alt_alleles.iter().enumerate() {
+        if !carried(idx + 1) {
+            continue;
+        }
+        if matches!(
+            classify_variant(&record.ref_allele, alt),
+            VariantType::NoVariant | VariantType::Symbolic
+        ) {
+            continue;
+        }
+
+        // soft-masked references or records may be lowercase
+        let alleles = [record.ref_allele.to_ascii_uppercase(), alt.to_ascii_uppercase()];
+        let (pos, alleles) = match ref_seq {
+            Some(ref_seq) => normalize_alleles(ref_seq, record.pos, &alleles)
+                .with_context(|| format!("{}:{}", record.chrom, record.pos + 1))?,
+            None => trim_alleles(record.pos, &alleles),
+        };
+        let end = pos + alleles[0].len();
+        if let Some(confident) = confident {
+            if !confident.within_the_range(&record.chrom, &(pos, end)) {
+                continue;
+            }
+        }
+
+        let variant_type = classify_variant(&alleles[0], &alleles[1]);
+        // indels keep the anchor base at pos, the changed bases start right after it
+        let (start, stop) = match variant_type {
+            VariantType::Insertion => (pos + 1, pos + 2),
+            VariantType::Deletion => (pos + 1, end),
+            _ => (pos, end),
+        };
+        let hp_len = ref_seq.map(|v| homopolymer_len(v, start, stop)).unwrap_or(0);
+        let hp_len = if hp_len < params.min_hp_len {
+            0
+        } else {
+            hp_len.min(params.max_hp_len)
+        };
+
+        variants.push(ConcordanceVariant {
+            chrom: record.chrom.clone(),
+            pos,
+            ref_allele: alleles[0].clone(),
+            alt_allele: alleles[1].clone(),
+            variant_type,
+            hp_len,
+        });
+    }
+    Ok(variants)
+}
+
+/// match called variants to the truth set.
+/// a call is a TP if a truth variant has the same normalized alleles at the same position.
+/// indels left unmatched then pair with the closest unmatched truth indel of the same type and
+/// inserted / deleted bases (up to rotation) within params.indel_pos_tolerance.
+/// ref_seqs: contig -> sequence, used for normalization and homopolymer context. contigs
+/// missing from it are only trimmed and get hp_len 0.
+/// confident: only variants fully inside these regions are compared
+pub fn compare_variants(
+    truth: &[VcfRecord],
+    calls: &[VcfRecord],
+    ref_seqs: &HashMap<String, String>,
+    confident: Option<&BedInfo>,
+    params: &ConcordanceParams,
+) -> anyhow::Result<ConcordanceReport> {
+    let mut truth_variants: BTreeMap<String, Vec<ConcordanceVariant>> = BTreeMap::new();
+    for record in truth {
+        for v in concordance_variants(record, ref_seqs, confident, params)? {
+            truth_variants.entry(v.chrom.clone()).or_default().push(v);
+        }
+    }
+    let mut call_variants: BTreeMap<String, Vec<ConcordanceVariant>> = BTreeMap::new();
+    for record in calls {
+        for v in concordance_variants(record, ref_seqs, confident, params)? {
+            call_variants.entry(v.chrom.clone()).or_default().push(v);
+        }
+    }
+
+    let mut report = ConcordanceReport::default();
+    let empty = vec![];
+    let mut chroms = truth_variants.keys().chain(call_variants.keys()).collect::<Vec<_>>();
+    chroms.sort();
+    chroms.dedup();
+
+    for chrom in chroms {
+        let mut truth = truth_variants.get(chrom).unwrap_or(&empty).clone();
+        truth.sort_by_key(|v| v.pos);
+        let calls = call_variants.get(chrom).unwrap_or(&empty);
+
+        let mut truth_used = vec![false; truth.len()];
+        let mut call_matched = vec![false; calls.len()];
+
+        let mut exact: HashMap<(usize, &str, &str), Vec<usize>> = HashMap::new();
+        truth.iter().enumerate().for_each(|(idx, v)| {
+            exact
+                .entry((v.pos, v.ref_allele.as_str(), v.alt_allele.as_str()))
+                .or_default()
+                .push(idx);
+        });
+        for (call_idx, call) in calls.iter().enumerate() {
+            let key = (call.pos, call.ref_allele.as_str(), call.alt_allele.as_str());
+            if let Some(idx) = exact
+                .get(&key)
+                .and_then(|idxs| idxs.iter().find(|&&idx| !truth_used[idx]))
+            {
+                truth_used[*idx] = true;
+                call_matched[call_idx] = true;
+            }
+        }
+
+        for (call_idx, call) in calls.iter().enumerate() {
+            if call_matched[call_idx] || !call.is_indel() {
+                continue;
+            }
+            let lo = call.pos.saturating_sub(params.indel_pos_tolerance);
+            let hi = call.pos + params.indel_pos_tolerance;
+            let first = truth.partition_point(|v| v.pos < lo);
+            let best = (first..truth.len())
+                .take_while(|&idx| truth[idx].pos <= hi)
+                .filter(|&idx| !truth_used[idx] && truth[idx].same_indel(call))
+                .min_by_key(|&idx| truth[idx].pos.abs_diff(call.pos));
+            if let Some(idx) = best {
+                truth_used[idx] = true;
+                call_matched[call_idx] = true;
+            }
+        }
+
+        truth.iter().zip(truth_used.iter()).for_each(|(v, &used)| {
+            report.add(v, if used { Outcome::Tp } else { Outcome::Fn });
+        });
+        calls
+            .iter()
+            .zip(call_matched.iter())
+            .filter(|(_, &matched)| !matched)
+            .for_each(|(v, _)| report.add(v, Outcome::Fp));
+    }
+
+    Ok(report)
+}
+
+/// concordance of call_vcf against truth_vcf. vcf, vcf.gz or bcf
+pub fn compare_vcf_files(
+    truth_vcf: &str,
+    call_vcf: &str,
+    ref_fasta: &str,
+    confident_bed: Option<&str>,
+    params: &ConcordanceParams,
+) -> anyhow::Result<ConcordanceReport> {
+    let truth = VcfReader::from_path(truth_vcf)?.collect::<anyhow::Result<Vec<_>>>()?;
+    let calls = VcfReader::from_path(call_vcf)?.collect::<anyhow::Result<Vec<_>>>()?;
+    let ref_seqs = FastaFileReader::new(ref_fasta.to_string())
+        .map(|read_info| (read_info.name, read_info.seq))
+        .collect::<HashMap<_, _>>();
+    let confident = confident_bed
+        .map(|path| -> anyhow::Result<BedInfo> {
+            let file = File::open(path).with_context(|| format!("open {} error", path))?;
+            Ok(BedInfo::new(&mut BufReader::new(file)))
+        })
+        .transpose()?;
+
+    compare_variants(&truth, &calls, &ref_seqs, confident.as_ref(), params)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::file_reader::{
+        bed_reader::BedInfo,
+        vcf_reader::{VariantType, VcfGenotype, VcfRecord, VcfSample},
+    };
+
+    use super::{compare_variants, homopolymer_len, ConcordanceParams, ConcordanceVariant};
+
+    #[test]
+    fn test_homopolymer_len() {
+        let ref_seq = b"ACGAAAATGCC";
+        assert_eq!(homopolymer_len(ref_seq, 4, 5), 4);
+        assert_eq!(homopolymer_len(ref_seq, 1, 3), 1);
+        assert_eq!(homopolymer_len(ref_seq, 8, 20), 2);
+        assert_eq!(homopolymer_len(ref_seq, 2, 2), 0);
+    }
+
+    #[test]
+    fn test_same_indel() {
+        let del = |pos: usize, r: &str| ConcordanceVariant {
+            chrom: "chr1".to_string(),
+            pos,
+            ref_allele: r.to_string(),
+            alt_allele: r[..1].to_string(),
+            variant_type: VariantType::Deletion,
+            hp_len: 0,
+        };
+        assert!(del(10, "CGA").same_indel(&del(12, "TGA")));
+        assert!(del(10, "CGA").same_indel(&del(13, "TAG")));
+        assert!(!del(10, "CGA").same_indel(&del(12, "ATT")));
+        assert!(!del(10, "CGAT").same_indel(&del(12, "AGA")));
+    }
+
+    #[test]
+    fn test_compare_variants() {
+        //                     0         1         2         3
+        //                     0123456789012345678901234567890123456
+        let ref_seq = "ACGTAGCTTAGCCCCCGATCGATTGCAGTCAGCTAGC";
+        let ref_seqs = HashMap::from([("chr1".to_string(), ref_seq.to_string())]);
+        let rec = |pos: usize, r: &str, a: &str| VcfRecord::new("chr1", pos, r, vec![a.into()]);
+
+        let mut hom_ref = rec(25, "C", "A");
+        hom_ref.samples = vec![VcfSample {
+            genotype: Some(VcfGenotype {
+                alleles: vec![Some(0), Some(0)],
+                phased: false,
+            }),
+            ..Default::default()
+        }];
+        let truth = vec![
+            rec(1, "C", "T"),
+            // deletion of one C of the homopolymer, anchored at the last C
+            rec(15, "CG", "G"),
+            rec(19, "CGA", "C"),
+            rec(22, "T", "G"),
+            hom_ref,
+            rec(33, "T", "A"),
+        ];
+        let mut filtered = rec(5, "G", "A");
+        filtered.filters = vec!["low_gq".to_string()];
+        let calls = vec![
+            // lowercase alleles match the uppercase truth
+            rec(1, "c", "t"),
+            // same deletion, left aligned by normalization
+            rec(12, "CC", "C"),
+            // GA deleted four bases away
+            rec(15, "CGA", "C"),
+            // same length deletion of other bases
+            rec(21, "ATT", "A"),
+            rec(22, "T", "C"),
+            filtered,
+            rec(25, "C", "A"),
+            rec(33, "T", "A"),
+        ];
+        let confident = BedInfo::from_info(HashMap::from([(
+            "chr1".to_string(),
+            vec![(0, 30), (100, 200)],
+        )]));
+
+        let report = compare_variants(
+            &truth,
+            &calls,
+            &ref_seqs,
+            Some(&confident),
+            &ConcordanceParams::default(),
+        )
+        .unwrap();
+
+        assert_eq!((report.total.tp, report.total.fp, report.total.fn_), (3, 3, 1));
+        assert!((report.total.precision() - 0.5).abs() < 1e-9);
+        assert!((report.total.recall() - 0.75).abs() < 1e-9);
+        assert_eq!(report.by_type[&VariantType::Deletion].tp, 2);
+        assert_eq!(report.by_type[&VariantType::Snv].fp, 2);
+        assert_eq!(report.by_hp_len[&5].tp, 1);
+        assert_eq!(report.false_negatives[0].pos, 22);
+        assert_eq!(
+            report.false_positives.iter().map(|v| v.pos).collect::<Vec<_>>(),
+            vec![21, 22, 25]
+        );
+        assert!(report.to_tsv().contains("type:Deletion\t2\t1\t0\t0.6667\t1.0000\t0.8000"));
+
+        let strict = ConcordanceParams {
+            indel_pos_tolerance: 0,
+            ..Default::default()
+        };
+        let report = compare_variants(&truth, &calls, &ref_seqs, None, &strict).unwrap();
+        assert_eq!((report.total.tp, report.total.fp, report.total.fn_), (3, 4, 2));
+    }
+}
@@ -1,4 +1,21 @@
-use std::{collections::HashMap, io::{self, BufRead}};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    fmt::Display,
+    io::{self, BufRead},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use rust_htslib::{
+    bcf::{
+        self,
+        header::{HeaderRecord, HeaderView, TagLength, TagType},
+        record::{GenotypeAllele, Numeric},
+        Read,
+    },
+    htslib,
+};
 
 #[derive(Debug)]
 pub struct VcfRowData {
@@ -6,29 +23,45 @@ pub struct VcfRowData {
     pub pos: usize, // zero based!!!. but the file is 1-based 
     pub ref_bases: String,
     pub alt_bases: String,
-    pub phred_q: u32,
+    pub phred_q: u32, // rounded qual, 0 for '.'
+    pub qual: Option<f32>, // None for '.'
 }
 
 impl VcfRowData {
 
     /// chrom pos id ref alt qual filter info format ..
-    pub fn from_str(inp: &str) -> Self {
+    pub fn from_str(inp: &str) -> anyhow::Result<Self> {
         let inp = inp.trim();
         let items = inp.split("\t").collect::<Vec<_>>();
+        if items.len() < 6 {
+            bail!("invalid line '{}', expect at least 6 columns", inp);
+        }
 
         let chrom = items[0].trim().to_string();
-        let pos = items[1].parse::<usize>().expect(&format!("invalid line '{}', invalid iterm:{}", inp, items[1])) - 1;
+        let pos = items[1]
+            .parse::<usize>()
+            .ok()
+            .filter(|&pos| pos > 0)
+            .with_context(|| format!("invalid line '{}', invalid iterm:'{}'", inp, items[1]))?
+            - 1;
         let ref_bases = items[3].trim().to_string();
         let alt_bases = items[4].trim().to_string();
-        let phred_q = items[5].parse::<u32>().expect(&format!("invalid line '{}', invalid iterm:'{}'", inp, items[5]));
+        let qual = if items[5] == "." {
+            None
+        } else {
+            Some(items[5].parse::<f32>().with_context(|| {
+                format!("invalid line '{}', invalid iterm:'{}'", inp, items[5])
+            })?)
+        };
 
-        VcfRowData {
+        Ok(VcfRowData {
             chrom,
             pos, 
             ref_bases, 
             alt_bases, 
-            phred_q
-        }
+            phred_q: qual.map(|v| v.round().max(0.0) as u32).unwrap_or(0),
+            qual,
+        })
     }
 
     #[allow(unused)]
@@ -38,10 +71,17 @@ impl VcfRowData {
             pos, 
             ref_bases, 
             alt_bases, 
-            phred_q
+            phred_q,
+            qual: Some(phred_q as f32),
         }
     }
 
+    pub fn with_qual(mut self, qual: Option<f32>) -> Self {
+        self.phred_q = qual.map(|v| v.round().max(0.0) as u32).unwrap_or(0);
+        self.qual = qual;
+        self
+    }
+
 }
 
 pub struct VcfReaderIter<'a> {
@@ -57,24 +97,22 @@ impl <'a> VcfReaderIter<'a>{
 }
 
 impl<'a> Iterator for VcfReaderIter<'a> {
-    type Item = VcfRowData;
+    type Item = anyhow::Result<VcfRowData>;
 
     fn next(&mut self) -> Option<Self::Item> {
         
         let mut line = String::new();
         loop {
             line.clear();
-            if let Ok(n) = self.reader.read_line(&mut line) {
-                if n == 0 {
-                    return None;
-                }
-                if line.starts_with("#") {
-                    continue;
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    if line.starts_with("#") || line.trim().is_empty() {
+                        continue;
+                    }
+                    return Some(VcfRowData::from_str(&line));
                 }
-
-                return Some(VcfRowData::from_str(&line));
-            } else {
-                return None;
+                Err(err) => return Some(Err(err.into())),
             }
 
         }
@@ -90,23 +128,29 @@ pub struct VcfInfo {
 }
 
 impl VcfInfo {
+    /// panics on invalid lines, see try_new
     pub fn new(file_reader: &mut dyn BufRead) -> Self {
+        Self::try_new(file_reader).unwrap()
+    }
+
+    pub fn try_new(file_reader: &mut dyn BufRead) -> anyhow::Result<Self> {
         let mut info = HashMap::new();
         let file_iter = VcfReaderIter::new(file_reader);
-        file_iter.for_each(|vcf| {
+        for vcf in file_iter {
+            let vcf = vcf?;
             // .into_iter().collect::<Vec<usize>>()
             let bad_points = vcf.pos..(vcf.pos + vcf.ref_bases.len()) ;
             info.entry(vcf.chrom).or_insert(vec![]).extend(bad_points);
 
-        });
+        }
 
         info.iter_mut().for_each(|(_k, v)| {
             v.sort_unstable();
         });
 
-        Self { 
+        Ok(Self { 
             info
-        }
+        })
     }
 
     /// true: means the range contains a variant loci
@@ -148,6 +192,602 @@ impl VcfInfo {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VcfFieldNumber {
+    Fixed(u32),
+    /// Number=A, one value per alt allele
+    AltAlleles,
+    /// Number=R, one value per allele, ref included
+    Alleles,
+    /// Number=G, one value per genotype
+    Genotypes,
+    /// Number=.
+    Variable,
+}
+
+impl From<TagLength> for VcfFieldNumber {
+    fn from(value: TagLength) -> Self {
+        match value {
+            TagLength::Fixed(n) => VcfFieldNumber::Fixed(n),
+            TagLength::AltAlleles => VcfFieldNumber::AltAlleles,
+            TagLength::Alleles => VcfFieldNumber::Alleles,
+            TagLength::Genotypes => VcfFieldNumber::Genotypes,
+            TagLength::Variable => VcfFieldNumber::Variable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VcfFieldType {
+    Flag,
+    Integer,
+    Float,
+    String,
+}
+
+impl From<TagType> for VcfFieldType {
+    fn from(value: TagType) -> Self {
+        match value {
+            TagType::Flag => VcfFieldType::Flag,
+            TagType::Integer => VcfFieldType::Integer,
+            TagType::Float => VcfFieldType::Float,
+            TagType::String => VcfFieldType::String,
+        }
+    }
+}
+
+/// an ##INFO or ##FORMAT header line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VcfFieldDef {
+    pub id: String,
+    pub number: VcfFieldNumber,
+    pub field_type: VcfFieldType,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VcfHeader {
+    /// (name, length) in header order
+    pub contigs: Vec<(String, Option<usize>)>,
+    pub filters: Vec<String>,
+    pub infos: Vec<VcfFieldDef>,
+    pub formats: Vec<VcfFieldDef>,
+    pub samples: Vec<String>,
+}
+
+impl VcfHeader {
+    pub fn from_header_view(header: &HeaderView) -> anyhow::Result<Self> {
+        let mut res = VcfHeader::default();
+        for record in header.header_records() {
+            match record {
+                HeaderRecord::Contig { values, .. } => {
+                    let name = values.get("ID").context("contig header without ID")?.clone();
+                    let len = values.get("length").and_then(|v| v.parse::<usize>().ok());
+                    res.contigs.push((name, len));
+                }
+                HeaderRecord::Filter { values, .. } => {
+                    res.filters
+                        .push(values.get("ID").context("FILTER header without ID")?.clone());
+                }
+                HeaderRecord::Info { values, .. } => {
+                    let id = values.get("ID").context("INFO header without ID")?.clone();
+                    let (tag_type, tag_len) = header.info_type(id.as_bytes())?;
+                    res.infos.push(VcfFieldDef {
+                        id,
+                        number: tag_len.into(),
+                        field_type: tag_type.into(),
+                        description: values.get("Description").cloned().unwrap_or_default(),
+                    });
+                }
+                HeaderRecord::Format { values, .. } => {
+                    let id = values.get("ID").context("FORMAT header without ID")?.clone();
+                    let (tag_type, tag_len) = header.format_type(id.as_bytes())?;
+                    res.formats.push(VcfFieldDef {
+                        id,
+                        number: tag_len.into(),
+                        field_type: tag_type.into(),
+                        description: values.get("Description").cloned().unwrap_or_default(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        res.samples = header
+            .samples()
+            .into_iter()
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .collect();
+        Ok(res)
+    }
+
+    pub fn info_def(&self, id: &str) -> Option<&VcfFieldDef> {
+        self.infos.iter().find(|v| v.id == id)
+    }
+
+    pub fn format_def(&self, id: &str) -> Option<&VcfFieldDef> {
+        self.formats.iter().find(|v| v.id == id)
+    }
+}
+
+/// typed INFO / FORMAT value, missing ('.') elements are None
+#[derive(Debug, Clone, PartialEq)]
+pub enum VcfValue {
+    Flag,
+    Integer(Vec<Option<i32>>),
+    Float(Vec<Option<f32>>),
+    String(Vec<String>),
+}
+
+impl VcfValue {
+    pub fn len(&self) -> usize {
+        match self {
+            VcfValue::Flag => 0,
+            VcfValue::Integer(v) => v.len(),
+            VcfValue::Float(v) => v.len(),
+            VcfValue::String(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_integers(&self) -> Option<&Vec<Option<i32>>> {
+        match self {
+            VcfValue::Integer(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_floats(&self) -> Option<&Vec<Option<f32>>> {
+        match self {
+            VcfValue::Float(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_strings(&self) -> Option<&Vec<String>> {
+        match self {
+            VcfValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn select(&self, idxs: &[usize]) -> Self {
+        match self {
+            VcfValue::Flag => VcfValue::Flag,
+            VcfValue::Integer(v) => VcfValue::Integer(idxs.iter().map(|&i| v[i]).collect()),
+            VcfValue::Float(v) => VcfValue::Float(idxs.iter().map(|&i| v[i]).collect()),
+            VcfValue::String(v) => VcfValue::String(idxs.iter().map(|&i| v[i].clone()).collect()),
+        }
+    }
+
+    /// keep the values of allele (1-based alt index) when splitting a multi-allelic record.
+    /// values whose length does not match the declared number are kept as is
+    fn select_allele(&self, number: VcfFieldNumber, n_alleles: usize, allele: usize) -> Self {
+        let idxs = match number {
+            VcfFieldNumber::AltAlleles if self.len() == n_alleles - 1 => vec![allele - 1],
+            VcfFieldNumber::Alleles if self.len() == n_alleles => vec![0, allele],
+            VcfFieldNumber::Genotypes if self.len() == n_alleles => vec![0, allele],
+            VcfFieldNumber::Genotypes if self.len() == n_alleles * (n_alleles + 1) / 2 => {
+                // diploid genotype (j, k), j <= k, is stored at k * (k + 1) / 2 + j
+                let offset = allele * (allele + 1) / 2;
+                vec![0, offset, offset + allele]
+            }
+            _ => return self.clone(),
+        };
+        self.select(&idxs)
+    }
+}
+
+fn integer_value(values: &[i32]) -> VcfValue {
+    VcfValue::Integer(
+        values
+            .iter()
+            .map(|v| if v.is_missing() { None } else { Some(*v) })
+            .collect(),
+    )
+}
+
+fn float_value(values: &[f32]) -> VcfValue {
+    VcfValue::Float(
+        values
+            .iter()
+            .map(|v| if v.is_missing() { None } else { Some(*v) })
+            .collect(),
+    )
+}
+
+fn string_value(value: &[u8]) -> VcfValue {
+    VcfValue::String(
+        String::from_utf8_lossy(value)
+            .split(',')
+            .map(|v| v.to_string())
+            .collect(),
+    )
+}
+
+/// allele indices of one sample, None for '.'
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VcfGenotype {
+    pub alleles: Vec<Option<usize>>,
+    pub phased: bool,
+}
+
+impl VcfGenotype {
+    pub fn ploidy(&self) -> usize {
+        self.alleles.len()
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.alleles.iter().all(|v| v.is_none())
+    }
+
+    pub fn is_hom_ref(&self) -> bool {
+        !self.alleles.is_empty() && self.alleles.iter().all(|v| *v == Some(0))
+    }
+
+    pub fn is_het(&self) -> bool {
+        let called = self.alleles.iter().flatten().collect::<Vec<_>>();
+        called.len() > 1 && called.iter().any(|v| *v != called[0])
+    }
+
+    pub fn is_hom_alt(&self) -> bool {
+        match self.alleles.first() {
+            Some(Some(first)) if *first > 0 => self.alleles.iter().all(|v| *v == Some(*first)),
+            _ => false,
+        }
+    }
+
+    fn from_encoded(encoded: &[i32]) -> Self {
+        let alleles = encoded.iter().map(|&v| GenotypeAllele::from(v)).collect::<Vec<_>>();
+        // the first allele is always unphased in bcf, phasing is carried by the later ones
+        let phased = alleles.len() > 1
+            && alleles[1..].iter().all(|v| {
+                matches!(v, GenotypeAllele::Phased(_) | GenotypeAllele::PhasedMissing)
+            });
+        Self {
+            alleles: alleles.into_iter().map(|v| v.index().map(|v| v as usize)).collect(),
+            phased,
+        }
+    }
+}
+
+impl Display for VcfGenotype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sep = if self.phased { "|" } else { "/" };
+        let alleles = self
+            .alleles
+            .iter()
+            .map(|v| v.map(|v| v.to_string()).unwrap_or(".".to_string()))
+            .collect::<Vec<_>>();
+        write!(f, "{}", alleles.join(sep))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VcfSample {
+    pub genotype: Option<VcfGenotype>,
+    /// FORMAT fields except GT
+    pub fields: BTreeMap<String, VcfValue>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum VariantType {
+    /// ALT is '.' or identical to REF
+    NoVariant,
+    Snv,
+    Mnv,
+    Insertion,
+    Deletion,
+    /// length changing substitution, e.g. ATG -> CC
+    Complex,
+    /// <DEL>, breakends, '*'
+    Symbolic,
+}
+
+/// classify ref -> alt after trimming the shared suffix and prefix
+pub fn classify_variant(ref_allele: &str, alt_allele: &str) -> VariantType {
+    if alt_allele == "."
+        || alt_allele == "*"
+        || alt_allele.starts_with('<')
+        || alt_allele.contains(['[', ']'])
+    {
+        return if alt_allele == "." {
+            VariantType::NoVariant
+        } else {
+            VariantType::Symbolic
+        };
+    }
+
+    let (r, a) = (ref_allele.as_bytes(), alt_allele.as_bytes());
+    let suffix = r
+        .iter()
+        .rev()
+        .zip(a.iter().rev())
+        .take_while(|(x, y)| x.eq_ignore_ascii_case(y))
+        .count();
+    let (r, a) = (&r[..r.len() - suffix], &a[..a.len() - suffix]);
+    let prefix = r
+        .iter()
+        .zip(a.iter())
+        .take_while(|(x, y)| x.eq_ignore_ascii_case(y))
+        .count();
+    let (r, a) = (&r[prefix..], &a[prefix..]);
+
+    match (r.len(), a.len()) {
+        (0, 0) => VariantType::NoVariant,
+        (0, _) => VariantType::Insertion,
+        (_, 0) => VariantType::Deletion,
+        (1, 1) => VariantType::Snv,
+        (x, y) if x == y => VariantType::Mnv,
+        _ => VariantType::Complex,
+    }
+}
+
+/// one VCF/BCF line. pos is 0-based
+#[derive(Debug, Clone, PartialEq)]
+pub struct VcfRecord {
+    pub chrom: String,
+    pub pos: usize,
+    pub id: Option<String>,
+    pub ref_allele: String,
+    pub alt_alleles: Vec<String>,
+    pub qual: Option<f32>,
+    /// empty for '.'
+    pub filters: Vec<String>,
+    pub info: BTreeMap<String, VcfValue>,
+    pub samples: Vec<VcfSample>,
+}
+
+impl VcfRecord {
+    pub fn from_bcf_record(record: &bcf::Record, header: &VcfHeader) -> anyhow::Result<Self> {
+        let header_view = record.header();
+        let rid = record.rid().context("vcf record without contig")?;
+        let chrom = String::from_utf8_lossy(header_view.rid2name(rid)?).to_string();
+
+        let id = record.id();
+        let id = if id == b"." {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&id).to_string())
+        };
+
+        let alleles = record
+            .alleles()
+            .into_iter()
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .collect::<Vec<_>>();
+
+        let qual = record.qual();
+        let qual = if qual.is_missing() { None } else { Some(qual) };
+
+        let filters = record
+            .filters()
+            .map(|v| String::from_utf8_lossy(&header_view.id_to_name(v)).to_string())
+            .collect::<Vec<_>>();
+
+        let mut info = BTreeMap::new();
+        for def in &header.infos {
+            let tag = def.id.as_bytes();
+            let value = match def.field_type {
+                VcfFieldType::Flag => record.info(tag).flag()?.then_some(VcfValue::Flag),
+                VcfFieldType::Integer => record.info(tag).integer()?.map(|v| integer_value(&v)),
+                VcfFieldType::Float => record.info(tag).float()?.map(|v| float_value(&v)),
+                VcfFieldType::String => record.info(tag).string()?.map(|v| {
+                    VcfValue::String(
+                        v.iter()
+                            .map(|s| String::from_utf8_lossy(s).to_string())
+                            .collect(),
+                    )
+                }),
+            };
+            if let Some(value) = value {
+                info.insert(def.id.clone(), value);
+            }
+        }
+
+        let n_samples = record.sample_count() as usize;
+        let mut samples = vec![VcfSample::default(); n_samples];
+        if n_samples > 0 {
+            for def in &header.formats {
+                let tag = def.id.as_bytes();
+                // a FORMAT tag not present in the record is an Err, not a missing value
+                if def.id == "GT" {
+                    if let Ok(gts) = record.format(tag).integer() {
+                        gts.iter().enumerate().for_each(|(i, gt)| {
+                            samples[i].genotype = Some(VcfGenotype::from_encoded(gt));
+                        });
+                    }
+                    continue;
+                }
+                let values = match def.field_type {
+                    VcfFieldType::Integer => record
+                        .format(tag)
+                        .integer()
+                        .map(|v| v.iter().map(|v| integer_value(v)).collect::<Vec<_>>()),
+                    VcfFieldType::Float => record
+                        .format(tag)
+                        .float()
+                        .map(|v| v.iter().map(|v| float_value(v)).collect::<Vec<_>>()),
+                    VcfFieldType::String | VcfFieldType::Flag => record
+                        .format(tag)
+                        .string()
+                        .map(|v| v.iter().map(|v| string_value(v)).collect::<Vec<_>>()),
+                };
+                if let Ok(values) = values {
+                    values.into_iter().enumerate().for_each(|(i, value)| {
+                        samples[i].fields.insert(def.id.clone(), value);
+                    });
+                }
+            }
+        }
+
+        Ok(Self {
+            chrom,
+            pos: record.pos() as usize,
+            id,
+            ref_allele: alleles[0].clone(),
+            alt_alleles: alleles[1..].to_vec(),
+            qual,
+            filters,
+            info,
+            samples,
+        })
+    }
+
+    /// 0-based, exclusive end of the REF allele
+    pub fn end(&self) -> usize {
+        self.pos + self.ref_allele.len()
+    }
+
+    pub fn is_pass(&self) -> bool {
+        self.filters.iter().all(|v| v == "PASS")
+    }
+
+    pub fn is_multiallelic(&self) -> bool {
+        self.alt_alleles.len() > 1
+    }
+
+    pub fn variant_types(&self) -> Vec<VariantType> {
+        self.alt_alleles
+            .iter()
+            .map(|alt| classify_variant(&self.ref_allele, alt))
+            .collect()
+    }
+
+    /// one record per alt allele. Number=A/R/G fields keep the values of that allele.
+    /// genotypes are recoded, the kept alt becomes 1 and any other alt becomes 0,
+    /// the same as bcftools norm -m-
+    pub fn split_multiallelic(&self, header: &VcfHeader) -> Vec<VcfRecord> {
+        if !self.is_multiallelic() {
+            return vec![self.clone()];
+        }
+        let n_alleles = self.alt_alleles.len() + 1;
+        (1..n_alleles)
+            .map(|allele| {
+                let mut record = self.clone();
+                record.alt_alleles = vec![self.alt_alleles[allele - 1].clone()];
+                record.info.iter_mut().for_each(|(id, value)| {
+                    if let Some(def) = header.info_def(id) {
+                        *value = value.select_allele(def.number, n_alleles, allele);
+                    }
+                });
+                record.samples.iter_mut().for_each(|sample| {
+                    sample.fields.iter_mut().for_each(|(id, value)| {
+                        if let Some(def) = header.format_def(id) {
+                            *value = value.select_allele(def.number, n_alleles, allele);
+                        }
+                    });
+                    if let Some(gt) = sample.genotype.as_mut() {
+                        gt.alleles.iter_mut().flatten().for_each(|v| {
+                            *v = if *v == allele { 1 } else { 0 };
+                        });
+                    }
+                });
+                record
+            })
+            .collect()
+    }
+}
+
+/// VCF, bgzipped VCF or BCF, read through htslib
+pub struct VcfReader {
+    reader: bcf::Reader,
+    header: VcfHeader,
+}
+
+impl VcfReader {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let reader = bcf::Reader::from_path(path.as_ref())
+            .with_context(|| format!("open vcf file {:?} error", path.as_ref()))?;
+        let header = VcfHeader::from_header_view(reader.header())?;
+        Ok(Self { reader, header })
+    }
+
+    pub fn header(&self) -> &VcfHeader {
+        &self.header
+    }
+
+    pub fn header_view(&self) -> &HeaderView {
+        self.reader.header()
+    }
+}
+
+impl Iterator for VcfReader {
+    type Item = anyhow::Result<VcfRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = self.reader.empty_record();
+        match self.reader.read(&mut record) {
+            None => None,
+            Some(Err(e)) => Some(Err(e.into())),
+            Some(Ok(())) => Some(VcfRecord::from_bcf_record(&record, &self.header)),
+        }
+    }
+}
+
+/// random access over a bgzipped VCF (.tbi / .csi) or BCF (.csi)
+pub struct IndexedVcfReader {
+    reader: bcf::IndexedReader,
+    header: VcfHeader,
+}
+
+impl IndexedVcfReader {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let reader = bcf::IndexedReader::from_path(path.as_ref())
+            .with_context(|| format!("open indexed vcf file {:?} error", path.as_ref()))?;
+        let header = VcfHeader::from_header_view(reader.header())?;
+        Ok(Self { reader, header })
+    }
+
+    pub fn header(&self) -> &VcfHeader {
+        &self.header
+    }
+
+    /// records whose REF overlaps [start, end) of chrom. unknown chrom gives no records
+    pub fn fetch(
+        &mut self,
+        chrom: &str,
+        start: usize,
+        end: usize,
+    ) -> anyhow::Result<Vec<VcfRecord>> {
+        let rid = match self.reader.header().name2rid(chrom.as_bytes()) {
+            Ok(rid) => rid,
+            Err(_) => return Ok(vec![]),
+        };
+        if end <= start {
+            return Ok(vec![]);
+        }
+        self.reader.fetch(rid, start as u64, Some(end as u64 - 1))?;
+
+        let mut records = vec![];
+        let mut record = self.reader.empty_record();
+        while let Some(res) = self.reader.read(&mut record) {
+            res?;
+            let record = VcfRecord::from_bcf_record(&record, &self.header)?;
+            if record.pos < end && record.end() > start {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// build a .tbi index for a bgzipped VCF file
+pub fn build_vcf_tabix_index<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
+    let path_str = path
+        .as_ref()
+        .to_str()
+        .with_context(|| format!("invalid path {:?}", path.as_ref()))?;
+    let c_path = CString::new(path_str)?;
+    let ret = unsafe { htslib::tbx_index_build(c_path.as_ptr(), 0, &htslib::tbx_conf_vcf) };
+    if ret != 0 {
+        bail!("build tabix index for {} error, code: {}", path_str, ret);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     // use std::{collections::HashSet, io::BufReader};
@@ -175,6 +815,175 @@ mod test {
     //     pbar.finish();
     //     eprintln!("{:?}", chroms);
     // }
-    
 
-}
\ No newline at end of file
+    use std::io::Write;
+
+    use rust_htslib::bcf::{self, Read};
+
+    use super::{
+        build_vcf_tabix_index, classify_variant, IndexedVcfReader, VariantType, VcfReader,
+        VcfReaderIter, VcfRowData, VcfValue,
+    };
+
+    const VCF_CONTENT: &str = "##fileformat=VCFv4.2
+##contig=<ID=chr1,length=1000>
+##contig=<ID=chr2,length=500>
+##FILTER=<ID=q10,Description=\"Quality below 10\">
+##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Total depth\">
+##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency\">
+##INFO=<ID=DB,Number=0,Type=Flag,Description=\"dbSNP membership\">
+##INFO=<ID=ANN,Number=.,Type=String,Description=\"Annotation\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+##FORMAT=<ID=GQ,Number=1,Type=Integer,Description=\"Genotype quality\">
+##FORMAT=<ID=AD,Number=R,Type=Integer,Description=\"Allelic depths\">
+##FORMAT=<ID=PL,Number=G,Type=Integer,Description=\"Phred likelihoods\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2
+chr1\t100\trs1\tA\tG\t31.5\tPASS\tDP=20;AF=0.5;DB;ANN=x,y\tGT:GQ:AD:PL\t0/1:30:10,10:30,0,30\t1|1:.:0,20:60,30,0
+chr1\t200\t.\tA\tC,AT\t.\tq10\tDP=15;AF=0.25,0.5\tGT:GQ:AD:PL\t1/2:20:1,5,9:90,60,50,40,0,30\t0/2:.:7,0,8:10,20,30,0,40,50
+chr1\t300\t.\tATG\tA\t50\t.\t.\tGT\t./.\t0
+chr2\t10\t.\tACG\tTCA\t50\tPASS\tDP=3\tGT:GQ\t0/1:10\t0/0:20
+";
+
+    fn write_tmp(suffix: &str, content: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("gskits_test_{}{}", std::process::id(), suffix));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_vcf_row_data_qual() {
+        let row = VcfRowData::from_str("chr1\t10\t.\tA\tG\t.\tPASS\t.").unwrap();
+        assert_eq!(row.pos, 9);
+        assert_eq!((row.qual, row.phred_q), (None, 0));
+        let row = VcfRowData::from_str("chr1\t10\t.\tA\tG\t31.5\tPASS\t.").unwrap();
+        assert_eq!((row.qual, row.phred_q), (Some(31.5), 32));
+
+        assert!(VcfRowData::from_str("chr1\tx\t.\tA\tG\t30").is_err());
+        assert!(VcfRowData::from_str("chr1\t0\t.\tA\tG\t30").is_err());
+        assert!(VcfRowData::from_str("chr1\t10\t.\tA\tG\tq").is_err());
+        assert!(VcfRowData::from_str("chr1\t10\t.\tA").is_err());
+
+        let content = "##fileformat=VCFv4.2\nchr1\t10\t.\tA\tG\t30\nchr1\tx\t.\tA\tG\t30\n";
+        let mut content = content.as_bytes();
+        let rows = VcfReaderIter::new(&mut content).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].as_ref().unwrap().phred_q, 30);
+        assert!(rows[1].is_err());
+    }
+
+    #[test]
+    fn test_classify_variant() {
+        assert_eq!(classify_variant("A", "G"), VariantType::Snv);
+        assert_eq!(classify_variant("ATG", "ACG"), VariantType::Snv);
+        assert_eq!(classify_variant("AC", "GT"), VariantType::Mnv);
+        assert_eq!(classify_variant("A", "ATT"), VariantType::Insertion);
+        assert_eq!(classify_variant("ATT", "T"), VariantType::Deletion);
+        assert_eq!(classify_variant("ATG", "CC"), VariantType::Complex);
+        assert_eq!(classify_variant("A", "<DEL>"), VariantType::Symbolic);
+        assert_eq!(classify_variant("A", "*"), VariantType::Symbolic);
+        assert_eq!(classify_variant("A", "."), VariantType::NoVariant);
+        assert_eq!(classify_variant("A", "a"), VariantType::NoVariant);
+    }
+
+    #[test]
+    fn test_vcf_reader() {
+        let path = write_tmp(".vcf", VCF_CONTENT);
+        let mut reader = VcfReader::from_path(&path).unwrap();
+        let header = reader.header().clone();
+        assert_eq!(header.samples, vec!["s1".to_string(), "s2".to_string()]);
+        assert_eq!(header.contigs[0], ("chr1".to_string(), Some(1000)));
+        let records = reader.by_ref().collect::<anyhow::Result<Vec<_>>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 4);
+
+        let rec = &records[0];
+        assert_eq!((rec.pos, rec.id.as_deref()), (99, Some("rs1")));
+        assert_eq!(rec.qual, Some(31.5));
+        assert!(rec.is_pass());
+        assert_eq!(rec.info["DP"], VcfValue::Integer(vec![Some(20)]));
+        assert_eq!(rec.info["DB"], VcfValue::Flag);
+        assert_eq!(
+            rec.info["ANN"],
+            VcfValue::String(vec!["x".to_string(), "y".to_string()])
+        );
+        assert_eq!(rec.samples[0].genotype.as_ref().unwrap().to_string(), "0/1");
+        assert!(rec.samples[0].genotype.as_ref().unwrap().is_het());
+        assert_eq!(rec.samples[1].genotype.as_ref().unwrap().to_string(), "1|1");
+        assert!(rec.samples[1].genotype.as_ref().unwrap().is_hom_alt());
+        assert_eq!(rec.samples[1].fields["GQ"], VcfValue::Integer(vec![None]));
+
+        let rec = &records[1];
+        assert_eq!(rec.qual, None);
+        assert!(!rec.is_pass());
+        assert_eq!(rec.filters, vec!["q10".to_string()]);
+        assert_eq!(rec.variant_types(), vec![VariantType::Snv, VariantType::Insertion]);
+
+        let split = rec.split_multiallelic(&header);
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[1].alt_alleles, vec!["AT".to_string()]);
+        assert_eq!(split[1].info["AF"], VcfValue::Float(vec![Some(0.5)]));
+        assert_eq!(split[1].info["DP"], VcfValue::Integer(vec![Some(15)]));
+        assert_eq!(split[0].samples[0].genotype.as_ref().unwrap().to_string(), "1/0");
+        assert_eq!(split[1].samples[0].genotype.as_ref().unwrap().to_string(), "0/1");
+        assert_eq!(split[1].samples[1].genotype.as_ref().unwrap().to_string(), "0/1");
+        assert_eq!(
+            split[1].samples[0].fields["AD"],
+            VcfValue::Integer(vec![Some(1), Some(9)])
+        );
+        assert_eq!(
+            split[1].samples[0].fields["PL"],
+            VcfValue::Integer(vec![Some(90), Some(40), Some(30)])
+        );
+
+        let rec = &records[2];
+        assert_eq!(rec.variant_types(), vec![VariantType::Deletion]);
+        assert_eq!(rec.end(), 302);
+        assert!(rec.is_pass());
+        assert!(rec.samples[0].genotype.as_ref().unwrap().is_missing());
+        assert_eq!(rec.samples[1].genotype.as_ref().unwrap().ploidy(), 1);
+        assert_eq!(records[3].variant_types(), vec![VariantType::Mnv]);
+    }
+
+    #[test]
+    fn test_indexed_vcf_reader_and_bcf() {
+        let path = std::env::temp_dir().join(format!("gskits_test_{}.vcf.gz", std::process::id()));
+        {
+            let mut writer = rust_htslib::bgzf::Writer::from_path(&path).unwrap();
+            writer.write_all(VCF_CONTENT.as_bytes()).unwrap();
+        }
+        build_vcf_tabix_index(&path).unwrap();
+
+        let mut reader = IndexedVcfReader::from_path(&path).unwrap();
+        let hits = reader.fetch("chr1", 150, 301).unwrap();
+        assert_eq!(hits.iter().map(|v| v.pos).collect::<Vec<_>>(), vec![199, 299]);
+        assert_eq!(reader.fetch("chr1", 301, 302).unwrap().len(), 1);
+        assert_eq!(reader.fetch("chr2", 0, 500).unwrap().len(), 1);
+        assert!(reader.fetch("chrX", 0, 500).unwrap().is_empty());
+
+        let gz_records = VcfReader::from_path(&path)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+
+        let bcf_path = std::env::temp_dir().join(format!("gskits_test_{}.bcf", std::process::id()));
+        {
+            let mut reader = bcf::Reader::from_path(&path).unwrap();
+            let header = bcf::Header::from_template(reader.header());
+            let mut writer =
+                bcf::Writer::from_path(&bcf_path, &header, false, bcf::Format::Bcf).unwrap();
+            for record in reader.records() {
+                writer.write(&record.unwrap()).unwrap();
+            }
+        }
+        let bcf_records = VcfReader::from_path(&bcf_path)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(gz_records, bcf_records);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.tbi", path.to_str().unwrap())).unwrap();
+        std::fs::remove_file(&bcf_path).unwrap();
+    }
+}
@@ -0,0 +1,612 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use rust_htslib::bcf::{self, record::Numeric, Format};
+
+use crate::gsbam::bam_header_ext::{BamHeaderExt, HeaderSQ};
+
+use super::vcf_reader::{
+    VcfFieldDef, VcfFieldNumber, VcfFieldType, VcfHeader, VcfRecord, VcfSample, VcfValue,
+};
+
+const VECTOR_END_INTEGER: i32 = i32::MIN + 1;
+const VECTOR_END_FLOAT_BITS: u32 = 0x7F80_0002;
+
+impl VcfFieldNumber {
+    fn to_header_str(self) -> String {
+        match self {
+            VcfFieldNumber::Fixed(n) => n.to_string(),
+            VcfFieldNumber::AltAlleles => "A".to_string(),
+            VcfFieldNumber::Alleles => "R".to_string(),
+            VcfFieldNumber::Genotypes => "G".to_string(),
+            VcfFieldNumber::Variable => ".".to_string(),
+        }
+    }
+}
+
+impl VcfFieldType {
+    fn to_header_str(self) -> &'static str {
+        match self {
+            VcfFieldType::Flag => "Flag",
+            VcfFieldType::Integer => "Integer",
+            VcfFieldType::Float => "Float",
+            VcfFieldType::String => "String",
+        }
+    }
+}
+
+impl VcfHeader {
+    /// contigs from bam SQ lines, GT FORMAT is always declared
+    pub fn new(seqs: &[HeaderSQ], samples: Vec<String>) -> Self {
+        let mut header = VcfHeader {
+            contigs: seqs
+                .iter()
+                .map(|seq| (seq.get_name().to_string(), Some(seq.get_len())))
+                .collect(),
+            samples,
+            ..Default::default()
+        };
+        header.add_format("GT", VcfFieldNumber::Fixed(1), VcfFieldType::String, "Genotype");
+        header
+    }
+
+    pub fn from_bam_header(header: &mut BamHeaderExt, samples: Vec<String>) -> Self {
+        let seqs = header.get_all_seqs_cached().cloned().unwrap_or_default();
+        Self::new(&seqs, samples)
+    }
+
+    pub fn add_filter(&mut self, id: &str) -> &mut Self {
+        if !self.filters.iter().any(|v| v == id) {
+            self.filters.push(id.to_string());
+        }
+        self
+    }
+
+    pub fn add_info(
+        &mut self,
+        id: &str,
+        number: VcfFieldNumber,
+        field_type: VcfFieldType,
+        description: &str,
+    ) -> &mut Self {
+        self.infos.retain(|v| v.id != id);
+        self.infos.push(VcfFieldDef {
+            id: id.to_string(),
+            number,
+            field_type,
+            description: description.to_string(),
+        });
+        self
+    }
+
+    pub fn add_format(
+        &mut self,
+        id: &str,
+        number: VcfFieldNumber,
+        field_type: VcfFieldType,
+        description: &str,
+    ) -> &mut Self {
+        self.formats.retain(|v| v.id != id);
+        self.formats.push(VcfFieldDef {
+            id: id.to_string(),
+            number,
+            field_type,
+            description: description.to_string(),
+        });
+        self
+    }
+
+    pub fn to_bcf_header(&self) -> bcf::Header {
+        let mut header = bcf::Header::new();
+        for (name, len) in &self.contigs {
+            let line = match len {
+                Some(len) => format!("##contig=<ID={},length={}>", name, len),
+                None => format!("##contig=<ID={}>", name),
+            };
+            header.push_record(line.as_bytes());
+        }
+        for filter in self.filters.iter().filter(|v| *v != "PASS") {
+            let line = format!("##FILTER=<ID={},Description=\"{}\">", filter, filter);
+            header.push_record(line.as_bytes());
+        }
+        for (kind, defs) in [("INFO", &self.infos), ("FORMAT", &self.formats)] {
+            for def in defs {
+                let line = format!(
+                    "##{}=<ID={},Number={},Type={},Description=\"{}\">",
+                    kind,
+                    def.id,
+                    def.number.to_header_str(),
+                    def.field_type.to_header_str(),
+                    def.description.replace('"', "'")
+                );
+                header.push_record(line.as_bytes());
+            }
+        }
+        for sample in &self.samples {
+            header.push_sample(sample.as_bytes());
+        }
+        header
+    }
+}
+
+/// .vcf plain text, .vcf.gz bgzip, .bcf binary
+pub struct VcfWriter {
+    writer: bcf::Writer,
+    header: VcfHeader,
+}
+
+impl VcfWriter {
+    pub fn from_path<P: AsRef<Path>>(path: P, header: &VcfHeader) -> anyhow::Result<Self> {
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        let (uncompressed, format) = if path_str.ends_with(".bcf") {
+            (false, Format::Bcf)
+        } else if path_str.ends_with(".gz") {
+            (false, Format::Vcf)
+        } else {
+            (true, Format::Vcf)
+        };
+        let writer =
+            bcf::Writer::from_path(path.as_ref(), &header.to_bcf_header(), uncompressed, format)
+                .with_context(|| format!("create vcf file {} error", path_str))?;
+        Ok(Self {
+            writer,
+            header: header.clone(),
+        })
+    }
+
+    pub fn header(&self) -> &VcfHeader {
+        &self.header
+    }
+
+    pub fn write(&mut self, record: &VcfRecord) -> anyhow::Result<()> {
+        let mut out = self.writer.empty_record();
+        let rid = self
+            .writer
+            .header()
+            .name2rid(record.chrom.as_bytes())
+            .with_context(|| format!("contig {} not in vcf header", record.chrom))?;
+        out.set_rid(Some(rid));
+        out.set_pos(record.pos as i64);
+        if let Some(id) = &record.id {
+            out.set_id(id.as_bytes())?;
+        }
+
+        let mut alleles = vec![record.ref_allele.as_bytes()];
+        alleles.extend(record.alt_alleles.iter().map(|v| v.as_bytes()));
+        out.set_alleles(&alleles)?;
+        out.set_qual(record.qual.unwrap_or(f32::missing()));
+        if !record.filters.is_empty() {
+            let filters = record.filters.iter().map(|v| v.as_bytes()).collect::<Vec<_>>();
+            out.set_filters(&filters)?;
+        }
+
+        for (id, value) in &record.info {
+            let tag = id.as_bytes();
+            match value {
+                VcfValue::Flag => out.push_info_flag(tag)?,
+                VcfValue::Integer(v) => out.push_info_integer(
+                    tag,
+                    &v.iter().map(|v| v.unwrap_or(i32::missing())).collect::<Vec<_>>(),
+                )?,
+                VcfValue::Float(v) => out.push_info_float(
+                    tag,
+                    &v.iter().map(|v| v.unwrap_or(f32::missing())).collect::<Vec<_>>(),
+                )?,
+                VcfValue::String(v) => out.push_info_string(
+                    tag,
+                    &v.iter().map(|v| v.as_bytes()).collect::<Vec<_>>(),
+                )?,
+            }
+        }
+
+        // htslib refuses records whose sample columns do not match the header,
+        // so a site only record gets a missing GT for every sample
+        if !self.header.samples.is_empty() {
+            let empty_samples;
+            let samples = if record.samples.is_empty() {
+                empty_samples = vec![VcfSample::default(); self.header.samples.len()];
+                &empty_samples
+            } else {
+                &record.samples
+            };
+            if samples.len() != self.header.samples.len() {
+                bail!(
+                    "record has {} samples, header has {}",
+                    samples.len(),
+                    self.header.samples.len()
+                );
+            }
+            push_genotypes(&mut out, samples)?;
+            for def in self.header.formats.iter().filter(|v| v.id != "GT") {
+                push_format(&mut out, def, samples)?;
+            }
+        }
+
+        self.writer.write(&out)?;
+        Ok(())
+    }
+}
+
+/// samples with a lower ploidy are padded with vector end, samples without genotype get '.'
+fn push_genotypes(out: &mut bcf::Record, samples: &[VcfSample]) -> anyhow::Result<()> {
+    let ploidy = samples
+        .iter()
+        .filter_map(|v| v.genotype.as_ref().map(|gt| gt.ploidy()))
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let mut encoded = vec![];
+    for sample in samples {
+        let alleles = sample
+            .genotype
+            .as_ref()
+            .map(|gt| gt.alleles.clone())
+            .filter(|v| !v.is_empty())
+            .unwrap_or(vec![None]);
+        let phased = sample.genotype.as_ref().map(|gt| gt.phased).unwrap_or(false);
+        for i in 0..ploidy {
+            let value = match alleles.get(i) {
+                // (allele + 1) << 1 | phased, the first allele is never marked phased
+                Some(allele) => {
+                    let allele = allele.map(|v| v as i32).unwrap_or(-1);
+                    ((allele + 1) << 1) | (phased && i > 0) as i32
+                }
+                None => VECTOR_END_INTEGER,
+            };
+            encoded.push(value);
+        }
+    }
+    out.push_format_integer(b"GT", &encoded)?;
+    Ok(())
+}
+
+fn push_format(
+    out: &mut bcf::Record,
+    def: &VcfFieldDef,
+    samples: &[VcfSample],
+) -> anyhow::Result<()> {
+    let values = samples.iter().map(|v| v.fields.get(&def.id)).collect::<Vec<_>>();
+    if values.iter().all(|v| v.is_none()) {
+        return Ok(());
+    }
+    let tag = def.id.as_bytes();
+    let width = values
+        .iter()
+        .map(|v| v.map(|v| v.len()).unwrap_or(1))
+        .max()
+        .unwrap_or(1)
+        .max(1);
+
+    match def.field_type {
+        VcfFieldType::Integer => {
+            let mut data = vec![];
+            for value in values {
+                let items = value.and_then(|v| v.as_integers()).cloned().unwrap_or(vec![None]);
+                data.extend((0..width).map(|i| match items.get(i) {
+                    Some(v) => v.unwrap_or(i32::missing()),
+                    None => VECTOR_END_INTEGER,
+                }));
+            }
+            out.push_format_integer(tag, &data)?;
+        }
+        VcfFieldType::Float => {
+            let mut data = vec![];
+            for value in values {
+                let items = value.and_then(|v| v.as_floats()).cloned().unwrap_or(vec![None]);
+                data.extend((0..width).map(|i| match items.get(i) {
+                    Some(v) => v.unwrap_or(f32::missing()),
+                    None => f32::from_bits(VECTOR_END_FLOAT_BITS),
+                }));
+            }
+            out.push_format_float(tag, &data)?;
+        }
+        VcfFieldType::String | VcfFieldType::Flag => {
+            let data = values
+                .iter()
+                .map(|v| {
+                    v.and_then(|v| v.as_strings())
+                        .map(|v| v.join(","))
+                        .filter(|v| !v.is_empty())
+                        .unwrap_or(".".to_string())
+                })
+                .collect::<Vec<_>>();
+            out.push_format_string(tag, &data.iter().map(|v| v.as_bytes()).collect::<Vec<_>>())?;
+        }
+    }
+    Ok(())
+}
+
+fn is_symbolic(allele: &str) -> bool {
+    allele.is_empty()
+        || allele == "."
+        || allele == "*"
+        || allele.starts_with('<')
+        || allele.contains(['[', ']'])
+}
+
+/// remove the bases shared by all alleles, suffix first then prefix, keeping at least one base.
+/// returns the new 0-based pos and alleles (ref first)
+pub fn trim_alleles(pos: usize, alleles: &[String]) -> (usize, Vec<String>) {
+    if alleles.len() < 2 || alleles.iter().any(|v| is_symbolic(v)) {
+        return (pos, alleles.to_vec());
+    }
+    let mut alleles = alleles.iter().map(|v| v.as_bytes().to_vec()).collect::<Vec<_>>();
+    let mut pos = pos;
+
+    while alleles.iter().all(|v| v.len() >= 2)
+        && alleles.iter().all(|v| v.last() == alleles[0].last())
+    {
+        alleles.iter_mut().for_each(|v| {
+            v.pop();
+        });
+    }
+    while alleles.iter().all(|v| v.len() >= 2) && alleles.iter().all(|v| v[0] == alleles[0][0]) {
+        alleles.iter_mut().for_each(|v| {
+            v.remove(0);
+        });
+        pos += 1;
+    }
+    (pos, alleles.into_iter().map(|v| String::from_utf8(v).unwrap()).collect())
+}
+
+/// left align and trim (vt normalize). ref_seq is the whole contig sequence,
+/// pos is 0-based and the ref allele must match ref_seq at pos
+pub fn normalize_alleles(
+    ref_seq: &[u8],
+    pos: usize,
+    alleles: &[String],
+) -> anyhow::Result<(usize, Vec<String>)> {
+    if alleles.len() < 2 || alleles.iter().any(|v| is_symbolic(v)) {
+        return Ok((pos, alleles.to_vec()));
+    }
+    let ref_allele = alleles[0].as_bytes();
+    if pos + ref_allele.len() > ref_seq.len()
+        || !ref_seq[pos..pos + ref_allele.len()].eq_ignore_ascii_case(ref_allele)
+    {
+        bail!("ref allele {} does not match reference at {}", alleles[0], pos);
+    }
+
+    let mut alleles = alleles.iter().map(|v| v.as_bytes().to_vec()).collect::<Vec<_>>();
+    let mut pos = pos;
+    loop {
+        let last = alleles[0].last().copied();
+        let same_last = last.is_some()
+            && alleles
+                .iter()
+                .all(|v| v.last().is_some_and(|b| b.eq_ignore_ascii_case(&last.unwrap())));
+        if same_last {
+            alleles.iter_mut().for_each(|v| {
+                v.pop();
+            });
+            continue;
+        }
+        if alleles.iter().any(|v| v.is_empty()) {
+            if pos == 0 {
+                // contig start, pad on the right as vt/bcftools do
+                let Some(&next) = ref_seq.get(alleles[0].len()) else {
+                    bail!("can't pad alleles {:?} at the contig start", alleles);
+                };
+                alleles.iter_mut().for_each(|v| v.push(next.to_ascii_uppercase()));
+                break;
+            }
+            pos -= 1;
+            alleles
+                .iter_mut()
+                .for_each(|v| v.insert(0, ref_seq[pos].to_ascii_uppercase()));
+            continue;
+        }
+        break;
+    }
+
+    let alleles = alleles.into_iter().map(|v| String::from_utf8(v).unwrap()).collect::<Vec<_>>();
+    Ok(trim_alleles(pos, &alleles))
+}
+
+impl VcfRecord {
+    /// a site record without samples, for callers building variants from scratch
+    pub fn new(chrom: &str, pos: usize, ref_allele: &str, alt_alleles: Vec<String>) -> Self {
+        Self {
+            chrom: chrom.to_string(),
+            pos,
+            id: None,
+            ref_allele: ref_allele.to_string(),
+            alt_alleles,
+            qual: None,
+            filters: vec![],
+            info: Default::default(),
+            samples: vec![],
+        }
+    }
+
+    fn alleles(&self) -> Vec<String> {
+        let mut alleles = vec![self.ref_allele.clone()];
+        alleles.extend(self.alt_alleles.iter().cloned());
+        alleles
+    }
+
+    fn set_alleles(&mut self, pos: usize, alleles: Vec<String>) {
+        self.pos = pos;
+        self.ref_allele = alleles[0].clone();
+        self.alt_alleles = alleles[1..].to_vec();
+    }
+
+    pub fn trim_alleles(&mut self) {
+        let (pos, alleles) = trim_alleles(self.pos, &self.alleles());
+        self.set_alleles(pos, alleles);
+    }
+
+    /// left align and trim against the contig sequence
+    pub fn normalize(&mut self, ref_seq: &[u8]) -> anyhow::Result<()> {
+        let (pos, alleles) = normalize_alleles(ref_seq, self.pos, &self.alleles())?;
+        self.set_alleles(pos, alleles);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        file_reader::vcf_reader::{
+            VcfFieldNumber, VcfFieldType, VcfGenotype, VcfHeader, VcfReader, VcfRecord, VcfSample,
+            VcfValue,
+        },
+        gsbam::bam_header_ext::HeaderSQ,
+    };
+
+    use super::{normalize_alleles, trim_alleles, VcfWriter};
+
+    fn to_strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_normalize_alleles() {
+        let ref_seq = b"GGCACACATT";
+        // delete one CA of the repeat, given right shifted
+        assert_eq!(
+            normalize_alleles(ref_seq, 5, &to_strings(&["ACA", "A"])).unwrap(),
+            (1, to_strings(&["GCA", "G"]))
+        );
+        // insert one CA, given right shifted
+        assert_eq!(
+            normalize_alleles(ref_seq, 7, &to_strings(&["A", "ACA"])).unwrap(),
+            (1, to_strings(&["G", "GCA"]))
+        );
+        // snv padded on both sides
+        assert_eq!(
+            normalize_alleles(ref_seq, 2, &to_strings(&["CAC", "CTC"])).unwrap(),
+            (3, to_strings(&["A", "T"]))
+        );
+        // multi-allelic keeps a shared anchor
+        assert_eq!(
+            normalize_alleles(ref_seq, 5, &to_strings(&["ACA", "A", "ACACA"])).unwrap(),
+            (1, to_strings(&["GCA", "G", "GCACA"]))
+        );
+        assert_eq!(
+            normalize_alleles(ref_seq, 0, &to_strings(&["G", "<DEL>"])).unwrap(),
+            (0, to_strings(&["G", "<DEL>"]))
+        );
+        assert!(normalize_alleles(ref_seq, 5, &to_strings(&["TT", "T"])).is_err());
+        // left shifted to the contig start, padded on the right
+        assert_eq!(
+            normalize_alleles(ref_seq, 1, &to_strings(&["GC", "C"])).unwrap(),
+            (0, to_strings(&["GG", "G"]))
+        );
+        assert_eq!(
+            normalize_alleles(ref_seq, 0, &to_strings(&["GGC", "C"])).unwrap(),
+            (0, to_strings(&["GGC", "C"]))
+        );
+        // soft-masked reference, the anchors are uppercase
+        assert_eq!(
+            normalize_alleles(b"ggcacacatt", 5, &to_strings(&["ACA", "A"])).unwrap(),
+            (1, to_strings(&["GCA", "G"]))
+        );
+
+        assert_eq!(
+            trim_alleles(10, &to_strings(&["ACGT", "AGGT"])),
+            (11, to_strings(&["C", "G"]))
+        );
+        assert_eq!(
+            trim_alleles(10, &to_strings(&["ACAC", "AC"])),
+            (10, to_strings(&["ACA", "A"]))
+        );
+    }
+
+    #[test]
+    fn test_vcf_writer_round_trip() {
+        let seqs = vec![
+            HeaderSQ::new(0, "chr1".to_string(), 1000),
+            HeaderSQ::new(1, "chr2".to_string(), 500),
+        ];
+        let mut header = VcfHeader::new(&seqs, vec!["s1".to_string(), "s2".to_string()]);
+        header
+            .add_filter("lowq")
+            .add_info("DP", VcfFieldNumber::Fixed(1), VcfFieldType::Integer, "depth")
+            .add_info("AF", VcfFieldNumber::AltAlleles, VcfFieldType::Float, "allele freq")
+            .add_info("SOMATIC", VcfFieldNumber::Fixed(0), VcfFieldType::Flag, "somatic")
+            .add_format("AD", VcfFieldNumber::Alleles, VcfFieldType::Integer, "allele depth")
+            .add_format("FT", VcfFieldNumber::Fixed(1), VcfFieldType::String, "sample filter");
+
+        let mut record = VcfRecord::new("chr1", 99, "A", to_strings(&["G", "AT"]));
+        record.id = Some("v1".to_string());
+        record.qual = Some(20.5);
+        record.filters = vec!["PASS".to_string()];
+        record.info = BTreeMap::from([
+            ("DP".to_string(), VcfValue::Integer(vec![Some(30)])),
+            ("AF".to_string(), VcfValue::Float(vec![Some(0.25), None])),
+            ("SOMATIC".to_string(), VcfValue::Flag),
+        ]);
+        record.samples = vec![
+            VcfSample {
+                genotype: Some(VcfGenotype {
+                    alleles: vec![Some(0), Some(2)],
+                    phased: true,
+                }),
+                fields: BTreeMap::from([
+                    ("AD".to_string(), VcfValue::Integer(vec![Some(10), Some(0), Some(20)])),
+                    ("FT".to_string(), VcfValue::String(vec!["PASS".to_string()])),
+                ]),
+            },
+            VcfSample {
+                genotype: Some(VcfGenotype {
+                    alleles: vec![Some(1)],
+                    phased: false,
+                }),
+                fields: BTreeMap::from([("AD".to_string(), VcfValue::Integer(vec![None]))]),
+            },
+        ];
+
+        let mut site = VcfRecord::new("chr2", 9, "C", to_strings(&["T"]));
+        site.filters = vec!["lowq".to_string()];
+
+        for suffix in [".vcf", ".vcf.gz", ".bcf"] {
+            let path = std::env::temp_dir()
+                .join(format!("gskits_writer_test_{}{}", std::process::id(), suffix));
+            {
+                let mut writer = VcfWriter::from_path(&path, &header).unwrap();
+                writer.write(&record).unwrap();
+                writer.write(&site).unwrap();
+                assert!(writer.write(&VcfRecord::new("chrX", 0, "A", vec![])).is_err());
+            }
+
+            let reader = VcfReader::from_path(&path).unwrap();
+            let read_header = reader.header().clone();
+            let records = reader.collect::<anyhow::Result<Vec<_>>>().unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(read_header.samples, header.samples);
+            assert_eq!(read_header.contigs, header.contigs);
+            assert_eq!(records.len(), 2);
+            let rec = &records[0];
+            assert_eq!(
+                (rec.pos, rec.id.as_deref(), rec.qual),
+                (99, Some("v1"), Some(20.5))
+            );
+            assert_eq!(rec.alt_alleles, record.alt_alleles);
+            assert_eq!(rec.info, record.info);
+            assert_eq!(rec.samples[0].genotype.as_ref().unwrap().to_string(), "0|2");
+            assert_eq!(rec.samples[1].genotype.as_ref().unwrap().to_string(), "1");
+            assert_eq!(rec.samples[0].fields["AD"], record.samples[0].fields["AD"]);
+            assert_eq!(rec.samples[1].fields["AD"], VcfValue::Integer(vec![None]));
+            assert_eq!(rec.samples[1].fields["FT"], VcfValue::String(vec![".".to_string()]));
+
+            assert_eq!(records[1].filters, vec!["lowq".to_string()]);
+            assert!(records[1].samples[0].genotype.as_ref().unwrap().is_missing());
+            assert_eq!(records[1].qual, None);
+        }
+    }
+
+    #[test]
+    fn test_record_normalize() {
+        let mut record = VcfRecord::new("chr1", 5, "ACA", to_strings(&["A"]));
+        record.normalize(b"GGCACACATT").unwrap();
+        assert_eq!((record.pos, record.ref_allele.as_str()), (1, "GCA"));
+        assert_eq!(record.alt_alleles, to_strings(&["G"]));
+
+        let mut record = VcfRecord::new("chr1", 10, "ACGT", to_strings(&["AGGT"]));
+        record.trim_alleles();
+        assert_eq!((record.pos, record.ref_allele.as_str()), (11, "C"));
+    }
+}
@@ -33,9 +33,14 @@
 //     }
 // }
 
+use std::collections::HashMap;
+
+use anyhow::anyhow;
 use rust_htslib::bam::Read;
 
-use super::bam_record_ext::BamRecord;
+use crate::fastx_reader::fasta_reader::FastaFileReader;
+
+use super::bam_record_ext::{BamReader, BamRecord};
 
 pub fn read_records(bam_h: &mut rust_htslib::bam::IndexedReader) -> Vec<BamRecord> {
     let mut records = vec![];
@@ -52,3 +57,55 @@ pub fn read_records(bam_h: &mut rust_htslib::bam::IndexedReader) -> Vec<BamRecor
 
     records
 }
+
+/// threads: bam decompression threads. default num_cpus::get_physical() / 2
+pub fn open_bam_reader(bam_file: &str, threads: Option<usize>) -> anyhow::Result<BamReader> {
+    let threads = threads.unwrap_or(num_cpus::get_physical() / 2);
+    let threads = if threads > 1 { threads } else { 1 };
+
+    let mut reader = BamReader::from_path(bam_file)?;
+    reader.set_threads(threads)?;
+    Ok(reader)
+}
+
+/// calls f with every primary alignment in the bam and the sequence of its reference.
+/// it is an error if the reference of a record is not in ref_fasta.
+/// threads: see [`open_bam_reader`]
+pub fn for_each_primary_record_with_ref<F>(
+    bam_file: &str,
+    ref_fasta: &str,
+    threads: Option<usize>,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(&BamRecord, &[u8]) -> anyhow::Result<()>,
+{
+    let ref_seqs = FastaFileReader::new(ref_fasta.to_string())
+        .map(|read_info| (read_info.name, read_info.seq))
+        .collect::<HashMap<_, _>>();
+
+    let mut reader = open_bam_reader(bam_file, threads)?;
+    let tid2ref_seq = reader
+        .header()
+        .target_names()
+        .into_iter()
+        .map(|name| ref_seqs.get(String::from_utf8_lossy(name).as_ref()))
+        .collect::<Vec<_>>();
+
+    let mut record = BamRecord::new();
+    while let Some(res) = reader.read(&mut record) {
+        res?;
+        if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        let ref_seq = tid2ref_seq[record.tid() as usize].ok_or_else(|| {
+            anyhow!(
+                "ref seq of tid {} not found in {}",
+                record.tid(),
+                ref_fasta
+            )
+        })?;
+        f(&record, ref_seq.as_bytes())?;
+    }
+    Ok(())
+}
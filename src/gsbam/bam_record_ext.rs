@@ -2,6 +2,11 @@ use std::fmt::Display;
 
 use rust_htslib::bam::{ext::BamRecordExtensions, record::Aux, record::Cigar, Record};
 
+use super::cigar_ext::{
+    hard_clips, homopolymer_indel_flags, leading_soft_clip, CigarIndex, IdentityCounts,
+    IdentityMetric, RefPosMapping,
+};
+
 pub type BamRecord = rust_htslib::bam::Record;
 pub type BamWriter = rust_htslib::bam::Writer;
 pub type BamReader = rust_htslib::bam::Reader;
@@ -53,6 +58,23 @@ impl<'a> BamRecordExt<'a> {
         matched as f32 / aligned_span as f32
     }
 
+    /// ref_seq: the whole reference, only used to find the homopolymer deletions for IdentityMetric::ConcordanceNoHpIndel
+    pub fn identity_counts(&self, ref_seq: Option<&[u8]>) -> IdentityCounts {
+        let cigar = self.bam_record.cigar();
+        let hp_indel_flags = homopolymer_indel_flags(
+            &cigar,
+            &self.bam_record.seq().as_bytes(),
+            ref_seq,
+            self.bam_record.pos().max(0) as usize,
+        );
+        IdentityCounts::from_cigars(cigar.iter(), Some(&hp_indel_flags))
+    }
+
+    /// eqx cigar is required, M ops are not counted. ref_seq: see identity_counts
+    pub fn compute_identity_with_metric(&self, metric: IdentityMetric, ref_seq: Option<&[u8]>) -> f32 {
+        self.identity_counts(ref_seq).identity(metric)
+    }
+
     /// (eq + diff + ins) / query_len
     pub fn compute_query_coverage(&self) -> f32 {
         let mut seq_len = self.bam_record.seq_len_from_cigar(true);
@@ -72,15 +94,9 @@ impl<'a> BamRecordExt<'a> {
         aligned_span as f32 / seq_len as f32
     }
 
+    /// alignment start in SEQ. leading hard clip is not included, leading soft clip is included.
     pub fn query_alignment_start(&self) -> usize {
-        match self.bam_record.cigar().first() {
-            Some(cigar) => match *cigar {
-                Cigar::SoftClip(n) => n as usize,
-                _ => 0,
-            },
-
-            _ => 0,
-        }
+        leading_soft_clip(&self.bam_record.cigar())
     }
 
     pub fn query_alignment_end(&self) -> usize {
@@ -97,6 +113,75 @@ impl<'a> BamRecordExt<'a> {
         self.query_alignment_start() + aligned_qlen as usize
     }
 
+    /// (leading hard clip, trailing hard clip) in BAM orientation
+    pub fn hard_clips(&self) -> (usize, usize) {
+        hard_clips(&self.bam_record.cigar())
+    }
+
+    /// length of the original read, hard clipped bases are included
+    pub fn original_query_len(&self) -> usize {
+        self.bam_record.seq_len_from_cigar(true)
+    }
+
+    /// convert position in SEQ to position in the original read (the sequenced strand, hard clips are included)
+    pub fn seq_pos_to_read_pos(&self, qpos: usize) -> usize {
+        let bam_orientation_pos = qpos + self.hard_clips().0;
+        if self.bam_record.is_reverse() {
+            self.original_query_len() - 1 - bam_orientation_pos
+        } else {
+            bam_orientation_pos
+        }
+    }
+
+    /// convert position in the original read to position in SEQ. None if the position is hard clipped
+    pub fn read_pos_to_seq_pos(&self, read_pos: usize) -> Option<usize> {
+        let original_len = self.original_query_len();
+        if read_pos >= original_len {
+            return None;
+        }
+        let bam_orientation_pos = if self.bam_record.is_reverse() {
+            original_len - 1 - read_pos
+        } else {
+            read_pos
+        };
+        let (leading, trailing) = self.hard_clips();
+        if bam_orientation_pos < leading || bam_orientation_pos >= original_len - trailing {
+            None
+        } else {
+            Some(bam_orientation_pos - leading)
+        }
+    }
+
+    /// aligned query range [start, end) in the original read (the sequenced strand, hard clips are included)
+    pub fn query_alignment_range_in_read(&self) -> (usize, usize) {
+        let leading = self.hard_clips().0;
+        let start = self.query_alignment_start() + leading;
+        let end = self.query_alignment_end() + leading;
+        if self.bam_record.is_reverse() {
+            let original_len = self.original_query_len();
+            (original_len - end, original_len - start)
+        } else {
+            (start, end)
+        }
+    }
+
+    pub fn cigar_index(&self) -> CigarIndex {
+        CigarIndex::from_record(self.bam_record)
+    }
+
+    /// the query position (in SEQ) that aligned to the ref position. None if the ref position is deleted or not covered
+    pub fn ref_pos_to_query_pos(&self, rpos: usize) -> Option<usize> {
+        match self.cigar_index().ref_pos_to_query_pos(rpos) {
+            RefPosMapping::Aligned { qpos, .. } => Some(qpos),
+            _ => None,
+        }
+    }
+
+    /// the ref position that the query position (in SEQ) aligned to. None if the query position is inserted or clipped
+    pub fn query_pos_to_ref_pos(&self, qpos: usize) -> Option<usize> {
+        self.cigar_index().query_pos_to_ref_pos(qpos)
+    }
+
     pub fn reference_start(&self) -> usize {
         assert!(self.bam_record.pos() >= 0, "set_pos first");
         self.bam_record.pos() as usize
@@ -291,8 +376,13 @@ pub fn draw_aligned_seq(
     let mut query_aligned_seq = String::new();
 
     let query_seq = record.seq().as_bytes();
+    let cigar_index = CigarIndex::from_record(record);
+    let aligned_pairs = match r_start {
+        Some(r_start) => cigar_index.aligned_pairs_from_ref(r_start),
+        None => cigar_index.aligned_pairs_full(),
+    };
     let mut rpos_cursor = None;
-    for [qpos, rpos] in record.aligned_pairs_full() {
+    for [qpos, rpos] in aligned_pairs {
         if rpos.is_some() {
             rpos_cursor = rpos;
         }
@@ -333,3 +423,109 @@ pub fn draw_aligned_seq(
 
     (ref_aligned_seq, query_aligned_seq)
 }
+
+#[cfg(test)]
+mod test {
+    use crate::gsbam::{
+        cigar_ext::IdentityMetric,
+        test_utils::{build_record, RecordBuilder},
+    };
+
+    use super::{draw_aligned_seq, BamRecordExt};
+
+    #[test]
+    fn test_query_coordinates_with_hard_clip() {
+        // HHHHHSSS====I==SS
+        let record = build_record("5H3S4=1I2=2S", "AAACCCCGTTGG", 100);
+        let record_ext = BamRecordExt::new(&record);
+        assert_eq!(record_ext.query_alignment_start(), 3);
+        assert_eq!(record_ext.query_alignment_end(), 10);
+        assert_eq!(record_ext.hard_clips(), (5, 0));
+        assert_eq!(record_ext.original_query_len(), 17);
+        assert_eq!(record_ext.query_alignment_range_in_read(), (8, 15));
+        assert_eq!(record_ext.seq_pos_to_read_pos(0), 5);
+        assert_eq!(record_ext.read_pos_to_seq_pos(5), Some(0));
+        assert_eq!(record_ext.read_pos_to_seq_pos(4), None);
+        assert_eq!(record_ext.read_pos_to_seq_pos(17), None);
+
+        let record = RecordBuilder::new("5H3S4=1I2=2S", "AAACCCCGTTGG")
+            .pos(100)
+            .reverse(true)
+            .build();
+        let record_ext = BamRecordExt::new(&record);
+        assert_eq!(record_ext.query_alignment_range_in_read(), (2, 9));
+        assert_eq!(record_ext.seq_pos_to_read_pos(0), 11);
+        assert_eq!(record_ext.seq_pos_to_read_pos(11), 0);
+        assert_eq!(record_ext.read_pos_to_seq_pos(11), Some(0));
+        assert_eq!(record_ext.read_pos_to_seq_pos(12), None);
+    }
+
+    #[test]
+    fn test_ref_query_pos_conversion() {
+        // ref:   ---ACGT-AC-GTA--
+        // query: SSSACGTAAC--TAGG
+        let record = build_record("3S4=1I2=2D1X1=2S", "TTTACGTAACTAGG", 100);
+        let record_ext = BamRecordExt::new(&record);
+        assert_eq!(record_ext.reference_end(), 110);
+        assert_eq!(record_ext.ref_pos_to_query_pos(99), None);
+        assert_eq!(record_ext.ref_pos_to_query_pos(100), Some(3));
+        assert_eq!(record_ext.ref_pos_to_query_pos(104), Some(8));
+        assert_eq!(record_ext.ref_pos_to_query_pos(106), None);
+        assert_eq!(record_ext.ref_pos_to_query_pos(108), Some(10));
+        assert_eq!(record_ext.ref_pos_to_query_pos(110), None);
+
+        assert_eq!(record_ext.query_pos_to_ref_pos(2), None);
+        assert_eq!(record_ext.query_pos_to_ref_pos(3), Some(100));
+        assert_eq!(record_ext.query_pos_to_ref_pos(7), None);
+        assert_eq!(record_ext.query_pos_to_ref_pos(11), Some(109));
+        assert_eq!(record_ext.query_pos_to_ref_pos(12), None);
+    }
+
+    #[test]
+    fn test_draw_aligned_seq() {
+        // ref:   ---ACGT-ACGTAC--
+        // query: TTTACGTAAC--TCGG
+        let ref_seq = b"ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT";
+        let record = build_record("3S4=1I2=2D1X1=2S", "TTTACGTAACTCGG", 100);
+        let (r, q) = draw_aligned_seq(&record, ref_seq, None, None);
+        assert_eq!(r, "---ACGT-ACGTAC--");
+        assert_eq!(q, "TTTACGTAAC--TCGG");
+
+        let (r, q) = draw_aligned_seq(&record, ref_seq, Some(103), Some(106));
+        assert_eq!(r, "T-AC");
+        assert_eq!(q, "TAAC");
+
+        let (r, q) = draw_aligned_seq(&record, ref_seq, Some(50), Some(101));
+        assert_eq!(r, "A");
+        assert_eq!(q, "A");
+    }
+
+    #[test]
+    fn test_identity_metrics() {
+        // ref:   CGTTTA--CGGA
+        // query: CGTT-AAACCGA
+        let ref_seq = format!("{}CGTTTACGGA", "N".repeat(100));
+        let record = build_record("4=1D1=2I1=1X2=", "CGTTAAACCGA", 100);
+        let record_ext = BamRecordExt::new(&record);
+
+        let counts = record_ext.identity_counts(Some(ref_seq.as_bytes()));
+        assert_eq!((counts.eq, counts.diff, counts.ins, counts.del), (8, 1, 2, 1));
+        assert_eq!((counts.hp_ins, counts.hp_del), (2, 1));
+
+        let identity = |metric, ref_seq: Option<&[u8]>| {
+            record_ext.compute_identity_with_metric(metric, ref_seq)
+        };
+        assert_eq!(
+            identity(IdentityMetric::Blast, None),
+            record_ext.compute_identity()
+        );
+        assert!((identity(IdentityMetric::Blast, None) - 8.0 / 12.0).abs() < 1e-6);
+        assert!((identity(IdentityMetric::GapCompressed, None) - 8.0 / 11.0).abs() < 1e-6);
+        let concordance =
+            identity(IdentityMetric::ConcordanceNoHpIndel, Some(ref_seq.as_bytes()));
+        assert!((concordance - 8.0 / 9.0).abs() < 1e-6);
+        // the deletion can't be classified without ref
+        assert!((identity(IdentityMetric::ConcordanceNoHpIndel, None) - 8.0 / 10.0).abs() < 1e-6);
+        assert_eq!(record_ext.identity_counts(None).phreq(IdentityMetric::Blast), 5.0);
+    }
+}
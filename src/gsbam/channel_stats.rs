@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rust_htslib::bam::Read;
+
+use crate::phreq::quality_2_phreq;
+
+use super::{
+    bam_reader::open_bam_reader,
+    bam_record_ext::{BamRecord, BamRecordExt},
+    record_group::GroupKeySource,
+};
+
+/// max phreq of the rq histogram, rq with larger phreq is put into the last bin
+pub const MAX_RQ_PHREQ: usize = 60;
+
+/// statistics of the records that belong to the same channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelStat {
+    pub n_records: usize,
+    pub tot_len: usize,
+    pub min_len: usize,
+    pub max_len: usize,
+    /// number of records that have the rq tag
+    pub n_rq: usize,
+    pub rq_sum: f64,
+    pub min_rq: Option<f32>,
+    pub max_rq: Option<f32>,
+    /// max np of the records
+    pub np: Option<u32>,
+}
+
+impl Default for ChannelStat {
+    fn default() -> Self {
+        Self {
+            n_records: 0,
+            tot_len: 0,
+            min_len: usize::MAX,
+            max_len: 0,
+            n_rq: 0,
+            rq_sum: 0.0,
+            min_rq: None,
+            max_rq: None,
+            np: None,
+        }
+    }
+}
+
+impl ChannelStat {
+    pub fn update(&mut self, read_len: usize, rq: Option<f32>, np: Option<u32>) {
+        self.n_records += 1;
+        self.tot_len += read_len;
+        self.min_len = self.min_len.min(read_len);
+        self.max_len = self.max_len.max(read_len);
+
+        if let Some(rq) = rq {
+            self.n_rq += 1;
+            self.rq_sum += rq as f64;
+            self.min_rq = Some(self.min_rq.map_or(rq, |v| v.min(rq)));
+            self.max_rq = Some(self.max_rq.map_or(rq, |v| v.max(rq)));
+        }
+
+        if let Some(np) = np {
+            self.np = Some(self.np.map_or(np, |v| v.max(np)));
+        }
+    }
+
+    pub fn merge(&mut self, other: &ChannelStat) {
+        self.n_records += other.n_records;
+        self.tot_len += other.tot_len;
+        self.min_len = self.min_len.min(other.min_len);
+        self.max_len = self.max_len.max(other.max_len);
+        self.n_rq += other.n_rq;
+        self.rq_sum += other.rq_sum;
+        self.min_rq = match (self.min_rq, other.min_rq) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max_rq = match (self.max_rq, other.max_rq) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.np = match (self.np, other.np) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    pub fn mean_len(&self) -> f64 {
+        if self.n_records == 0 {
+            0.0
+        } else {
+            self.tot_len as f64 / self.n_records as f64
+        }
+    }
+
+    pub fn mean_rq(&self) -> Option<f64> {
+        if self.n_rq == 0 {
+            None
+        } else {
+            Some(self.rq_sum / self.n_rq as f64)
+        }
+    }
+}
+
+/// per channel statistics of a bam. the records are consumed one by one,
+/// only the aggregated values are kept, so it's fine for very large bam files
+#[derive(Debug, Clone)]
+pub struct ChannelStats {
+    channels: HashMap<usize, ChannelStat>,
+    /// records whose channel can't be extracted
+    n_no_channel: usize,
+    /// rq_phreq_hist[phreq] = number of records. phreq is capped at MAX_RQ_PHREQ
+    rq_phreq_hist: Vec<usize>,
+}
+
+impl Default for ChannelStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelStats {
+    pub fn new() -> Self {
+        Self {
+            channels: HashMap::new(),
+            n_no_channel: 0,
+            rq_phreq_hist: vec![0; MAX_RQ_PHREQ + 1],
+        }
+    }
+
+    /// the channel is the key of channel_source, see GroupKey::channel
+    pub fn update(&mut self, record: &BamRecord, channel_source: &GroupKeySource) {
+        let ch = match channel_source.extract(record).and_then(|key| key.channel()) {
+            Some(ch) => ch,
+            None => {
+                self.n_no_channel += 1;
+                return;
+            }
+        };
+
+        let record_ext = BamRecordExt::new(record);
+        let rq = record_ext.get_rq();
+        if let Some(rq) = rq {
+            let phreq = (quality_2_phreq(rq, None) as usize).min(MAX_RQ_PHREQ);
+            self.rq_phreq_hist[phreq] += 1;
+        }
+
+        self.channels
+            .entry(ch)
+            .or_default()
+            .update(record.seq_len(), rq, record_ext.get_np());
+    }
+
+    pub fn merge(&mut self, other: &ChannelStats) {
+        other.channels.iter().for_each(|(ch, stat)| {
+            self.channels.entry(*ch).or_default().merge(stat);
+        });
+        self.n_no_channel += other.n_no_channel;
+        self.rq_phreq_hist
+            .iter_mut()
+            .zip(other.rq_phreq_hist.iter())
+            .for_each(|(a, b)| *a += *b);
+    }
+
+    pub fn get_channels(&self) -> &HashMap<usize, ChannelStat> {
+        &self.channels
+    }
+
+    pub fn get_channel(&self, ch: usize) -> Option<&ChannelStat> {
+        self.channels.get(&ch)
+    }
+
+    pub fn get_n_no_channel(&self) -> usize {
+        self.n_no_channel
+    }
+
+    pub fn get_rq_phreq_hist(&self) -> &Vec<usize> {
+        &self.rq_phreq_hist
+    }
+
+    pub fn n_records(&self) -> usize {
+        self.channels.values().map(|stat| stat.n_records).sum()
+    }
+
+    /// channels that have more than 1 record
+    pub fn dup_channels(&self) -> HashSet<usize> {
+        self.channels
+            .iter()
+            .filter(|(_, stat)| stat.n_records > 1)
+            .map(|(ch, _)| *ch)
+            .collect()
+    }
+
+    /// channels that have exactly 1 record
+    pub fn nondup_channels(&self) -> HashSet<usize> {
+        self.channels
+            .iter()
+            .filter(|(_, stat)| stat.n_records == 1)
+            .map(|(ch, _)| *ch)
+            .collect()
+    }
+
+    /// records per channel -> number of channels
+    pub fn multiplicity_hist(&self) -> BTreeMap<usize, usize> {
+        let mut hist = BTreeMap::new();
+        self.channels.values().for_each(|stat| {
+            *hist.entry(stat.n_records).or_insert(0) += 1;
+        });
+        hist
+    }
+}
+
+/// threads: see [`open_bam_reader`]
+pub fn channel_stats_from_bam(
+    bam_file: &str,
+    channel_source: &GroupKeySource,
+    threads: Option<usize>,
+) -> anyhow::Result<ChannelStats> {
+    let mut reader = open_bam_reader(bam_file, threads)?;
+
+    let mut stats = ChannelStats::new();
+    let mut record = BamRecord::new();
+    while let Some(res) = reader.read(&mut record) {
+        res?;
+        stats.update(&record, channel_source);
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashSet},
+        sync::Arc,
+    };
+
+    use crate::gsbam::{
+        bam_record_ext::BamRecord,
+        record_group::{GroupKey, GroupKeySource},
+        test_utils::RecordBuilder,
+    };
+
+    use super::ChannelStats;
+
+    #[test]
+    fn test_channel_stats() {
+        let records = [
+            RecordBuilder::eqx("ACGT").qname("m/1/ccs").ch(Some(1)).rq(Some(0.99)).build(),
+            RecordBuilder::eqx("ACGTAC").qname("m/1/ccs/rev").rq(Some(0.999)).build(),
+            RecordBuilder::eqx("AC").qname("m/2/ccs").ch(Some(2)).build(),
+            RecordBuilder::eqx("AC").qname("unknown").build(),
+        ];
+
+        let mut stats = ChannelStats::new();
+        records
+            .iter()
+            .for_each(|record| stats.update(record, &GroupKeySource::default()));
+
+        assert_eq!(stats.n_records(), 3);
+        assert_eq!(stats.get_n_no_channel(), 1);
+        assert_eq!(stats.dup_channels(), HashSet::from([1]));
+        assert_eq!(stats.nondup_channels(), HashSet::from([2]));
+        assert_eq!(stats.multiplicity_hist(), BTreeMap::from([(1, 1), (2, 1)]));
+
+        let ch1 = stats.get_channel(1).unwrap();
+        assert_eq!(ch1.min_len, 4);
+        assert_eq!(ch1.max_len, 6);
+        assert!((ch1.mean_len() - 5.0).abs() < 1e-6);
+        assert!((ch1.mean_rq().unwrap() - 0.9945).abs() < 1e-6);
+        assert_eq!(stats.get_channel(2).unwrap().mean_rq(), None);
+
+        assert_eq!(stats.get_rq_phreq_hist()[20], 1);
+        assert_eq!(stats.get_rq_phreq_hist()[30], 1);
+
+        let mut merged = ChannelStats::new();
+        merged.merge(&stats);
+        merged.merge(&stats);
+        assert_eq!(merged.n_records(), 6);
+        assert_eq!(merged.get_channel(1).unwrap().min_rq, Some(0.99));
+        assert_eq!(merged.dup_channels(), HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_channel_source() {
+        let record = RecordBuilder::eqx("ACGT").qname("movie/12/ccs").ch(Some(3)).build();
+        let channel = |source: GroupKeySource| source.extract(&record).and_then(|k| k.channel());
+        assert_eq!(channel(GroupKeySource::Tag(b"ch".to_vec())), Some(3));
+        assert_eq!(channel(GroupKeySource::QnameField(1)), Some(12));
+        assert_eq!(channel(GroupKeySource::QnameField(2)), None);
+        assert_eq!(channel(GroupKeySource::default()), Some(3));
+
+        let custom = GroupKeySource::Custom(Arc::new(|record: &BamRecord| {
+            Some(GroupKey::Int(record.seq_len() as i64))
+        }));
+        assert_eq!(channel(custom), Some(4));
+    }
+}
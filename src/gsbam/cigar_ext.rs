@@ -2,10 +2,161 @@ use std::cmp;
 
 use rust_htslib::bam::record::{Cigar, CigarString};
 
+use crate::phreq::quality_2_phreq;
+
+use super::bam_record_ext::BamRecord;
+
+/// identity definitions. eqx cigar is required, M ops are not counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdentityMetric {
+    /// eq / (eq + diff + ins + del), matches over alignment columns. the same as BamRecordExt::compute_identity
+    #[default]
+    Blast,
+    /// eq / (eq + diff + ins_events + del_events), every indel is counted once no matter how long it is
+    GapCompressed,
+    /// eq / (eq + diff + ins + del), the homopolymer indels are not counted
+    ConcordanceNoHpIndel,
+}
+
+/// cigar counts that the identities are computed from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdentityCounts {
+    pub eq: usize,
+    pub diff: usize,
+    pub ins: usize,
+    pub del: usize,
+    pub ins_events: usize,
+    pub del_events: usize,
+    /// inserted / deleted bases of the homopolymer indels
+    pub hp_ins: usize,
+    pub hp_del: usize,
+    /// bases of the M ops. eq and diff can't be told apart, so they are not in the identities
+    pub mat: usize,
+}
+
+impl IdentityCounts {
+    /// hp_indel_flags: result of homopolymer_indel_flags. if None, no indel is treated as homopolymer indel
+    pub fn from_cigars<'a>(
+        cigars: impl IntoIterator<Item = &'a Cigar>,
+        hp_indel_flags: Option<&[bool]>,
+    ) -> Self {
+        let mut counts = Self::default();
+        for (idx, cigar) in cigars.into_iter().enumerate() {
+            counts.add(
+                cigar,
+                hp_indel_flags.map(|flags| flags[idx]).unwrap_or(false),
+            );
+        }
+        counts
+    }
+
+    fn add(&mut self, cigar: &Cigar, is_hp_indel: bool) {
+        match *cigar {
+            Cigar::Equal(n) => self.eq += n as usize,
+            Cigar::Diff(n) => self.diff += n as usize,
+            Cigar::Ins(n) if n > 0 => {
+                self.ins += n as usize;
+                self.ins_events += 1;
+                if is_hp_indel {
+                    self.hp_ins += n as usize;
+                }
+            }
+            Cigar::Del(n) if n > 0 => {
+                self.del += n as usize;
+                self.del_events += 1;
+                if is_hp_indel {
+                    self.hp_del += n as usize;
+                }
+            }
+            Cigar::Match(n) => self.mat += n as usize,
+            _ => {}
+        }
+    }
+
+    pub fn identity(&self, metric: IdentityMetric) -> f32 {
+        let span = match metric {
+            IdentityMetric::Blast => self.eq + self.diff + self.ins + self.del,
+            IdentityMetric::GapCompressed => {
+                self.eq + self.diff + self.ins_events + self.del_events
+            }
+            IdentityMetric::ConcordanceNoHpIndel => {
+                self.eq + self.diff + (self.ins - self.hp_ins) + (self.del - self.hp_del)
+            }
+        };
+        self.eq as f32 / span.max(1) as f32
+    }
+
+    /// empirical phreq of the identity, quality_2_phreq(identity)
+    pub fn phreq(&self, metric: IdentityMetric) -> f32 {
+        quality_2_phreq(self.identity(metric), None) as f32
+    }
+}
+
+/// for each cigar op, whether it is a homopolymer indel.
+/// an insertion is a homopolymer insertion if the inserted bases are the same as the query base right before/after it.
+/// a deletion is a homopolymer deletion if the deleted bases are the same as the ref base right before/after it,
+/// ref_seq (the whole reference) is required to classify the deletions, without it no deletion is treated as homopolymer deletion.
+///
+/// query_seq: SEQ of the record
+pub fn homopolymer_indel_flags(
+    cigar_str: &CigarString,
+    query_seq: &[u8],
+    ref_seq: Option<&[u8]>,
+    ref_start: usize,
+) -> Vec<bool> {
+    let same_as_flank = |seq: &[u8], start: usize, end: usize| {
+        let indel = &seq[start..end];
+        [start.checked_sub(1), Some(end).filter(|&end| end < seq.len())]
+            .into_iter()
+            .flatten()
+            .any(|flank| indel.iter().all(|base| base.eq_ignore_ascii_case(&seq[flank])))
+    };
+
+    let mut qpos = 0;
+    let mut rpos = ref_start;
+    cigar_str
+        .iter()
+        .map(|cigar| {
+            let n = cigar.len() as usize;
+            match *cigar {
+                Cigar::Ins(_) => {
+                    let is_hp = n > 0 && same_as_flank(query_seq, qpos, qpos + n);
+                    qpos += n;
+                    is_hp
+                }
+                Cigar::Del(_) => {
+                    let is_hp = n > 0
+                        && ref_seq
+                            .map(|ref_seq| same_as_flank(ref_seq, rpos, rpos + n))
+                            .unwrap_or(false);
+                    rpos += n;
+                    is_hp
+                }
+                Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) => {
+                    qpos += n;
+                    rpos += n;
+                    false
+                }
+                Cigar::SoftClip(_) => {
+                    qpos += n;
+                    false
+                }
+                Cigar::RefSkip(_) => {
+                    rpos += n;
+                    false
+                }
+                Cigar::HardClip(_) | Cigar::Pad(_) => false,
+            }
+        })
+        .collect()
+}
+
 /// indentity of query range !!
 #[derive(Debug, Clone)]
 pub struct RangeIdentityCalculator {
     query_start_pos_and_op: Vec<(u32, Cigar)>,
+    /// homopolymer_indel_flags, only for IdentityMetric::ConcordanceNoHpIndel
+    hp_indel_flags: Option<Vec<bool>>,
     qstart: u32,
     qend: u32,
 }
@@ -34,12 +185,37 @@ impl RangeIdentityCalculator {
         let (qstart, qend) = compute_qstart_qend_with_cigar(cigar_str);
         Self {
             query_start_pos_and_op,
+            hp_indel_flags: None,
             qstart: qstart as u32,
             qend: qend as u32,
         }
     }
 
+    /// the homopolymer indels are identified, so IdentityMetric::ConcordanceNoHpIndel can be used. see homopolymer_indel_flags
+    pub fn new_with_seqs(
+        cigar_str: &CigarString,
+        query_seq: &[u8],
+        ref_seq: Option<&[u8]>,
+        ref_start: usize,
+    ) -> Self {
+        let mut calculator = Self::new(cigar_str);
+        calculator.hp_indel_flags = Some(homopolymer_indel_flags(
+            cigar_str, query_seq, ref_seq, ref_start,
+        ));
+        calculator
+    }
+
+    /// blast identity of the query range
     pub fn compute_range_identity(&self, start: u32, end: u32) -> (u32, u32, f32) {
+        self.compute_range_identity_with_metric(start, end, IdentityMetric::Blast)
+    }
+
+    pub fn compute_range_identity_with_metric(
+        &self,
+        start: u32,
+        end: u32,
+        metric: IdentityMetric,
+    ) -> (u32, u32, f32) {
         let start = cmp::min(cmp::max(self.qstart, start), self.qend);
         let end = cmp::max(cmp::min(self.qend, end), self.qstart);
         if start == end {
@@ -122,27 +298,284 @@ impl RangeIdentityCalculator {
             res_cigars
         };
 
-        let span_len = res_cigars
-            .iter()
-            .map(|&cigar| match cigar {
-                Cigar::Diff(n) | Cigar::Equal(n) | Cigar::Ins(n) | Cigar::Del(n) => n,
-                _ => 0,
-            })
-            .sum::<u32>();
-        let eq_len = res_cigars
-            .iter()
-            .map(|&cigar| match cigar {
-                Cigar::Equal(n) => n,
-                _ => 0,
-            })
-            .sum::<u32>();
+        let hp_indel_flags = self
+            .hp_indel_flags
+            .as_ref()
+            .map(|flags| &flags[start_idx..=end_idx]);
+        let counts = IdentityCounts::from_cigars(res_cigars.iter(), hp_indel_flags);
+        (start, end, counts.identity(metric))
+    }
+}
+
+/// a cigar op with its start positions. qstart is the position in SEQ (hard clip excluded)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CigarBlock {
+    pub op: Cigar,
+    pub qstart: usize,
+    pub rstart: usize,
+}
+
+impl CigarBlock {
+    pub fn qlen(&self) -> usize {
+        match self.op {
+            Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) | Cigar::Ins(n) | Cigar::SoftClip(n) => {
+                n as usize
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn rlen(&self) -> usize {
+        match self.op {
+            Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) | Cigar::Del(n) | Cigar::RefSkip(n) => {
+                n as usize
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn qend(&self) -> usize {
+        self.qstart + self.qlen()
+    }
+
+    pub fn rend(&self) -> usize {
+        self.rstart + self.rlen()
+    }
+
+    /// M/=/X
+    pub fn is_aligned(&self) -> bool {
+        matches!(self.op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_))
+    }
+}
 
-        let span_len = if span_len == 0 {
-            1.0_f32
+/// what the ref position is in the query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefPosMapping {
+    /// the ref position is aligned to qpos.
+    /// ins_len: length of the insertion between this ref position and the next ref position
+    Aligned { qpos: usize, ins_len: usize },
+    /// the ref position is deleted (D/N) in the query. qpos: the query position right after the deletion
+    Deleted { qpos: usize },
+    /// the ref position is not covered by the alignment
+    Outside,
+}
+
+/// precomputed cigar block boundaries, answer the position queries in O(log n)
+/// instead of scanning aligned_pairs_full()
+#[derive(Debug, Clone)]
+pub struct CigarIndex {
+    ref_start: usize,
+    ref_end: usize,
+    /// all cigar ops except H and P, sorted by both qstart and rstart
+    blocks: Vec<CigarBlock>,
+}
+
+impl CigarIndex {
+    pub fn new(cigar_str: &CigarString, ref_start: usize) -> Self {
+        let mut qpos = 0;
+        let mut rpos = ref_start;
+        let mut blocks = Vec::with_capacity(cigar_str.len());
+        cigar_str.iter().for_each(|&op| {
+            if matches!(op, Cigar::HardClip(_) | Cigar::Pad(_)) {
+                return;
+            }
+            let block = CigarBlock {
+                op,
+                qstart: qpos,
+                rstart: rpos,
+            };
+            qpos = block.qend();
+            rpos = block.rend();
+            blocks.push(block);
+        });
+
+        Self {
+            ref_start,
+            ref_end: rpos,
+            blocks,
+        }
+    }
+
+    pub fn from_record(record: &BamRecord) -> Self {
+        assert!(record.pos() >= 0, "set_pos first");
+        Self::new(&record.cigar(), record.pos() as usize)
+    }
+
+    pub fn ref_start(&self) -> usize {
+        self.ref_start
+    }
+
+    pub fn ref_end(&self) -> usize {
+        self.ref_end
+    }
+
+    pub fn blocks(&self) -> &Vec<CigarBlock> {
+        &self.blocks
+    }
+
+    /// idx of the first block whose rend > rpos. the block contains rpos if rpos is in [ref_start, ref_end)
+    fn block_idx_of_ref(&self, rpos: usize) -> usize {
+        self.blocks.partition_point(|block| block.rend() <= rpos)
+    }
+
+    /// idx of the first block whose qend > qpos
+    fn block_idx_of_query(&self, qpos: usize) -> usize {
+        self.blocks.partition_point(|block| block.qend() <= qpos)
+    }
+
+    pub fn ref_pos_to_query_pos(&self, rpos: usize) -> RefPosMapping {
+        let idx = self.block_idx_of_ref(rpos);
+        if idx >= self.blocks.len() || self.blocks[idx].rstart > rpos {
+            return RefPosMapping::Outside;
+        }
+
+        let block = &self.blocks[idx];
+        if block.is_aligned() {
+            let ins_len = if rpos + 1 == block.rend() {
+                self.blocks[idx + 1..]
+                    .iter()
+                    .take_while(|block| matches!(block.op, Cigar::Ins(_)))
+                    .map(|block| block.qlen())
+                    .sum()
+            } else {
+                0
+            };
+            RefPosMapping::Aligned {
+                qpos: block.qstart + rpos - block.rstart,
+                ins_len,
+            }
+        } else {
+            RefPosMapping::Deleted { qpos: block.qstart }
+        }
+    }
+
+    /// the ref position that the query position aligned to. None if the query position is inserted or clipped
+    pub fn query_pos_to_ref_pos(&self, qpos: usize) -> Option<usize> {
+        let idx = self.block_idx_of_query(qpos);
+        self.blocks
+            .get(idx)
+            .filter(|block| block.is_aligned())
+            .map(|block| block.rstart + qpos - block.qstart)
+    }
+
+    /// ref range [rstart, rend) spanned by the query range [qstart, qend).
+    /// deletions at the edges of the query range are not included. None if no ref base is spanned
+    pub fn query_range_to_ref_range(&self, qstart: usize, qend: usize) -> Option<(usize, usize)> {
+        if qstart >= qend {
+            return None;
+        }
+
+        let rstart = match self.blocks.get(self.block_idx_of_query(qstart)) {
+            Some(block) if block.is_aligned() => block.rstart + qstart - block.qstart,
+            Some(block) => block.rstart,
+            None => self.ref_end,
+        };
+
+        let idx = self.blocks.partition_point(|block| block.qstart < qend);
+        let rend = if idx == 0 {
+            self.ref_start
+        } else {
+            let block = &self.blocks[idx - 1];
+            if block.is_aligned() {
+                block.rstart + qend - block.qstart
+            } else {
+                block.rend()
+            }
+        };
+
+        if rstart < rend {
+            Some((rstart, rend))
+        } else {
+            None
+        }
+    }
+
+    /// the last query position before the aligned pair of the ref position. soft clip is included
+    pub fn query_pos_before_ref(&self, rpos: usize) -> Option<usize> {
+        let consumed = if rpos < self.ref_start {
+            self.blocks
+                .iter()
+                .find(|block| block.rlen() > 0)
+                .map(|block| block.qstart)
+                .unwrap_or(0)
         } else {
-            span_len as f32
+            match self.blocks.get(self.block_idx_of_ref(rpos)) {
+                Some(block) if block.is_aligned() => block.qstart + rpos - block.rstart,
+                Some(block) => block.qstart,
+                None => return None,
+            }
         };
-        (start, end, eq_len as f32 / span_len)
+        consumed.checked_sub(1)
+    }
+
+    /// same as BamRecordExtensions::aligned_pairs_full. [qpos, rpos]
+    pub fn aligned_pairs_full(&self) -> CigarIndexPairs<'_> {
+        CigarIndexPairs {
+            blocks: &self.blocks,
+            block_idx: 0,
+            offset: 0,
+        }
+    }
+
+    /// aligned_pairs_full, but start from the pair of the ref position.
+    /// the skipped pairs are the pairs before rpos, including the insertions right before rpos.
+    /// if rpos < ref_start, the pairs start from the first ref position (the leading soft clip is skipped)
+    pub fn aligned_pairs_from_ref(&self, rpos: usize) -> CigarIndexPairs<'_> {
+        let (block_idx, offset) = if rpos < self.ref_start {
+            let idx = self
+                .blocks
+                .iter()
+                .position(|block| block.rlen() > 0)
+                .unwrap_or(self.blocks.len());
+            (idx, 0)
+        } else {
+            let idx = self.block_idx_of_ref(rpos);
+            match self.blocks.get(idx) {
+                Some(block) => (idx, rpos - block.rstart),
+                None => (idx, 0),
+            }
+        };
+
+        CigarIndexPairs {
+            blocks: &self.blocks,
+            block_idx,
+            offset,
+        }
+    }
+}
+
+pub struct CigarIndexPairs<'a> {
+    blocks: &'a [CigarBlock],
+    block_idx: usize,
+    offset: usize,
+}
+
+impl<'a> Iterator for CigarIndexPairs<'a> {
+    type Item = [Option<i64>; 2];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let block = self.blocks.get(self.block_idx)?;
+            let len = block.qlen().max(block.rlen());
+            if self.offset >= len {
+                self.block_idx += 1;
+                self.offset = 0;
+                continue;
+            }
+
+            let qpos = if block.qlen() > 0 {
+                Some((block.qstart + self.offset) as i64)
+            } else {
+                None
+            };
+            let rpos = if block.rlen() > 0 {
+                Some((block.rstart + self.offset) as i64)
+            } else {
+                None
+            };
+            self.offset += 1;
+            return Some([qpos, rpos]);
+        }
     }
 }
 
@@ -212,7 +645,7 @@ pub fn long_ins_regions_in_query(cigar_str: &CigarString, ins_thr: usize) -> Vec
     let mut regions = vec![];
     cigar_str.iter().for_each(|&cigar| match cigar {
         Cigar::SoftClip(n) | Cigar::Diff(n) | Cigar::Equal(n) => pos += n as usize,
-        Cigar::Del(_) => {}
+        Cigar::Del(_) | Cigar::HardClip(_) => {}
         Cigar::Ins(n) => {
             let n = n as usize;
             if n >= ins_thr {
@@ -225,13 +658,46 @@ pub fn long_ins_regions_in_query(cigar_str: &CigarString, ins_thr: usize) -> Vec
     regions
 }
 
-pub fn compute_qstart_qend_with_cigar(cigar_str: &CigarString) -> (usize, usize) {
+/// leading soft clip length. the hard clip before the soft clip is skipped (e.g. 5H3S10= -> 3)
+pub fn leading_soft_clip(cigar_str: &CigarString) -> usize {
+    cigar_str
+        .iter()
+        .take_while(|cigar| matches!(cigar, Cigar::HardClip(_) | Cigar::SoftClip(_)))
+        .map(|cigar| match *cigar {
+            Cigar::SoftClip(n) => n as usize,
+            _ => 0,
+        })
+        .sum()
+}
 
-    let first_cigar = cigar_str.first().unwrap();
-    let qstart = match *first_cigar {
-        Cigar::SoftClip(n) => n as usize,
+/// trailing soft clip length. the hard clip after the soft clip is skipped (e.g. 10=3S5H -> 3)
+pub fn trailing_soft_clip(cigar_str: &CigarString) -> usize {
+    cigar_str
+        .iter()
+        .rev()
+        .take_while(|cigar| matches!(cigar, Cigar::HardClip(_) | Cigar::SoftClip(_)))
+        .map(|cigar| match *cigar {
+            Cigar::SoftClip(n) => n as usize,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// (leading hard clip, trailing hard clip)
+pub fn hard_clips(cigar_str: &CigarString) -> (usize, usize) {
+    let leading = match cigar_str.first() {
+        Some(Cigar::HardClip(n)) => *n as usize,
         _ => 0,
     };
+    let trailing = match cigar_str.last() {
+        Some(Cigar::HardClip(n)) if cigar_str.len() > 1 => *n as usize,
+        _ => 0,
+    };
+    (leading, trailing)
+}
+
+pub fn compute_qstart_qend_with_cigar(cigar_str: &CigarString) -> (usize, usize) {
+    let qstart = leading_soft_clip(cigar_str);
     let mut qlen = 0;
     cigar_str.iter().for_each(|cigar| {
         match *cigar {
@@ -245,8 +711,345 @@ pub fn compute_qstart_qend_with_cigar(cigar_str: &CigarString) -> (usize, usize)
 
     (qstart, qstart + qlen)
 }
+/// merge the adjacent identical ops and drop the zero length ops. 2=3=0I1X -> 5=1X
+pub fn compact_cigar(cigar_str: &CigarString) -> CigarString {
+    let mut ops: Vec<Cigar> = Vec::with_capacity(cigar_str.len());
+    for &op in cigar_str.iter() {
+        if op.is_empty() {
+            continue;
+        }
+        match ops.last_mut() {
+            Some(last) if last.char() == op.char() => *last = with_len(*last, last.len() + op.len()),
+            _ => ops.push(op),
+        }
+    }
+    CigarString(ops)
+}
+
+/// cigar of the reverse complemented alignment
+pub fn reverse_cigar(cigar_str: &CigarString) -> CigarString {
+    CigarString(cigar_str.iter().rev().copied().collect())
+}
+
+/// convert the soft clips at the ends to hard clips.
+/// return (new cigar, leading soft clip len, trailing soft clip len), the caller needs to trim the SEQ/QUAL with the lengths
+pub fn soft_clip_to_hard_clip(cigar_str: &CigarString) -> (CigarString, usize, usize) {
+    let leading = leading_soft_clip(cigar_str);
+    let trailing = trailing_soft_clip(cigar_str);
+    let ops = cigar_str
+        .iter()
+        .map(|&op| match op {
+            Cigar::SoftClip(n) => Cigar::HardClip(n),
+            op => op,
+        })
+        .collect();
+    (compact_cigar(&CigarString(ops)), leading, trailing)
+}
+
+/// convert the hard clips to soft clips, the caller needs to provide the full SEQ/QUAL
+pub fn hard_clip_to_soft_clip(cigar_str: &CigarString) -> CigarString {
+    let ops = cigar_str
+        .iter()
+        .map(|&op| match op {
+            Cigar::HardClip(n) => Cigar::SoftClip(n),
+            op => op,
+        })
+        .collect();
+    compact_cigar(&CigarString(ops))
+}
+
+/// trim the alignment to the reference window [rstart, rend).
+/// the query bases out of the window become soft clip, the deletions and insertions at the edges are removed / soft clipped.
+/// return (new cigar, new pos). None if no query base is aligned in the window
+pub fn trim_cigar_to_ref_window(
+    cigar_str: &CigarString,
+    pos: usize,
+    rstart: usize,
+    rend: usize,
+) -> Option<(CigarString, usize)> {
+    let mut ops = vec![];
+    let mut new_pos = None;
+    let mut rpos = pos;
+    for &op in cigar_str.iter() {
+        match op {
+            Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) | Cigar::Del(_) | Cigar::RefSkip(_) => {
+                let n = op.len() as usize;
+                let inner_start = rpos.clamp(rstart, rend.max(rstart));
+                let inner_end = (rpos + n).clamp(rstart, rend.max(rstart));
+                let (before, inner, after) = if inner_start < inner_end {
+                    (inner_start - rpos, inner_end - inner_start, rpos + n - inner_end)
+                } else if rpos + n <= rstart {
+                    (n, 0, 0)
+                } else {
+                    (0, 0, n)
+                };
+                let consume_query = matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_));
+                if consume_query {
+                    ops.push(Cigar::SoftClip(before as u32));
+                }
+                if inner > 0 {
+                    if consume_query && new_pos.is_none() {
+                        new_pos = Some(inner_start);
+                    }
+                    ops.push(with_len(op, inner as u32));
+                }
+                if consume_query {
+                    ops.push(Cigar::SoftClip(after as u32));
+                }
+                rpos += n;
+            }
+            Cigar::Ins(n) => {
+                if rstart <= rpos && rpos <= rend {
+                    ops.push(op);
+                } else {
+                    ops.push(Cigar::SoftClip(n));
+                }
+            }
+            Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => ops.push(op),
+        }
+    }
+
+    new_pos.map(|new_pos| (fix_alignment_edges(ops), new_pos))
+}
+
+/// trim the alignment to the query window [qstart, qend). qstart and qend are positions in SEQ.
+/// the query bases out of the window become soft clip.
+/// return (new cigar, new pos). None if no query base in the window is aligned
+pub fn trim_cigar_to_query_window(
+    cigar_str: &CigarString,
+    pos: usize,
+    qstart: usize,
+    qend: usize,
+) -> Option<(CigarString, usize)> {
+    let mut ops = vec![];
+    let mut new_pos = None;
+    let mut rpos = pos;
+    let mut qpos = 0;
+    for &op in cigar_str.iter() {
+        match op {
+            Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) | Cigar::Ins(_) | Cigar::SoftClip(_) => {
+                let n = op.len() as usize;
+                let inner_start = qpos.clamp(qstart, qend.max(qstart));
+                let inner_end = (qpos + n).clamp(qstart, qend.max(qstart));
+                let (before, inner) = if inner_start < inner_end {
+                    (inner_start - qpos, inner_end - inner_start)
+                } else if qpos + n <= qstart {
+                    (n, 0)
+                } else {
+                    (0, 0)
+                };
+                let after = n - before - inner;
+                ops.push(Cigar::SoftClip(before as u32));
+                if inner > 0 {
+                    let consume_ref = matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_));
+                    if consume_ref && new_pos.is_none() {
+                        new_pos = Some(rpos + before);
+                    }
+                    ops.push(with_len(op, inner as u32));
+                }
+                ops.push(Cigar::SoftClip(after as u32));
+
+                qpos += n;
+                if matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_)) {
+                    rpos += n;
+                }
+            }
+            Cigar::Del(n) | Cigar::RefSkip(n) => {
+                if qstart < qpos && qpos < qend {
+                    ops.push(op);
+                }
+                rpos += n as usize;
+            }
+            Cigar::HardClip(_) | Cigar::Pad(_) => ops.push(op),
+        }
+    }
+
+    new_pos.map(|new_pos| (fix_alignment_edges(ops), new_pos))
+}
+
+/// split the alignment at the reference position. left part: ref < rpos, right part: ref >= rpos.
+/// each part is (cigar, pos) with the query bases of the other part soft clipped, None if the part is empty
+#[allow(clippy::type_complexity)]
+pub fn split_cigar_at_ref(
+    cigar_str: &CigarString,
+    pos: usize,
+    rpos: usize,
+) -> (Option<(CigarString, usize)>, Option<(CigarString, usize)>) {
+    (
+        trim_cigar_to_ref_window(cigar_str, pos, pos.min(rpos), rpos),
+        trim_cigar_to_ref_window(cigar_str, pos, rpos, usize::MAX),
+    )
+}
+
+/// liftover. query_to_ref: query aligned to ref at query_to_ref_pos;
+/// ref_to_ref2: ref (as the query, SEQ is the whole ref) aligned to ref2 at ref_to_ref2_pos.
+/// return the alignment of query to ref2 (cigar, pos). None if no query base can be aligned to ref2.
+///
+/// =,= -> =; =,X -> X; X,X and M -> M
+pub fn compose_cigars(
+    query_to_ref: &CigarString,
+    query_to_ref_pos: usize,
+    ref_to_ref2: &CigarString,
+    ref_to_ref2_pos: usize,
+) -> Option<(CigarString, usize)> {
+    // ref_to_ref2 ops in ref coordinate. the ref positions that are not covered are treated as soft clip
+    let mut ref_ops = ref_to_ref2
+        .iter()
+        .filter(|op| !matches!(op, Cigar::Pad(_)))
+        .map(|&op| match op {
+            Cigar::HardClip(n) => Cigar::SoftClip(n),
+            op => op,
+        })
+        .collect::<Vec<_>>();
+    ref_ops.push(Cigar::SoftClip(u32::MAX));
+
+    let mut ref_ops = ref_ops.into_iter().filter(|op| !op.is_empty());
+    let mut ref_op = ref_ops.next().unwrap();
+    let mut ref_op_remain = ref_op.len() as usize;
+    let mut ref2_pos = ref_to_ref2_pos;
+    // ref position of the ref_op
+    let mut ref_pos = 0_usize;
+
+    // skip to query_to_ref_pos
+    loop {
+        let consume_ref = !matches!(ref_op, Cigar::Del(_) | Cigar::RefSkip(_));
+        let consume_ref2 = matches!(
+            ref_op,
+            Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) | Cigar::Del(_) | Cigar::RefSkip(_)
+        );
+        let n = if consume_ref {
+            ref_op_remain.min(query_to_ref_pos - ref_pos)
+        } else {
+            ref_op_remain
+        };
+        if n == 0 {
+            break;
+        }
+        if consume_ref {
+            ref_pos += n;
+        }
+        if consume_ref2 {
+            ref2_pos += n;
+        }
+        ref_op_remain -= n;
+        if ref_op_remain == 0 {
+            ref_op = ref_ops.next().unwrap();
+            ref_op_remain = ref_op.len() as usize;
+        }
+    }
+
+    let mut ops = vec![];
+    let mut new_pos = None;
+    for &op in query_to_ref.iter() {
+        let mut remain = op.len() as usize;
+        match op {
+            Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {
+                ops.push(op);
+                continue;
+            }
+            _ => {}
+        }
+
+        while remain > 0 {
+            if matches!(ref_op, Cigar::Del(_) | Cigar::RefSkip(_)) {
+                ops.push(Cigar::Del(ref_op_remain as u32));
+                ref2_pos += ref_op_remain;
+                ref_op = ref_ops.next().unwrap();
+                ref_op_remain = ref_op.len() as usize;
+                continue;
+            }
+
+            let n = remain.min(ref_op_remain);
+            let query_aligned = matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_));
+            let ref_aligned = matches!(ref_op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_));
+            match (query_aligned, ref_aligned) {
+                (true, true) => {
+                    if new_pos.is_none() {
+                        new_pos = Some(ref2_pos);
+                    }
+                    ops.push(with_len(compose_match(op, ref_op), n as u32));
+                }
+                (true, false) => match ref_op {
+                    Cigar::Ins(_) => ops.push(Cigar::Ins(n as u32)),
+                    _ => ops.push(Cigar::SoftClip(n as u32)),
+                },
+                (false, true) => ops.push(Cigar::Del(n as u32)),
+                (false, false) => {}
+            }
+
+            if ref_aligned {
+                ref2_pos += n;
+            }
+            remain -= n;
+            ref_op_remain -= n;
+            if ref_op_remain == 0 {
+                ref_op = ref_ops.next().unwrap();
+                ref_op_remain = ref_op.len() as usize;
+            }
+        }
+    }
+
+    new_pos.map(|new_pos| (fix_alignment_edges(ops), new_pos))
+}
+
+fn compose_match(a: Cigar, b: Cigar) -> Cigar {
+    match (a, b) {
+        (Cigar::Equal(_), Cigar::Equal(_)) => Cigar::Equal(0),
+        (Cigar::Equal(_), Cigar::Diff(_)) | (Cigar::Diff(_), Cigar::Equal(_)) => Cigar::Diff(0),
+        _ => Cigar::Match(0),
+    }
+}
+
+fn with_len(op: Cigar, n: u32) -> Cigar {
+    match op {
+        Cigar::Match(_) => Cigar::Match(n),
+        Cigar::Ins(_) => Cigar::Ins(n),
+        Cigar::Del(_) => Cigar::Del(n),
+        Cigar::RefSkip(_) => Cigar::RefSkip(n),
+        Cigar::SoftClip(_) => Cigar::SoftClip(n),
+        Cigar::HardClip(_) => Cigar::HardClip(n),
+        Cigar::Pad(_) => Cigar::Pad(n),
+        Cigar::Equal(_) => Cigar::Equal(n),
+        Cigar::Diff(_) => Cigar::Diff(n),
+    }
+}
+
+/// before the first and after the last aligned op: I -> S, D/N are removed. then compact
+fn fix_alignment_edges(ops: Vec<Cigar>) -> CigarString {
+    let is_aligned = |op: &Cigar| matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_));
+    let first = ops.iter().position(is_aligned);
+    let last = ops.iter().rposition(is_aligned);
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return compact_cigar(&CigarString(ops)),
+    };
+
+    let fix_edge = |op: &Cigar| match *op {
+        Cigar::Ins(n) => Some(Cigar::SoftClip(n)),
+        Cigar::Del(_) | Cigar::RefSkip(_) => None,
+        op => Some(op),
+    };
+
+    let mut leading = ops[..first].iter().filter_map(fix_edge).collect::<Vec<_>>();
+    let mut trailing = ops[last + 1..].iter().filter_map(fix_edge).collect::<Vec<_>>();
+    // hard clip must be the outermost op
+    leading.sort_by_key(|op| !matches!(op, Cigar::HardClip(_)));
+    trailing.sort_by_key(|op| matches!(op, Cigar::HardClip(_)));
+
+    let mut res = leading;
+    res.extend_from_slice(&ops[first..=last]);
+    res.extend(trailing);
+    compact_cigar(&CigarString(res))
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::gsbam::test_utils::RecordBuilder;
+
     use super::*;
 
     #[test]
@@ -348,11 +1151,452 @@ mod tests {
         println!("{:?}", calc.compute_range_identity(1, 4));
     }
 
+    #[test]
+    fn test_range_identity_with_metric() {
+        // ref:   CGTTTA--CGGA
+        // query: CGTT-AAACCGA
+        let cigar_str = parse_cigar_string("4=1D1=2I1=1X2=").unwrap();
+        let ref_seq = format!("{}CGTTTACGGA", "N".repeat(100));
+        let calculator = RangeIdentityCalculator::new_with_seqs(
+            &cigar_str,
+            b"CGTTAAACCGA",
+            Some(ref_seq.as_bytes()),
+            100,
+        );
+
+        let identity = |start, end, metric| {
+            calculator
+                .compute_range_identity_with_metric(start, end, metric)
+                .2
+        };
+        assert!((identity(0, 11, IdentityMetric::Blast) - 8.0 / 12.0).abs() < 1e-6);
+        assert!((identity(0, 11, IdentityMetric::GapCompressed) - 8.0 / 11.0).abs() < 1e-6);
+        assert!((identity(0, 11, IdentityMetric::ConcordanceNoHpIndel) - 8.0 / 9.0).abs() < 1e-6);
+        assert!((identity(5, 8, IdentityMetric::Blast) - 1.0 / 3.0).abs() < 1e-6);
+        assert!((identity(5, 8, IdentityMetric::ConcordanceNoHpIndel) - 1.0).abs() < 1e-6);
+        assert_eq!(
+            identity(5, 8, IdentityMetric::Blast),
+            calculator.compute_range_identity(5, 8).2
+        );
+
+        // without seqs, no indel is treated as homopolymer indel
+        let calculator = RangeIdentityCalculator::new(&cigar_str);
+        let (_, _, identity) = calculator.compute_range_identity_with_metric(
+            0,
+            11,
+            IdentityMetric::ConcordanceNoHpIndel,
+        );
+        assert!((identity - 8.0 / 12.0).abs() < 1e-6);
+
+        assert_eq!(
+            homopolymer_indel_flags(&cigar_str, b"CGTTAAACCGA", None, 100),
+            vec![false, false, false, true, false, false, false]
+        );
+
+        // M ops in the middle of the range are skipped
+        let calculator = RangeIdentityCalculator::new(&parse_cigar_string("2=3M1X2=").unwrap());
+        assert!((calculator.compute_range_identity(0, 8).2 - 4.0 / 5.0).abs() < 1e-6);
+    }
+
     #[test]
     fn test_parse_cigar_str() {
         println!("{:?}", parse_cigar_string("4=3S"));
     }
 
+    #[test]
+    fn test_clips() {
+        let cigar_str = parse_cigar_string("5H3S4=1I2=2S1H").unwrap();
+        assert_eq!(leading_soft_clip(&cigar_str), 3);
+        assert_eq!(trailing_soft_clip(&cigar_str), 2);
+        assert_eq!(hard_clips(&cigar_str), (5, 1));
+        assert_eq!(compute_qstart_qend_with_cigar(&cigar_str), (3, 10));
+
+        let cigar_str = parse_cigar_string("4=").unwrap();
+        assert_eq!(leading_soft_clip(&cigar_str), 0);
+        assert_eq!(trailing_soft_clip(&cigar_str), 0);
+        assert_eq!(hard_clips(&cigar_str), (0, 0));
+    }
+
+    #[test]
+    fn test_cigar_index_pos_mapping() {
+        // ref:   ---ACGT-AC--GTA--
+        // query: SSSACGTAAC--TAGG
+        let cigar_index = CigarIndex::new(&parse_cigar_string("2H3S4=1I2=2D1X1=2S").unwrap(), 100);
+        assert_eq!(cigar_index.ref_start(), 100);
+        assert_eq!(cigar_index.ref_end(), 110);
+
+        assert_eq!(cigar_index.ref_pos_to_query_pos(99), RefPosMapping::Outside);
+        assert_eq!(
+            cigar_index.ref_pos_to_query_pos(100),
+            RefPosMapping::Aligned { qpos: 3, ins_len: 0 }
+        );
+        assert_eq!(
+            cigar_index.ref_pos_to_query_pos(103),
+            RefPosMapping::Aligned { qpos: 6, ins_len: 1 }
+        );
+        assert_eq!(
+            cigar_index.ref_pos_to_query_pos(104),
+            RefPosMapping::Aligned { qpos: 8, ins_len: 0 }
+        );
+        assert_eq!(
+            cigar_index.ref_pos_to_query_pos(107),
+            RefPosMapping::Deleted { qpos: 10 }
+        );
+        assert_eq!(
+            cigar_index.ref_pos_to_query_pos(109),
+            RefPosMapping::Aligned { qpos: 11, ins_len: 0 }
+        );
+        assert_eq!(cigar_index.ref_pos_to_query_pos(110), RefPosMapping::Outside);
+
+        assert_eq!(cigar_index.query_pos_to_ref_pos(2), None);
+        assert_eq!(cigar_index.query_pos_to_ref_pos(3), Some(100));
+        assert_eq!(cigar_index.query_pos_to_ref_pos(7), None);
+        assert_eq!(cigar_index.query_pos_to_ref_pos(10), Some(108));
+        assert_eq!(cigar_index.query_pos_to_ref_pos(12), None);
+        assert_eq!(cigar_index.query_pos_to_ref_pos(100), None);
+
+        assert_eq!(cigar_index.query_range_to_ref_range(0, 14), Some((100, 110)));
+        assert_eq!(cigar_index.query_range_to_ref_range(0, 3), None);
+        assert_eq!(cigar_index.query_range_to_ref_range(4, 8), Some((101, 104)));
+        assert_eq!(cigar_index.query_range_to_ref_range(7, 8), None);
+        assert_eq!(cigar_index.query_range_to_ref_range(9, 11), Some((105, 109)));
+        assert_eq!(cigar_index.query_range_to_ref_range(10, 11), Some((108, 109)));
+        assert_eq!(cigar_index.query_range_to_ref_range(12, 14), None);
+
+        assert_eq!(cigar_index.query_pos_before_ref(50), Some(2));
+        assert_eq!(cigar_index.query_pos_before_ref(100), Some(2));
+        assert_eq!(cigar_index.query_pos_before_ref(104), Some(7));
+        assert_eq!(cigar_index.query_pos_before_ref(107), Some(9));
+        assert_eq!(cigar_index.query_pos_before_ref(110), None);
+    }
+
+    #[test]
+    fn test_cigar_index_aligned_pairs() {
+        use rust_htslib::bam::ext::BamRecordExtensions;
+
+        let mut rng = StdRng::seed_from_u64(17);
+        for _ in 0..200 {
+            let mut cigar = String::new();
+            if rng.gen_bool(0.5) {
+                cigar.push_str(&format!("{}H", rng.gen_range(1..=5)));
+            }
+            if rng.gen_bool(0.5) {
+                cigar.push_str(&format!("{}S", rng.gen_range(1..=5)));
+            }
+            cigar.push_str(&format!("{}=", rng.gen_range(1..=5)));
+            for _ in 0..rng.gen_range(0..8) {
+                let op = ["=", "X", "I", "D", "N"][rng.gen_range(0..5)];
+                cigar.push_str(&format!("{}{}", rng.gen_range(1..=5), op));
+            }
+            cigar.push_str(&format!("{}=", rng.gen_range(1..=5)));
+            if rng.gen_bool(0.5) {
+                cigar.push_str(&format!("{}S", rng.gen_range(1..=5)));
+            }
+
+            let cigar_str = parse_cigar_string(&cigar).unwrap();
+            let qlen = CigarIndex::new(&cigar_str, 0)
+                .blocks()
+                .iter()
+                .map(|block| block.qlen())
+                .sum();
+            let record = RecordBuilder::new(&cigar, "A".repeat(qlen)).pos(50).build();
+            let cigar_index = CigarIndex::from_record(&record);
+            let expected = record.aligned_pairs_full().collect::<Vec<_>>();
+            assert_eq!(
+                cigar_index.aligned_pairs_full().collect::<Vec<_>>(),
+                expected,
+                "{}",
+                cigar
+            );
+
+            for rpos in 45..(cigar_index.ref_end() + 3) {
+                let mut rpos_cursor = None;
+                let expected_from_ref = expected
+                    .iter()
+                    .copied()
+                    .skip_while(|&[_, r]| {
+                        if r.is_some() {
+                            rpos_cursor = r;
+                        }
+                        rpos_cursor.map(|r| (r as usize) < rpos).unwrap_or(true)
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    cigar_index.aligned_pairs_from_ref(rpos).collect::<Vec<_>>(),
+                    expected_from_ref,
+                    "{}, {}",
+                    cigar,
+                    rpos
+                );
+
+                let mut qpos_cursor = None;
+                let mut rpos_cursor = None;
+                for [q, r] in expected.iter().copied() {
+                    if r.is_some() {
+                        rpos_cursor = r;
+                    }
+                    if rpos_cursor.map(|r| (r as usize) >= rpos).unwrap_or(false) {
+                        break;
+                    }
+                    if q.is_some() {
+                        qpos_cursor = q.map(|q| q as usize);
+                    }
+                }
+                if rpos < cigar_index.ref_end() {
+                    assert_eq!(
+                        cigar_index.query_pos_before_ref(rpos),
+                        qpos_cursor,
+                        "{}, {}",
+                        cigar,
+                        rpos
+                    );
+                }
+            }
+        }
+    }
+
+    fn random_cigar(rng: &mut StdRng, with_hard_clip: bool) -> CigarString {
+        let mut cigar = String::new();
+        if with_hard_clip && rng.gen_bool(0.5) {
+            cigar.push_str(&format!("{}H", rng.gen_range(1..5)));
+        }
+        if rng.gen_bool(0.5) {
+            cigar.push_str(&format!("{}S", rng.gen_range(1..5)));
+        }
+        cigar.push_str(&format!("{}=", rng.gen_range(1..5)));
+        for _ in 0..rng.gen_range(0..10) {
+            let op = ["=", "X", "M", "I", "D", "N"][rng.gen_range(0..6)];
+            cigar.push_str(&format!("{}{}", rng.gen_range(1..5), op));
+        }
+        cigar.push_str(&format!("{}X", rng.gen_range(1..5)));
+        if rng.gen_bool(0.5) {
+            cigar.push_str(&format!("{}S", rng.gen_range(1..5)));
+        }
+        if with_hard_clip && rng.gen_bool(0.5) {
+            cigar.push_str(&format!("{}H", rng.gen_range(1..5)));
+        }
+        parse_cigar_string(&cigar).unwrap()
+    }
+
+    fn query_len(cigar_str: &CigarString) -> usize {
+        CigarIndex::new(cigar_str, 0)
+            .blocks()
+            .iter()
+            .map(|block| block.qlen())
+            .sum()
+    }
+
+    fn aligned_pairs(cigar_str: &CigarString, pos: usize) -> Vec<(usize, usize)> {
+        CigarIndex::new(cigar_str, pos)
+            .aligned_pairs_full()
+            .filter_map(|[q, r]| Some((q? as usize, r? as usize)))
+            .collect()
+    }
+
+    /// clips only at the ends (H outermost), the first and last non-clip ops are aligned ops
+    fn assert_valid_cigar(cigar_str: &CigarString) {
+        let ops = cigar_str.iter().collect::<Vec<_>>();
+        let is_clip = |op: &&Cigar| matches!(op, Cigar::SoftClip(_) | Cigar::HardClip(_));
+        let first = ops.iter().position(|op| !is_clip(op)).unwrap();
+        let last = ops.iter().rposition(|op| !is_clip(op)).unwrap();
+        assert!(ops[first..=last].iter().all(|op| !is_clip(op)), "{}", cigar_str);
+        for op in [ops[first], ops[last]] {
+            assert!(
+                matches!(op, Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_)),
+                "{}",
+                cigar_str
+            );
+        }
+        for clips in [&ops[..first], &ops[last + 1..]] {
+            assert!(clips.len() <= 2, "{}", cigar_str);
+        }
+        if first == 2 {
+            assert!(matches!(ops[0], Cigar::HardClip(_)), "{}", cigar_str);
+        }
+        if last + 3 == ops.len() {
+            assert!(matches!(ops[ops.len() - 1], Cigar::HardClip(_)), "{}", cigar_str);
+        }
+        assert!(
+            ops.windows(2).all(|w| w[0].char() != w[1].char() && !w[0].is_empty()),
+            "{}",
+            cigar_str
+        );
+    }
+
+    #[test]
+    fn test_compact_and_reverse_cigar() {
+        let cigar_str = parse_cigar_string("2S2=3=0I1X1X2D1D").unwrap();
+        assert_eq!(compact_cigar(&cigar_str).to_string(), "2S5=2X3D");
+        assert_eq!(
+            reverse_cigar(&parse_cigar_string("2H3S4=1I2=").unwrap()).to_string(),
+            "2=1I4=3S2H"
+        );
+
+        let mut rng = StdRng::seed_from_u64(31);
+        for _ in 0..200 {
+            let cigar_str = random_cigar(&mut rng, true);
+            let compacted = compact_cigar(&cigar_str);
+            assert_valid_cigar(&compacted);
+            assert_eq!(aligned_pairs(&compacted, 10), aligned_pairs(&cigar_str, 10));
+
+            let reversed = reverse_cigar(&cigar_str);
+            assert_eq!(reverse_cigar(&reversed), cigar_str);
+
+            let qlen = query_len(&cigar_str);
+            let rend = CigarIndex::new(&cigar_str, 10).ref_end();
+            let mut expected = aligned_pairs(&cigar_str, 10)
+                .into_iter()
+                .map(|(q, r)| (qlen - 1 - q, 10 + rend - 1 - r))
+                .collect::<Vec<_>>();
+            expected.reverse();
+            assert_eq!(aligned_pairs(&reversed, 10), expected, "{}", cigar_str);
+        }
+    }
+
+    #[test]
+    fn test_clip_conversion() {
+        let cigar_str = parse_cigar_string("2H3S4=1I2=2S").unwrap();
+        let (hard, leading, trailing) = soft_clip_to_hard_clip(&cigar_str);
+        assert_eq!(hard.to_string(), "5H4=1I2=2H");
+        assert_eq!((leading, trailing), (3, 2));
+        assert_eq!(hard_clip_to_soft_clip(&hard).to_string(), "5S4=1I2=2S");
+
+        let mut rng = StdRng::seed_from_u64(31);
+        for _ in 0..100 {
+            let cigar_str = compact_cigar(&random_cigar(&mut rng, false));
+            let (hard, leading, trailing) = soft_clip_to_hard_clip(&cigar_str);
+            assert_valid_cigar(&hard);
+            assert_eq!(query_len(&hard) + leading + trailing, query_len(&cigar_str));
+            assert_eq!(hard_clip_to_soft_clip(&hard), cigar_str);
+        }
+    }
+
+    #[test]
+    fn test_trim_cigar() {
+        // ref:     01234-567  (+100)
+        // query: SSAC-GTTACG
+        let cigar_str = parse_cigar_string("2S2=1D2=1I3=").unwrap();
+        let (trimmed, pos) = trim_cigar_to_ref_window(&cigar_str, 100, 102, 105).unwrap();
+        assert_eq!((trimmed.to_string(), pos), ("4S2=4S".to_string(), 103));
+        let (trimmed, pos) = trim_cigar_to_ref_window(&cigar_str, 100, 103, 106).unwrap();
+        assert_eq!((trimmed.to_string(), pos), ("4S2=1I1=2S".to_string(), 103));
+        assert!(trim_cigar_to_ref_window(&cigar_str, 100, 108, 120).is_none());
+        assert!(trim_cigar_to_ref_window(&cigar_str, 100, 102, 103).is_none());
+
+        let (trimmed, pos) = trim_cigar_to_query_window(&cigar_str, 100, 3, 7).unwrap();
+        assert_eq!((trimmed.to_string(), pos), ("3S1=1D2=4S".to_string(), 101));
+        assert!(trim_cigar_to_query_window(&cigar_str, 100, 0, 2).is_none());
+
+        let mut rng = StdRng::seed_from_u64(31);
+        for _ in 0..300 {
+            let cigar_str = random_cigar(&mut rng, true);
+            let qlen = query_len(&cigar_str);
+            let pairs = aligned_pairs(&cigar_str, 100);
+            let rend = CigarIndex::new(&cigar_str, 100).ref_end();
+
+            let ws = rng.gen_range(95..rend + 3);
+            let we = rng.gen_range(ws..rend + 5);
+            let expected = pairs
+                .iter()
+                .copied()
+                .filter(|&(_, r)| ws <= r && r < we)
+                .collect::<Vec<_>>();
+            match trim_cigar_to_ref_window(&cigar_str, 100, ws, we) {
+                Some((trimmed, pos)) => {
+                    assert_valid_cigar(&trimmed);
+                    assert_eq!(query_len(&trimmed), qlen);
+                    assert_eq!(hard_clips(&trimmed), hard_clips(&cigar_str));
+                    assert_eq!(pos, expected[0].1);
+                    assert_eq!(aligned_pairs(&trimmed, pos), expected, "{} {} {}", cigar_str, ws, we);
+                }
+                None => assert!(expected.is_empty(), "{} {} {}", cigar_str, ws, we),
+            }
+
+            let qs = rng.gen_range(0..qlen + 2);
+            let qe = rng.gen_range(qs..qlen + 3);
+            let expected = pairs
+                .iter()
+                .copied()
+                .filter(|&(q, _)| qs <= q && q < qe)
+                .collect::<Vec<_>>();
+            match trim_cigar_to_query_window(&cigar_str, 100, qs, qe) {
+                Some((trimmed, pos)) => {
+                    assert_valid_cigar(&trimmed);
+                    assert_eq!(query_len(&trimmed), qlen);
+                    assert_eq!(pos, expected[0].1);
+                    assert_eq!(aligned_pairs(&trimmed, pos), expected, "{} {} {}", cigar_str, qs, qe);
+                }
+                None => assert!(expected.is_empty(), "{} {} {}", cigar_str, qs, qe),
+            }
+
+            let rsplit = rng.gen_range(98..rend + 2);
+            let (left, right) = split_cigar_at_ref(&cigar_str, 100, rsplit);
+            let mut union = vec![];
+            for (part, pos) in left.into_iter().chain(right) {
+                assert_valid_cigar(&part);
+                assert_eq!(query_len(&part), qlen);
+                union.extend(aligned_pairs(&part, pos));
+            }
+            assert_eq!(union, pairs, "{} {}", cigar_str, rsplit);
+        }
+    }
+
+    #[test]
+    fn test_compose_cigars() {
+        // query -> ref:  2=1I3=   at 1
+        // ref -> ref2:   3=2D4=   at 10
+        // query -> ref2: 2=1I2D3=    at 11
+        let (composed, pos) = compose_cigars(
+            &parse_cigar_string("2=1I3=").unwrap(),
+            1,
+            &parse_cigar_string("3=2D4=").unwrap(),
+            10,
+        )
+        .unwrap();
+        assert_eq!((composed.to_string(), pos), ("2=1I2D3=".to_string(), 11));
+
+        let mut rng = StdRng::seed_from_u64(31);
+        let mut n_composed = 0;
+        for _ in 0..500 {
+            let ref_to_ref2 = random_cigar(&mut rng, true);
+            let ref_len = query_len(&hard_clip_to_soft_clip(&ref_to_ref2));
+            let query_to_ref = random_cigar(&mut rng, true);
+            let span = CigarIndex::new(&query_to_ref, 0).ref_end();
+            if span > ref_len {
+                continue;
+            }
+            let query_to_ref_pos = rng.gen_range(0..=ref_len - span);
+
+            let ref_to_ref2_pairs = aligned_pairs(&hard_clip_to_soft_clip(&ref_to_ref2), 50)
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+            let expected = aligned_pairs(&query_to_ref, query_to_ref_pos)
+                .into_iter()
+                .filter_map(|(q, r)| ref_to_ref2_pairs.get(&r).map(|&r2| (q, r2)))
+                .collect::<Vec<_>>();
+
+            match compose_cigars(&query_to_ref, query_to_ref_pos, &ref_to_ref2, 50) {
+                Some((composed, pos)) => {
+                    n_composed += 1;
+                    assert_valid_cigar(&composed);
+                    assert_eq!(query_len(&composed), query_len(&query_to_ref));
+                    assert_eq!(hard_clips(&composed), hard_clips(&query_to_ref));
+                    assert_eq!(pos, expected[0].1);
+                    assert_eq!(
+                        aligned_pairs(&composed, pos),
+                        expected,
+                        "{} {} {}",
+                        query_to_ref,
+                        query_to_ref_pos,
+                        ref_to_ref2
+                    );
+                }
+                None => assert!(expected.is_empty()),
+            }
+        }
+        assert!(n_composed > 100);
+    }
+
     #[test]
     fn test_long_ins_regions_in_query() {
         let cigar_str = parse_cigar_string("10I2=").unwrap();
@@ -0,0 +1,498 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context};
+use rust_htslib::bam::record::Cigar;
+
+use crate::poly_n::find_poly_n_regions;
+
+use super::{
+    bam_reader::for_each_primary_record_with_ref,
+    bam_record_ext::{BamRecord, BamRecordExt},
+};
+
+/// index of the substitution matrix
+pub const BASES: [u8; 5] = *b"ACGTN";
+
+fn base_idx(base: u8) -> usize {
+    match base.to_ascii_uppercase() {
+        b'A' => 0,
+        b'C' => 1,
+        b'G' => 2,
+        b'T' => 3,
+        _ => 4,
+    }
+}
+
+/// n_bases is the denominator. ref bases for the homopolymer/non-homopolymer counts, aligned query bases (=/X/I) for the position bins
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub n_bases: usize,
+    pub mismatch: usize,
+    pub ins: usize,
+    pub del: usize,
+}
+
+impl ErrorCounts {
+    pub fn merge(&mut self, other: &ErrorCounts) {
+        self.n_bases += other.n_bases;
+        self.mismatch += other.mismatch;
+        self.ins += other.ins;
+        self.del += other.del;
+    }
+
+    pub fn n_errors(&self) -> usize {
+        self.mismatch + self.ins + self.del
+    }
+
+    pub fn error_rate(&self) -> Option<f64> {
+        if self.n_bases == 0 {
+            None
+        } else {
+            Some(self.n_errors() as f64 / self.n_bases as f64)
+        }
+    }
+}
+
+/// alignment error profile. built from one record by `ErrorProfile::from_record`,
+/// profiles of different records can be merged into a report of the whole bam.
+///
+/// the mismatches are determined by comparing the query to the reference, so M cigar is fine.
+/// the substitutions are in reference forward strand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorProfile {
+    pub n_records: usize,
+    pub n_match: usize,
+    pub n_mismatch: usize,
+    pub n_ins_events: usize,
+    pub n_ins_bases: usize,
+    pub n_del_events: usize,
+    pub n_del_bases: usize,
+    /// substitutions[ref_base][query_base], index of BASES. the diagonal is the matched bases
+    pub substitutions: [[usize; 5]; 5],
+    /// insertion length -> number of insertion events
+    pub ins_len_hist: BTreeMap<usize, usize>,
+    /// deletion length -> number of deletion events
+    pub del_len_hist: BTreeMap<usize, usize>,
+    /// errors in the reference homopolymers (find_poly_n_regions).
+    /// an insertion is a homopolymer error if the inserted bases are the same as the adjacent homopolymer base
+    pub homopolymer: ErrorCounts,
+    pub non_homopolymer: ErrorCounts,
+    /// errors along the original read (sequenced strand, hard clips included), the read is splitted into pos_bins.len() bins
+    pub pos_bins: Vec<ErrorCounts>,
+}
+
+impl ErrorProfile {
+    pub fn new(n_pos_bins: usize) -> Self {
+        Self {
+            n_records: 0,
+            n_match: 0,
+            n_mismatch: 0,
+            n_ins_events: 0,
+            n_ins_bases: 0,
+            n_del_events: 0,
+            n_del_bases: 0,
+            substitutions: [[0; 5]; 5],
+            ins_len_hist: BTreeMap::new(),
+            del_len_hist: BTreeMap::new(),
+            homopolymer: ErrorCounts::default(),
+            non_homopolymer: ErrorCounts::default(),
+            pos_bins: vec![ErrorCounts::default(); n_pos_bins],
+        }
+    }
+
+    /// ref_seq: the whole reference sequence that the record aligned to
+    pub fn from_record(
+        record: &BamRecord,
+        ref_seq: &[u8],
+        n_pos_bins: usize,
+    ) -> anyhow::Result<Self> {
+        let mut profile = Self::new(n_pos_bins);
+        profile.update(record, ref_seq)?;
+        Ok(profile)
+    }
+
+    /// add the errors of the record. unmapped record is ignored.
+    /// RefSkip (N) ops are introns, not deletions, they are ignored.
+    /// Err if the alignment runs past ref_seq, e.g. the bam is aligned to another reference
+    pub fn update(&mut self, record: &BamRecord, ref_seq: &[u8]) -> anyhow::Result<()> {
+        if record.is_unmapped() {
+            return Ok(());
+        }
+        let record_ext = BamRecordExt::new(record);
+        let ref_start = record_ext.reference_start();
+        let ref_end = record_ext.reference_end();
+        if ref_end > ref_seq.len() {
+            bail!(
+                "alignment {}-{} runs past the reference, len {}",
+                ref_start,
+                ref_end,
+                ref_seq.len()
+            );
+        }
+        let mut ref_skips = vec![];
+        let mut rpos = ref_start;
+        for cigar in record.cigar().iter() {
+            match *cigar {
+                Cigar::RefSkip(n) => ref_skips.push((rpos, rpos + n as usize)),
+                Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) | Cigar::Del(_) => {}
+                _ => continue,
+            }
+            rpos += cigar.len() as usize;
+        }
+        let is_ref_skip = |rpos: usize| ref_skips.iter().any(|&(s, e)| s <= rpos && rpos < e);
+        let qstart = record_ext.query_alignment_start();
+        let qend = record_ext.query_alignment_end();
+        let query_seq = record.seq().as_bytes();
+        let homopolymer_bases = homopolymer_bases(ref_seq, ref_start, ref_end);
+        let hp_base = |rpos: usize| {
+            rpos.checked_sub(ref_start)
+                .and_then(|idx| homopolymer_bases.get(idx).copied().flatten())
+        };
+
+        let n_pos_bins = self.pos_bins.len();
+        let read_len = record_ext.original_query_len().max(1);
+        let pos_bin = |qpos: usize| record_ext.seq_pos_to_read_pos(qpos) * n_pos_bins / read_len;
+
+        self.n_records += 1;
+
+        // (qstart, len) of the current insertion, (rstart, len) of the current deletion
+        let mut ins_run: Option<(usize, usize)> = None;
+        let mut del_run: Option<(usize, usize)> = None;
+        // the last aligned query position, the ref position after the last aligned / deleted ref position
+        let mut last_qpos = qstart;
+        let mut next_rpos = ref_start;
+
+        for [qpos, rpos] in record_ext.cigar_index().aligned_pairs_full() {
+            match (qpos.map(|v| v as usize), rpos.map(|v| v as usize)) {
+                (Some(qpos), Some(rpos)) => {
+                    self.flush_ins(ins_run.take(), &query_seq, next_rpos, &hp_base, &pos_bin);
+                    self.flush_del(del_run.take(), last_qpos, &hp_base, &pos_bin);
+
+                    let ref_base = base_idx(ref_seq[rpos]);
+                    let query_base = base_idx(query_seq[qpos]);
+                    self.substitutions[ref_base][query_base] += 1;
+                    let is_mismatch = ref_base != query_base;
+                    if is_mismatch {
+                        self.n_mismatch += 1;
+                    } else {
+                        self.n_match += 1;
+                    }
+
+                    let counts = if hp_base(rpos).is_some() {
+                        &mut self.homopolymer
+                    } else {
+                        &mut self.non_homopolymer
+                    };
+                    counts.n_bases += 1;
+                    counts.mismatch += is_mismatch as usize;
+
+                    if n_pos_bins > 0 {
+                        let bin = &mut self.pos_bins[pos_bin(qpos)];
+                        bin.n_bases += 1;
+                        bin.mismatch += is_mismatch as usize;
+                    }
+
+                    last_qpos = qpos;
+                    next_rpos = rpos + 1;
+                }
+                (Some(qpos), None) => {
+                    // soft clip
+                    if qpos < qstart || qpos >= qend {
+                        continue;
+                    }
+                    self.flush_del(del_run.take(), last_qpos, &hp_base, &pos_bin);
+                    ins_run = Some(ins_run.map_or((qpos, 1), |(start, len)| (start, len + 1)));
+                }
+                (None, Some(rpos)) if is_ref_skip(rpos) => {
+                    self.flush_ins(ins_run.take(), &query_seq, next_rpos, &hp_base, &pos_bin);
+                    self.flush_del(del_run.take(), last_qpos, &hp_base, &pos_bin);
+                    next_rpos = rpos + 1;
+                }
+                (None, Some(rpos)) => {
+                    self.flush_ins(ins_run.take(), &query_seq, next_rpos, &hp_base, &pos_bin);
+                    del_run = Some(del_run.map_or((rpos, 1), |(start, len)| (start, len + 1)));
+                    next_rpos = rpos + 1;
+                }
+                (None, None) => {}
+            }
+        }
+        self.flush_ins(ins_run, &query_seq, next_rpos, &hp_base, &pos_bin);
+        self.flush_del(del_run, last_qpos, &hp_base, &pos_bin);
+        Ok(())
+    }
+
+    fn flush_ins(
+        &mut self,
+        ins_run: Option<(usize, usize)>,
+        query_seq: &[u8],
+        next_rpos: usize,
+        hp_base: &impl Fn(usize) -> Option<u8>,
+        pos_bin: &impl Fn(usize) -> usize,
+    ) {
+        let (ins_start, ins_len) = match ins_run {
+            Some(ins_run) => ins_run,
+            None => return,
+        };
+        self.n_ins_events += 1;
+        self.n_ins_bases += ins_len;
+        *self.ins_len_hist.entry(ins_len).or_insert(0) += 1;
+
+        // the insertion is between next_rpos - 1 and next_rpos
+        let inserted = &query_seq[ins_start..ins_start + ins_len];
+        let is_hp = [next_rpos.checked_sub(1), Some(next_rpos)]
+            .into_iter()
+            .flatten()
+            .filter_map(hp_base)
+            .any(|base| inserted.iter().all(|b| b.eq_ignore_ascii_case(&base)));
+        if is_hp {
+            self.homopolymer.ins += ins_len;
+        } else {
+            self.non_homopolymer.ins += ins_len;
+        }
+
+        if !self.pos_bins.is_empty() {
+            (ins_start..ins_start + ins_len).for_each(|qpos| {
+                let bin = &mut self.pos_bins[pos_bin(qpos)];
+                bin.n_bases += 1;
+                bin.ins += 1;
+            });
+        }
+    }
+
+    fn flush_del(
+        &mut self,
+        del_run: Option<(usize, usize)>,
+        last_qpos: usize,
+        hp_base: &impl Fn(usize) -> Option<u8>,
+        pos_bin: &impl Fn(usize) -> usize,
+    ) {
+        let (del_start, del_len) = match del_run {
+            Some(del_run) => del_run,
+            None => return,
+        };
+        self.n_del_events += 1;
+        self.n_del_bases += del_len;
+        *self.del_len_hist.entry(del_len).or_insert(0) += 1;
+
+        (del_start..del_start + del_len).for_each(|rpos| {
+            let counts = if hp_base(rpos).is_some() {
+                &mut self.homopolymer
+            } else {
+                &mut self.non_homopolymer
+            };
+            counts.n_bases += 1;
+            counts.del += 1;
+        });
+
+        if !self.pos_bins.is_empty() {
+            self.pos_bins[pos_bin(last_qpos)].del += del_len;
+        }
+    }
+
+    /// pos_bins are merged only if the bin numbers are the same
+    pub fn merge(&mut self, other: &ErrorProfile) {
+        self.n_records += other.n_records;
+        self.n_match += other.n_match;
+        self.n_mismatch += other.n_mismatch;
+        self.n_ins_events += other.n_ins_events;
+        self.n_ins_bases += other.n_ins_bases;
+        self.n_del_events += other.n_del_events;
+        self.n_del_bases += other.n_del_bases;
+        for (row, other_row) in self.substitutions.iter_mut().zip(other.substitutions.iter()) {
+            row.iter_mut().zip(other_row.iter()).for_each(|(a, b)| *a += *b);
+        }
+        other.ins_len_hist.iter().for_each(|(len, cnt)| {
+            *self.ins_len_hist.entry(*len).or_insert(0) += *cnt;
+        });
+        other.del_len_hist.iter().for_each(|(len, cnt)| {
+            *self.del_len_hist.entry(*len).or_insert(0) += *cnt;
+        });
+        self.homopolymer.merge(&other.homopolymer);
+        self.non_homopolymer.merge(&other.non_homopolymer);
+        assert_eq!(self.pos_bins.len(), other.pos_bins.len(), "pos bins not match");
+        self.pos_bins
+            .iter_mut()
+            .zip(other.pos_bins.iter())
+            .for_each(|(a, b)| a.merge(b));
+    }
+
+    /// match / (match + mismatch + ins + del)
+    pub fn identity(&self) -> f64 {
+        let span = self.n_match + self.n_mismatch + self.n_ins_bases + self.n_del_bases;
+        self.n_match as f64 / span.max(1) as f64
+    }
+
+    /// (ref_base, query_base, count) of the mismatches, sorted by count desc
+    pub fn top_substitutions(&self) -> Vec<(char, char, usize)> {
+        let mut res = vec![];
+        for (r, row) in self.substitutions.iter().enumerate() {
+            for (q, cnt) in row.iter().enumerate() {
+                if r != q && *cnt > 0 {
+                    res.push((BASES[r] as char, BASES[q] as char, *cnt));
+                }
+            }
+        }
+        res.sort_by_key(|v| std::cmp::Reverse(v.2));
+        res
+    }
+}
+
+/// homopolymer base of each ref position in [ref_start, ref_end), None if the position is not in a homopolymer
+fn homopolymer_bases(ref_seq: &[u8], ref_start: usize, ref_end: usize) -> Vec<Option<u8>> {
+    let mut bases = vec![None; ref_end.saturating_sub(ref_start)];
+    // one more base at each side, so the homopolymers across the boundaries are found
+    let window_start = ref_start.saturating_sub(1);
+    let window_end = (ref_end + 1).min(ref_seq.len());
+    if window_start >= window_end {
+        return bases;
+    }
+    let window = ref_seq[window_start..window_end].to_ascii_uppercase();
+    for (start, end, base) in find_poly_n_regions(&window) {
+        let start = (start + window_start).max(ref_start);
+        let end = (end + window_start).min(ref_end);
+        (start..end).for_each(|rpos| bases[rpos - ref_start] = Some(base));
+    }
+    bases
+}
+
+/// error profile of the primary alignments in the bam.
+/// threads: see [`open_bam_reader`](super::bam_reader::open_bam_reader)
+pub fn error_profile_from_bam(
+    bam_file: &str,
+    ref_fasta: &str,
+    n_pos_bins: usize,
+    threads: Option<usize>,
+) -> anyhow::Result<ErrorProfile> {
+    let mut profile = ErrorProfile::new(n_pos_bins);
+    for_each_primary_record_with_ref(bam_file, ref_fasta, threads, |record, ref_seq| {
+        profile.update(record, ref_seq).with_context(|| {
+            format!("record {}", String::from_utf8_lossy(record.qname()))
+        })
+    })?;
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::gsbam::test_utils::{build_record, RecordBuilder};
+
+    use super::{base_idx, ErrorCounts, ErrorProfile};
+
+    #[test]
+    fn test_error_profile() {
+        // ref:     CGTTTA-CGGA
+        // query: GGCGTT-AACCGA
+        let ref_seq = b"ACGTTTACGGA";
+        let record = build_record("2S4=1D1=1I1=1X2=", "GGCGTTAACCGA", 1);
+        let profile = ErrorProfile::from_record(&record, ref_seq, 2).unwrap();
+
+        assert_eq!(profile.n_records, 1);
+        assert_eq!(profile.n_match, 8);
+        assert_eq!(profile.n_mismatch, 1);
+        assert_eq!(profile.substitutions[base_idx(b'G')][base_idx(b'C')], 1);
+        assert_eq!(profile.top_substitutions(), vec![('G', 'C', 1)]);
+        assert_eq!((profile.n_ins_events, profile.n_ins_bases), (1, 1));
+        assert_eq!((profile.n_del_events, profile.n_del_bases), (1, 1));
+        assert_eq!(profile.ins_len_hist, BTreeMap::from([(1, 1)]));
+        assert_eq!(profile.del_len_hist, BTreeMap::from([(1, 1)]));
+        assert!((profile.identity() - 8.0 / 11.0).abs() < 1e-6);
+
+        assert_eq!(
+            profile.homopolymer,
+            ErrorCounts {
+                n_bases: 5,
+                mismatch: 1,
+                ins: 0,
+                del: 1
+            }
+        );
+        assert_eq!(
+            profile.non_homopolymer,
+            ErrorCounts {
+                n_bases: 5,
+                mismatch: 0,
+                ins: 1,
+                del: 0
+            }
+        );
+
+        assert_eq!(
+            profile.pos_bins,
+            vec![
+                ErrorCounts {
+                    n_bases: 4,
+                    mismatch: 0,
+                    ins: 0,
+                    del: 1
+                },
+                ErrorCounts {
+                    n_bases: 6,
+                    mismatch: 1,
+                    ins: 1,
+                    del: 0
+                }
+            ]
+        );
+
+        // the read is sequenced from the other end
+        let record = RecordBuilder::new("2S4=1D1=1I1=1X2=", "GGCGTTAACCGA")
+            .pos(1)
+            .reverse(true)
+            .build();
+        let reverse_profile = ErrorProfile::from_record(&record, ref_seq, 2).unwrap();
+        assert_eq!(reverse_profile.pos_bins[0], profile.pos_bins[1]);
+        assert_eq!(reverse_profile.pos_bins[1], profile.pos_bins[0]);
+
+        let mut merged = ErrorProfile::new(2);
+        merged.merge(&profile);
+        merged.merge(&reverse_profile);
+        assert_eq!(merged.n_records, 2);
+        assert_eq!(merged.n_mismatch, 2);
+        assert_eq!(merged.homopolymer.del, 2);
+        assert_eq!(merged.pos_bins[0].n_errors(), 3);
+        assert!((merged.identity() - profile.identity()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_homopolymer_insertion() {
+        // ref:   ACGTT-TA
+        // query: ACGTTTTA
+        let ref_seq = b"ACGTTTA";
+        let record = build_record("5=1I2=", "ACGTTTTA", 0);
+        let profile = ErrorProfile::from_record(&record, ref_seq, 0).unwrap();
+        assert_eq!(profile.homopolymer.ins, 1);
+        assert_eq!(profile.homopolymer.n_bases, 3);
+        assert_eq!(profile.non_homopolymer.ins, 0);
+        assert!(profile.pos_bins.is_empty());
+
+        // ref:   ACG-TTTA
+        // query: ACGCTTTA
+        let record = build_record("3=1I4=", "ACGCTTTA", 0);
+        let profile = ErrorProfile::from_record(&record, ref_seq, 0).unwrap();
+        assert_eq!(profile.homopolymer.ins, 0);
+        assert_eq!(profile.non_homopolymer.ins, 1);
+        assert_eq!(profile.homopolymer.error_rate(), Some(0.0));
+    }
+
+    #[test]
+    fn test_ref_skip_and_short_ref() {
+        // ref:   ACG.....TTA
+        // query: ACG-----TCA, the N op is an intron
+        let ref_seq = b"ACGCCCCCTTA";
+        let record = build_record("3=5N1=1X1=", "ACGTCA", 0);
+        let profile = ErrorProfile::from_record(&record, ref_seq, 0).unwrap();
+        assert_eq!((profile.n_match, profile.n_mismatch), (5, 1));
+        assert_eq!((profile.n_del_events, profile.n_del_bases), (0, 0));
+        assert_eq!(profile.homopolymer.n_bases + profile.non_homopolymer.n_bases, 6);
+
+        // a deletion right before the intron is still a deletion
+        let record = build_record("3=1D4N1=1X1=", "ACGTCA", 0);
+        let profile = ErrorProfile::from_record(&record, ref_seq, 0).unwrap();
+        assert_eq!((profile.n_del_events, profile.n_del_bases), (1, 1));
+
+        // truncated reference
+        assert!(ErrorProfile::from_record(&record, &ref_seq[..10], 0).is_err());
+    }
+}
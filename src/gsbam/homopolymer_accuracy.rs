@@ -0,0 +1,294 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::poly_n::{
+    extract_poly_locus_info_from_record, find_poly_n_regions, RefPolyLocusInfo,
+};
+
+use super::{
+    bam_reader::for_each_primary_record_with_ref,
+    bam_record_ext::BamRecord,
+};
+
+/// called lengths of the reference homopolymers of one (base, true length) stratum
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HpLenCounts {
+    /// called length -> number of loci.
+    /// only the loci whose query bases are all the homopolymer base
+    pub called: BTreeMap<usize, usize>,
+    /// loci with other bases in the query, e.g. AAAA called as AACA. counted as errors
+    pub n_dirty: usize,
+    /// clean loci called with the reference length
+    pub n_correct: usize,
+}
+
+impl HpLenCounts {
+    pub fn n_loci(&self) -> usize {
+        self.called.values().sum::<usize>() + self.n_dirty
+    }
+
+    pub fn merge(&mut self, other: &HpLenCounts) {
+        other
+            .called
+            .iter()
+            .for_each(|(k, v)| *self.called.entry(*k).or_default() += v);
+        self.n_dirty += other.n_dirty;
+        self.n_correct += other.n_correct;
+    }
+
+    /// fraction of the loci called with the reference length, None if no locus
+    pub fn accuracy(&self) -> Option<f64> {
+        let n_loci = self.n_loci();
+        if n_loci == 0 {
+            None
+        } else {
+            Some(self.n_correct as f64 / n_loci as f64)
+        }
+    }
+}
+
+/// confusion matrix of called vs true homopolymer length, per base and true length.
+/// built from the RefPolyLocusInfo of each record, see `extract_poly_locus_info_from_record`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomopolymerAccuracy {
+    /// longer reference homopolymers are put into the max_hp_len stratum.
+    /// the called lengths are not capped
+    pub max_hp_len: usize,
+    pub n_records: usize,
+    /// (base, true length) -> called lengths
+    pub confusion: BTreeMap<(char, usize), HpLenCounts>,
+}
+
+impl HomopolymerAccuracy {
+    pub fn new(max_hp_len: usize) -> Self {
+        Self {
+            max_hp_len,
+            n_records: 0,
+            confusion: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_locus(&mut self, locus: &RefPolyLocusInfo) {
+        let true_len = locus.ref_repeats.min(self.max_hp_len);
+        let counts = self
+            .confusion
+            .entry((locus.ref_base.to_ascii_uppercase(), true_len))
+            .or_default();
+        if locus.query_clean {
+            *counts.called.entry(locus.query_repeats).or_default() += 1;
+            if locus.query_repeats == locus.ref_repeats {
+                counts.n_correct += 1;
+            }
+        } else {
+            counts.n_dirty += 1;
+        }
+    }
+
+    /// ref_poly_regions: find_poly_n_regions of the whole reference sequence the record aligned to.
+    /// unmapped record is ignored
+    pub fn update(&mut self, record: &BamRecord, ref_poly_regions: &[(usize, usize, u8)]) {
+        if record.is_unmapped() {
+            return;
+        }
+        self.n_records += 1;
+        if let Some(loci) = extract_poly_locus_info_from_record(record, ref_poly_regions) {
+            loci.iter().for_each(|locus| self.add_locus(locus));
+        }
+    }
+
+    /// the reports must use the same max_hp_len, otherwise the true length strata are mixed
+    pub fn merge(&mut self, other: &HomopolymerAccuracy) {
+        assert_eq!(self.max_hp_len, other.max_hp_len, "max_hp_len not match");
+        self.n_records += other.n_records;
+        other.confusion.iter().for_each(|(k, v)| {
+            self.confusion.entry(*k).or_default().merge(v);
+        });
+    }
+
+    /// the strata of all bases merged. true length -> called lengths
+    pub fn all_bases(&self) -> BTreeMap<usize, HpLenCounts> {
+        let mut res: BTreeMap<usize, HpLenCounts> = BTreeMap::new();
+        self.confusion.iter().for_each(|((_, true_len), v)| {
+            res.entry(*true_len).or_default().merge(v);
+        });
+        res
+    }
+
+    /// (true length, accuracy, number of loci) sorted by true length.
+    /// base: None for all bases
+    pub fn accuracy_curve(&self, base: Option<char>) -> Vec<(usize, f64, usize)> {
+        let strata = match base {
+            Some(base) => self
+                .confusion
+                .iter()
+                .filter(|((b, _), _)| *b == base.to_ascii_uppercase())
+                .map(|((_, true_len), v)| (*true_len, v.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            None => self.all_bases(),
+        };
+        strata
+            .iter()
+            .filter_map(|(true_len, v)| {
+                v.accuracy()
+                    .map(|accuracy| (*true_len, accuracy, v.n_loci()))
+            })
+            .collect()
+    }
+
+    /// base true_len called_len count. called_len is "dirty" for the unclean loci
+    pub fn confusion_tsv(&self) -> String {
+        let mut rows = vec!["base\ttrue_len\tcalled_len\tcount".to_string()];
+        for ((base, true_len), v) in &self.confusion {
+            v.called.iter().for_each(|(called_len, cnt)| {
+                rows.push(format!("{}\t{}\t{}\t{}", base, true_len, called_len, cnt));
+            });
+            if v.n_dirty > 0 {
+                rows.push(format!("{}\t{}\tdirty\t{}", base, true_len, v.n_dirty));
+            }
+        }
+        rows.join("\n") + "\n"
+    }
+
+    /// base true_len n_loci n_correct accuracy. base is "*" for all bases merged
+    pub fn accuracy_tsv(&self) -> String {
+        let mut rows = vec!["base\ttrue_len\tn_loci\tn_correct\taccuracy".to_string()];
+        let row = |base: char, true_len: usize, v: &HpLenCounts| {
+            format!(
+                "{}\t{}\t{}\t{}\t{:.6}",
+                base,
+                true_len,
+                v.n_loci(),
+                v.n_correct,
+                v.accuracy().unwrap_or(0.0)
+            )
+        };
+        for ((base, true_len), v) in &self.confusion {
+            rows.push(row(*base, *true_len, v));
+        }
+        for (true_len, v) in &self.all_bases() {
+            rows.push(row('*', *true_len, v));
+        }
+        rows.join("\n") + "\n"
+    }
+
+    pub fn to_json(&self) -> String {
+        let strata = self
+            .confusion
+            .iter()
+            .map(|((base, true_len), v)| {
+                let called = v
+                    .called
+                    .iter()
+                    .map(|(called_len, cnt)| format!("\"{}\":{}", called_len, cnt))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(
+                    concat!(
+                        "{{\"base\":\"{}\",\"true_len\":{},\"n_loci\":{},",
+                        "\"n_correct\":{},\"n_dirty\":{},\"accuracy\":{},\"called\":{{{}}}}}"
+                    ),
+                    base,
+                    true_len,
+                    v.n_loci(),
+                    v.n_correct,
+                    v.n_dirty,
+                    v.accuracy().unwrap_or(0.0),
+                    called
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let curve = self
+            .accuracy_curve(None)
+            .iter()
+            .map(|(true_len, accuracy, n_loci)| {
+                format!(
+                    "{{\"true_len\":{},\"n_loci\":{},\"accuracy\":{}}}",
+                    true_len, n_loci, accuracy
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"n_records\":{},\"max_hp_len\":{},\"strata\":[{}],\"accuracy_curve\":[{}]}}",
+            self.n_records, self.max_hp_len, strata, curve
+        )
+    }
+}
+
+/// homopolymer accuracy of the primary alignments in the bam.
+/// threads: see [`open_bam_reader`](super::bam_reader::open_bam_reader)
+pub fn homopolymer_accuracy_from_bam(
+    bam_file: &str,
+    ref_fasta: &str,
+    max_hp_len: usize,
+    threads: Option<usize>,
+) -> anyhow::Result<HomopolymerAccuracy> {
+    let mut accuracy = HomopolymerAccuracy::new(max_hp_len);
+    let mut tid2poly_regions = HashMap::new();
+    for_each_primary_record_with_ref(bam_file, ref_fasta, threads, |record, ref_seq| {
+        let poly_regions = tid2poly_regions
+            .entry(record.tid())
+            .or_insert_with(|| find_poly_n_regions(&ref_seq.to_ascii_uppercase()));
+        accuracy.update(record, poly_regions);
+        Ok(())
+    })?;
+    Ok(accuracy)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{gsbam::test_utils::build_record, poly_n::find_poly_n_regions};
+
+    use super::HomopolymerAccuracy;
+
+    #[test]
+    fn test_homopolymer_accuracy() {
+        // ref:   GTACCCGTTAAAAGC
+        let ref_seq = b"GTACCCGTTAAAAGC";
+        let regions = find_poly_n_regions(ref_seq);
+
+        let mut accuracy = HomopolymerAccuracy::new(3);
+        // exact copy of ACCCGTTAAAAG
+        accuracy.update(&build_record("12=", "ACCCGTTAAAAG", 2), &regions);
+        // ACC-GTTTAAAAG: CCC called as 2, TT called as 3
+        accuracy.update(&build_record("3=1D2=1I6=", "ACCGTTTAAAAG", 2), &regions);
+        // starts inside CCC, so only TT and AAAA. AAAA called as AACA
+        accuracy.update(&build_record("6=1X2=", "CGTTAACAG", 5), &regions);
+
+        assert_eq!(accuracy.n_records, 3);
+        let c3 = &accuracy.confusion[&('C', 3)];
+        assert_eq!(c3.called.iter().collect::<Vec<_>>(), vec![(&2, &1), (&3, &1)]);
+        let t2 = &accuracy.confusion[&('T', 2)];
+        assert_eq!(t2.n_loci(), 3);
+        assert_eq!(t2.accuracy(), Some(2.0 / 3.0));
+        // AAAA is in the stratum of 3, correct when called as 4
+        let a3 = &accuracy.confusion[&('A', 3)];
+        assert_eq!(a3.called.iter().collect::<Vec<_>>(), vec![(&4, &2)]);
+        assert_eq!(a3.n_dirty, 1);
+        assert_eq!(a3.n_correct, 2);
+
+        let curve = accuracy.accuracy_curve(None);
+        assert_eq!(curve.len(), 2);
+        assert_eq!((curve[0].0, curve[0].2), (2, 3));
+        assert_eq!((curve[1].0, curve[1].2), (3, 5));
+        assert!((curve[1].1 - 0.6).abs() < 1e-9);
+
+        assert!(accuracy.confusion_tsv().contains("A\t3\tdirty\t1\n"));
+        assert!(accuracy.accuracy_tsv().contains("*\t3\t5\t3\t0.600000\n"));
+        assert!(accuracy
+            .to_json()
+            .starts_with("{\"n_records\":3,\"max_hp_len\":3,\"strata\":[{\"base\":\"A\""));
+
+        let mut merged = HomopolymerAccuracy::new(3);
+        merged.merge(&accuracy);
+        merged.merge(&accuracy);
+        assert_eq!(merged.n_records, 6);
+        assert_eq!(merged.confusion[&('T', 2)].n_loci(), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_hp_len not match")]
+    fn test_merge_different_max_hp_len() {
+        HomopolymerAccuracy::new(3).merge(&HomopolymerAccuracy::new(5));
+    }
+}
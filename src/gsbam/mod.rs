@@ -7,10 +7,18 @@ use crate::ds::ReadInfo;
 
 pub mod bam_reader;
 pub mod bam_record_ext;
+pub mod channel_stats;
 pub mod cigar_ext;
+pub mod error_profile;
+pub mod homopolymer_accuracy;
 pub mod bam_header_ext;
 pub mod plp_counts_from_records;
+pub mod plp_variant_caller;
 pub mod query_locus_blacklist_gen;
+pub mod record_filter;
+pub mod record_group;
+#[cfg(test)]
+pub(crate) mod test_utils;
 pub mod utils;
 
 #[deprecated(since="0.10.0", note="use gsbam::bam_header_ext::BamHeaderExt instead")]
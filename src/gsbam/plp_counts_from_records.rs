@@ -1,11 +1,16 @@
 use core::fmt;
 use std::{cmp, collections::HashMap};
 
-use rust_htslib::bam::{ext::BamRecordExtensions, IndexedReader, Read};
+use rust_htslib::bam::{IndexedReader, Read};
+
+use crate::{
+    ds::region::{Region, Regions},
+    file_reader::bed_reader::BedInfo,
+};
 
 use super::{
     bam_record_ext::{BamRecord, BamRecordExt},
-    query_locus_blacklist_gen::{get_query_locus_blacklist, TQueryLocusBlacklist},
+    query_locus_blacklist_gen::{get_query_locus_blacklist_intervals, TQueryLocusBlacklist},
 };
 
 use lazy_static::lazy_static;
@@ -44,6 +49,64 @@ pub fn get_base_idx(base: u8, fwd: bool) -> usize {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefMaskMode {
+    /// the masked ref positions (and the insertions after them) are not in the plp
+    Skip,
+    /// the masked ref positions are counted as usual, and flagged in PlpCnts::get_masked
+    Flag,
+}
+
+/// masked regions (repeats, low complexity ...) of one contig
+#[derive(Debug, Clone)]
+pub struct RefMask {
+    /// sorted and merged [start, end)
+    regions: Vec<(usize, usize)>,
+    mode: RefMaskMode,
+}
+
+impl RefMask {
+    pub fn new(regions: Vec<(usize, usize)>, mode: RefMaskMode) -> Self {
+        let regions = Regions::new(
+            regions
+                .into_iter()
+                .filter(|&(start, end)| start < end)
+                .map(|(start, end)| Region::new(start, end))
+                .collect(),
+        )
+        .merge_regions()
+        .iter()
+        .map(|region| (region.start(), region.end()))
+        .collect();
+        Self { regions, mode }
+    }
+
+    /// the regions of the contig in the bed. no region is masked if the contig is not in the bed
+    pub fn from_bed_info(bed_info: &BedInfo, contig: &str, mode: RefMaskMode) -> Self {
+        Self::new(
+            bed_info.get_regions(contig).cloned().unwrap_or_default(),
+            mode,
+        )
+    }
+
+    pub fn get_mode(&self) -> RefMaskMode {
+        self.mode
+    }
+
+    pub fn get_regions(&self) -> &Vec<(usize, usize)> {
+        &self.regions
+    }
+
+    pub fn contains(&self, ref_pos: usize) -> bool {
+        let idx = self.regions.partition_point(|&(_, end)| end <= ref_pos);
+        idx < self.regions.len() && self.regions[idx].0 <= ref_pos
+    }
+
+    fn skip(&self, ref_pos: usize) -> bool {
+        self.mode == RefMaskMode::Skip && self.contains(ref_pos)
+    }
+}
+
 #[derive(Clone)]
 pub struct PlpCnts {
     ref_start: usize,
@@ -53,6 +116,9 @@ pub struct PlpCnts {
     cnts: Vec<u32>, // 10 * lengths. atcgATCG gap GAP
     major_start_idx: HashMap<usize, usize>,
     timesteps: usize,
+    /// per timestep, whether the ref position is masked. all false if no ref mask is set
+    masked: Vec<bool>,
+    ref_mask: Option<RefMask>,
 }
 
 impl fmt::Debug for PlpCnts {
@@ -63,6 +129,7 @@ impl fmt::Debug for PlpCnts {
             .field("major", &self.major)
             .field("minor", &self.minor)
             .field("cnts", &self.cnts)
+            .field("masked", &self.masked)
             .finish()
     }
 }
@@ -101,15 +168,38 @@ impl PlpCnts {
             cnts: vec![0; 10 * tot_lengths],
             major_start_idx,
             timesteps,
+            masked: vec![false; tot_lengths],
+            ref_mask: None,
         }
     }
 
+    /// set before update_with_record(s). in Skip mode, the masked ref positions should not be
+    /// in the ref_pos_length of PlpCnts::new
+    pub fn set_ref_mask(&mut self, ref_mask: Option<RefMask>) {
+        self.masked = match &ref_mask {
+            Some(ref_mask) => self.major.iter().map(|&pos| ref_mask.contains(pos)).collect(),
+            None => vec![false; self.timesteps],
+        };
+        self.ref_mask = ref_mask;
+    }
+
     /// build plp_cnts from records and ref_start and end
     pub fn from_records(
         records: &Vec<BamRecord>,
         rstart: Option<usize>,
         rend: Option<usize>,
         query_locus_blacklist_gen: Option<&Vec<Box<dyn TQueryLocusBlacklist>>>,
+    ) -> Self {
+        Self::from_records_with_mask(records, rstart, rend, query_locus_blacklist_gen, None)
+    }
+
+    /// from_records with a ref mask, see RefMaskMode
+    pub fn from_records_with_mask(
+        records: &Vec<BamRecord>,
+        rstart: Option<usize>,
+        rend: Option<usize>,
+        query_locus_blacklist_gen: Option<&Vec<Box<dyn TQueryLocusBlacklist>>>,
+        ref_mask: Option<&RefMask>,
     ) -> Self {
         let max_ins_of_ref_position =
             compute_max_ins_of_each_ref_position(records, rstart, rend, query_locus_blacklist_gen);
@@ -123,7 +213,11 @@ impl PlpCnts {
             .map(|(&pos, &len)| (pos as usize, len as usize))
             .collect::<Vec<(_, _)>>();
         len_of_ref_positions_list.sort_by_key(|v| v.0);
+        if let Some(ref_mask) = ref_mask {
+            len_of_ref_positions_list.retain(|&(pos, _)| !ref_mask.skip(pos));
+        }
         let mut plp_cnts = Self::new(len_of_ref_positions_list);
+        plp_cnts.set_ref_mask(ref_mask.cloned());
         plp_cnts.update_with_records(records, query_locus_blacklist_gen);
         plp_cnts
     }
@@ -143,21 +237,28 @@ impl PlpCnts {
         record: &BamRecord,
         query_locus_blacklist_gen: Option<&Vec<Box<dyn TQueryLocusBlacklist>>>,
     ) {
-        let query_locus_blacklist = get_query_locus_blacklist(record, query_locus_blacklist_gen);
+        let query_locus_blacklist =
+            get_query_locus_blacklist_intervals(record, query_locus_blacklist_gen);
+        let mut query_locus_blacklist = query_locus_blacklist.cursor();
 
         let record_ext = BamRecordExt::new(record);
         let start = cmp::max(self.ref_start as i64, record_ext.reference_start() as i64);
         let end = cmp::min(self.ref_end as i64, record_ext.reference_end() as i64);
         let fwd = !record.is_reverse();
+        let cigar_index = record_ext.cigar_index();
         let mut rpos_cursor = None;
-        let mut qpos_cursor = None;
+        let mut qpos_cursor = cigar_index
+            .query_pos_before_ref(start as usize)
+            .map(|v| v as i64);
         let mut cur_ins = 0;
         let mut anchor = 0;
         let query_seq = record.seq().as_bytes();
         let query_end = record_ext.query_alignment_end();
+        // the current ref position and the insertions after it are skipped
+        let mut skip_cur_ref_pos = false;
 
         // println!("qname:{}, start:{}, end:{}", record_ext.get_qname(), start, end);
-        for [qpos, rpos] in record.aligned_pairs_full() {
+        for [qpos, rpos] in cigar_index.aligned_pairs_from_ref(start as usize) {
             if rpos.is_some() {
                 rpos_cursor = rpos;
             }
@@ -182,6 +283,14 @@ impl PlpCnts {
             }
             // print!("{},", rpos_cursor.unwrap());
             if let Some(rpos_) = rpos {
+                skip_cur_ref_pos = self
+                    .ref_mask
+                    .as_ref()
+                    .map(|ref_mask| ref_mask.skip(rpos_ as usize))
+                    .unwrap_or(false);
+                if skip_cur_ref_pos {
+                    continue;
+                }
                 if !self.major_start_idx.contains_key(&(rpos_ as usize)) {
                     let mut all_pos = self.major_start_idx.keys().map(|&v| v).collect::<Vec<_>>();
                     all_pos.sort();
@@ -192,11 +301,14 @@ impl PlpCnts {
 
                 cur_ins = 0;
             } else {
+                if skip_cur_ref_pos {
+                    continue;
+                }
                 cur_ins += 1;
             }
 
             if let Some(qpos_) = qpos {
-                if query_locus_blacklist.contains(&(qpos_ as usize)) {
+                if query_locus_blacklist.contains(qpos_ as usize) {
                     if cur_ins > 0 {
                         cur_ins -= 1
                     };
@@ -206,7 +318,7 @@ impl PlpCnts {
                 self.update_cnts(anchor + cur_ins, query_seq[qpos_ as usize], fwd);
             } else {
                 let qpos_cursor_ = qpos_cursor.unwrap() as usize;
-                if query_locus_blacklist.contains(&(qpos_cursor_ as usize)) || query_locus_blacklist.contains(&(qpos_cursor_ + 1)) {
+                if query_locus_blacklist.contains(qpos_cursor_) || query_locus_blacklist.contains(qpos_cursor_ + 1) {
                     continue;
                 } 
 
@@ -224,6 +336,11 @@ impl PlpCnts {
         &self.minor
     }
 
+    /// [timestep], true if the ref position of the timestep is masked
+    pub fn get_masked(&self) -> &Vec<bool> {
+        &self.masked
+    }
+
     /// [feat_size, timestemp]
     pub fn get_cnts(&self) -> &Vec<u32> {
         &self.cnts
@@ -263,6 +380,18 @@ pub fn plp_within_region(
     start: Option<usize>,
     end: Option<usize>,
     query_locus_blacklist_gen: Option<&Vec<Box<dyn TQueryLocusBlacklist>>>,
+) -> PlpCnts {
+    plp_within_region_with_mask(reader, contig, start, end, query_locus_blacklist_gen, None)
+}
+
+/// plp_within_region with a ref mask, see RefMaskMode
+pub fn plp_within_region_with_mask(
+    reader: &mut IndexedReader,
+    contig: &str,
+    start: Option<usize>,
+    end: Option<usize>,
+    query_locus_blacklist_gen: Option<&Vec<Box<dyn TQueryLocusBlacklist>>>,
+    ref_mask: Option<&RefMask>,
 ) -> PlpCnts {
     if start.is_none() && end.is_none() {
         reader.fetch(contig).unwrap();
@@ -289,7 +418,7 @@ pub fn plp_within_region(
         }
     }
 
-    plp_with_records_region(&records, start, end, query_locus_blacklist_gen)
+    PlpCnts::from_records_with_mask(&records, start, end, query_locus_blacklist_gen, ref_mask)
 }
 
 pub fn plp_with_records_region(
@@ -316,7 +445,9 @@ pub fn compute_max_ins_of_each_ref_position(
     let rstart = rstart.map(|v| v as i64);
     let rend = rend.map(|v| v as i64);
     for record in records {
-        let query_locus_blacklist = get_query_locus_blacklist(record, query_locus_blacklist_gen);
+        let query_locus_blacklist =
+            get_query_locus_blacklist_intervals(record, query_locus_blacklist_gen);
+        let mut query_locus_blacklist = query_locus_blacklist.cursor();
 
         let record_ext = BamRecordExt::new(record);
         let mut start = rstart.unwrap_or(record_ext.reference_start() as i64);
@@ -335,19 +466,30 @@ pub fn compute_max_ins_of_each_ref_position(
         //     start,
         //     end
         // );
-        let mut aligned_pair_full = record.aligned_pairs_full().collect::<Vec<_>>();
-        if aligned_pair_full.len() == 0 {
-            continue;
-        }
+        let cigar_index = record_ext.cigar_index();
+        let last_pair = match cigar_index.blocks().last() {
+            Some(last_block) => {
+                let last_qpos = last_block.qend() as i64 - 1;
+                let last_rpos = last_block.rend() as i64 - 1;
+                match (last_block.qlen() > 0, last_block.rlen() > 0) {
+                    (true, true) => [Some(last_qpos), Some(last_rpos)],
+                    (true, false) => [Some(last_qpos), None],
+                    _ => [None, Some(last_rpos)],
+                }
+            }
+            None => continue,
+        };
 
         // this make the following for loop correct.
         // if the query match to the last base of the ref seqence. the following for loop won't give the right result
         // but add this , it will get the right result.
-        if let Some(last_ref_pos) = aligned_pair_full.last().unwrap()[0] {
-            aligned_pair_full.push([Some(last_ref_pos + 1), None]);
-        }
+        let tail_pair = last_pair[0].map(|last_qpos| [Some(last_qpos + 1), None]);
 
-        for [qpos, rpos] in aligned_pair_full.into_iter() {
+        let aligned_pairs = cigar_index
+            .aligned_pairs_from_ref(cmp::max(start, 0) as usize)
+            .chain(tail_pair);
+
+        for [qpos, rpos] in aligned_pairs {
             if rpos.is_some() {
                 rpos_cursor = rpos;
             }
@@ -395,7 +537,7 @@ pub fn compute_max_ins_of_each_ref_position(
                 cur_ins = 0;
             } else {
                 let qpos_ = qpos.unwrap() as usize;
-                cur_ins += if query_locus_blacklist.contains(&qpos_) {
+                cur_ins += if query_locus_blacklist.contains(qpos_) {
                     0
                 } else {
                     1
@@ -413,16 +555,20 @@ mod test {
 
     use rust_htslib::bam::{ext::BamRecordExtensions, Header, IndexedReader, Read};
 
+    use std::collections::HashMap;
+
+    use crate::file_reader::bed_reader::BedInfo;
     use crate::gsbam::{
         bam_record_ext::{BamRecord, BamRecordExt},
         cigar_ext::parse_cigar_string,
-        plp_counts_from_records::PlpCnts,
+        plp_counts_from_records::{PlpCnts, RefMask, RefMaskMode},
         query_locus_blacklist_gen::{
             get_query_locus_blacklist, LongInsBlacklist, LowIdentityBlacklist, TQueryLocusBlacklist,
         },
+        test_utils::RecordBuilder,
     };
 
-    use super::compute_max_ins_of_each_ref_position;
+    use super::{compute_max_ins_of_each_ref_position, get_base_idx};
 
     #[test]
     fn test_test_plp_using_aligned_pairs_with_right_soft_clip() {
@@ -693,4 +839,57 @@ mod test {
         //     ]
         // );
     }
+
+    #[test]
+    fn test_plp_cnts_with_ref_mask() {
+        // ACG-TA
+        // ACG-TA
+        // ACGGTA
+        let mut records = vec![];
+        for (qname, seq, cigar) in [
+            ("qname0", "ACGTA", "5="),
+            ("qname1", "ACGTA", "5="),
+            ("qname2", "ACGGTA", "3=1I2="),
+        ] {
+            records.push(RecordBuilder::new(cigar, seq).qname(qname).pos(0).build());
+        }
+
+        let no_mask = PlpCnts::from_records(&records, None, None, None);
+        assert_eq!(no_mask.get_major(), &vec![0, 1, 2, 2, 3, 4]);
+        assert_eq!(no_mask.get_masked(), &vec![false; 6]);
+
+        let bed_info = BedInfo::from_info(HashMap::from([("chr1".to_string(), vec![(2, 3)])]));
+
+        let ref_mask = RefMask::from_bed_info(&bed_info, "chr1", RefMaskMode::Flag);
+        let flagged = PlpCnts::from_records_with_mask(&records, None, None, None, Some(&ref_mask));
+        assert_eq!(flagged.get_major(), no_mask.get_major());
+        assert_eq!(flagged.get_cnts(), no_mask.get_cnts());
+        assert_eq!(
+            flagged.get_masked(),
+            &vec![false, false, true, true, false, false]
+        );
+
+        let ref_mask = RefMask::from_bed_info(&bed_info, "chr1", RefMaskMode::Skip);
+        let skipped = PlpCnts::from_records_with_mask(&records, None, None, None, Some(&ref_mask));
+        assert_eq!(skipped.get_major(), &vec![0, 1, 3, 4]);
+        assert_eq!(skipped.get_minor(), &vec![0, 0, 0, 0]);
+        let timesteps = skipped.get_major().len();
+        // column of ref pos 3: 3 T, no gap
+        assert_eq!(skipped.get_cnts()[get_base_idx(b'T', true) * timesteps + 2], 3);
+        assert_eq!(skipped.get_cnts()[get_base_idx(b'-', true) * timesteps + 2], 0);
+        assert_eq!(skipped.get_cnts().iter().sum::<u32>(), 12);
+
+        // contig not in the bed
+        let ref_mask = RefMask::from_bed_info(&bed_info, "chr2", RefMaskMode::Skip);
+        let plp_cnts = PlpCnts::from_records_with_mask(&records, None, None, None, Some(&ref_mask));
+        assert_eq!(plp_cnts.get_cnts(), no_mask.get_cnts());
+
+        let ref_mask = RefMask::new(vec![(5, 8), (1, 3), (2, 4), (9, 9)], RefMaskMode::Flag);
+        assert_eq!(ref_mask.get_regions(), &vec![(1, 4), (5, 8)]);
+        assert!(!ref_mask.contains(0));
+        assert!(ref_mask.contains(3));
+        assert!(!ref_mask.contains(4));
+        assert!(ref_mask.contains(7));
+        assert!(!ref_mask.contains(9));
+    }
 }
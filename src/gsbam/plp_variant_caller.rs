@@ -0,0 +1,582 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    file_reader::vcf_reader::{
+        classify_variant, VariantType, VcfFieldNumber, VcfFieldType, VcfGenotype, VcfHeader,
+        VcfRecord, VcfRowData, VcfSample, VcfValue,
+    },
+    phreq::phreq2err,
+};
+
+use super::{bam_header_ext::HeaderSQ, plp_counts_from_records::PlpCnts};
+
+/// A C G T gap
+const BASES: [u8; 5] = [b'A', b'C', b'G', b'T', b'-'];
+const GAP_IDX: usize = 4;
+/// cnts rows of PlpCnts, ordered as BASES
+const FWD_ROWS: [usize; 5] = [4, 5, 6, 7, 9];
+const REV_ROWS: [usize; 5] = [0, 1, 2, 3, 8];
+const MAX_GQ: u8 = 99;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ploidy {
+    Haploid,
+    Diploid,
+}
+
+#[derive(Debug, Clone)]
+pub struct VariantCallerParams {
+    pub ploidy: Ploidy,
+    /// reads covering the ref position
+    pub min_depth: u32,
+    pub min_alt_cnt: u32,
+    pub min_alt_frac: f32,
+    /// min(fwd, rev) / alt_cnt below this is flagged as strand_bias
+    pub min_strand_frac: f32,
+    /// genotype quality below this is flagged as low_gq
+    pub min_gq: u8,
+    /// per read phreq used as the sequencing error rate of the genotype likelihood
+    pub error_phreq: f64,
+}
+
+impl Default for VariantCallerParams {
+    fn default() -> Self {
+        Self {
+            ploidy: Ploidy::Haploid,
+            min_depth: 10,
+            min_alt_cnt: 3,
+            min_alt_frac: 0.2,
+            min_strand_frac: 0.1,
+            min_gq: 20,
+            error_phreq: 20.0,
+        }
+    }
+}
+
+/// one called variant, pos / ref_bases / alt_bases are vcf style (0-based pos, anchored indels)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlpVariant {
+    pub pos: usize,
+    pub ref_bases: String,
+    pub alt_bases: String,
+    pub variant_type: VariantType,
+    pub depth: u32,
+    pub alt_cnt: u32,
+    pub fwd_alt_cnt: u32,
+    pub rev_alt_cnt: u32,
+    /// allele indices, [1] / [0, 1] / [1, 1]
+    pub genotype: Vec<usize>,
+    pub gq: u8,
+    pub qual: f32,
+    /// phred scaled genotype likelihoods, normalized to min 0
+    pub pl: Vec<u32>,
+    /// empty means PASS
+    pub filters: Vec<&'static str>,
+}
+
+impl PlpVariant {
+    pub fn alt_frac(&self) -> f32 {
+        if self.depth == 0 {
+            0.0
+        } else {
+            self.alt_cnt as f32 / self.depth as f32
+        }
+    }
+
+    pub fn is_pass(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn to_vcf_row_data(&self, contig: &str) -> VcfRowData {
+        VcfRowData::new(
+            contig.to_string(),
+            self.pos,
+            self.ref_bases.clone(),
+            self.alt_bases.clone(),
+            0,
+        )
+        .with_qual(Some(self.qual))
+    }
+
+    /// single sample record, fields declared by caller_vcf_header
+    pub fn to_vcf_record(&self, contig: &str) -> VcfRecord {
+        let mut record = VcfRecord::new(
+            contig,
+            self.pos,
+            &self.ref_bases,
+            vec![self.alt_bases.clone()],
+        );
+        record.qual = Some(self.qual);
+        record.filters = if self.is_pass() {
+            vec!["PASS".to_string()]
+        } else {
+            self.filters.iter().map(|v| v.to_string()).collect()
+        };
+        record.info = BTreeMap::from([
+            ("DP".to_string(), VcfValue::Integer(vec![Some(self.depth as i32)])),
+            ("AF".to_string(), VcfValue::Float(vec![Some(self.alt_frac())])),
+            (
+                "SB".to_string(),
+                VcfValue::Integer(vec![
+                    Some(self.fwd_alt_cnt as i32),
+                    Some(self.rev_alt_cnt as i32),
+                ]),
+            ),
+        ]);
+        record.samples = vec![VcfSample {
+            genotype: Some(VcfGenotype {
+                alleles: self.genotype.iter().map(|v| Some(*v)).collect(),
+                phased: false,
+            }),
+            fields: BTreeMap::from([
+                ("GQ".to_string(), VcfValue::Integer(vec![Some(self.gq as i32)])),
+                (
+                    "AD".to_string(),
+                    VcfValue::Integer(vec![
+                        Some((self.depth - self.alt_cnt) as i32),
+                        Some(self.alt_cnt as i32),
+                    ]),
+                ),
+                ("DP".to_string(), VcfValue::Integer(vec![Some(self.depth as i32)])),
+                (
+                    "PL".to_string(),
+                    VcfValue::Integer(self.pl.iter().map(|v| Some(*v as i32)).collect()),
+                ),
+            ]),
+        }];
+        record
+    }
+}
+
+/// header declaring the fields written by PlpVariant::to_vcf_record
+pub fn caller_vcf_header(seqs: &[HeaderSQ], sample: &str) -> VcfHeader {
+    let mut header = VcfHeader::new(seqs, vec![sample.to_string()]);
+    header
+        .add_filter("strand_bias")
+        .add_filter("low_gq")
+        .add_info("DP", VcfFieldNumber::Fixed(1), VcfFieldType::Integer, "Read depth")
+        .add_info("AF", VcfFieldNumber::AltAlleles, VcfFieldType::Float, "Alt allele fraction")
+        .add_info("SB", VcfFieldNumber::Fixed(2), VcfFieldType::Integer, "Fwd and rev alt count")
+        .add_format("GQ", VcfFieldNumber::Fixed(1), VcfFieldType::Integer, "Genotype quality")
+        .add_format("AD", VcfFieldNumber::Alleles, VcfFieldType::Integer, "Allelic depths")
+        .add_format("DP", VcfFieldNumber::Fixed(1), VcfFieldType::Integer, "Read depth")
+        .add_format("PL", VcfFieldNumber::Genotypes, VcfFieldType::Integer, "Phred likelihoods");
+    header
+}
+
+/// fwd / rev counts of one plp column, ordered as BASES
+fn column_cnts(plp: &PlpCnts, tt: usize) -> ([u32; 5], [u32; 5]) {
+    let timesteps = plp.get_major().len();
+    let cnts = plp.get_cnts();
+    let fwd = FWD_ROWS.map(|row| cnts[row * timesteps + tt]);
+    let rev = REV_ROWS.map(|row| cnts[row * timesteps + tt]);
+    (fwd, rev)
+}
+
+struct Genotyping {
+    genotype: Vec<usize>,
+    gq: u8,
+    qual: f32,
+    pl: Vec<u32>,
+}
+
+/// binomial genotype likelihoods of alt_cnt alt reads in n reads, flat prior
+fn genotyping(n: u32, alt_cnt: u32, ploidy: Ploidy, err: f64) -> Genotyping {
+    let (genotypes, alt_probs) = match ploidy {
+        Ploidy::Haploid => (vec![vec![0], vec![1]], vec![err, 1.0 - err]),
+        Ploidy::Diploid => (
+            vec![vec![0, 0], vec![0, 1], vec![1, 1]],
+            vec![err, 0.5, 1.0 - err],
+        ),
+    };
+    let (k, n) = (alt_cnt as f64, n as f64);
+    let log_likes = alt_probs
+        .iter()
+        .map(|p| k * p.log10() + (n - k) * (1.0 - p).log10())
+        .collect::<Vec<_>>();
+    let max_ll = log_likes.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let likes = log_likes.iter().map(|v| 10_f64.powf(v - max_ll)).collect::<Vec<_>>();
+    let tot = likes.iter().sum::<f64>();
+
+    let best = (0..likes.len())
+        .max_by(|&a, &b| likes[a].total_cmp(&likes[b]))
+        .unwrap();
+    let pl = log_likes
+        .iter()
+        .map(|v| (-10.0 * (v - max_ll)).round() as u32)
+        .collect::<Vec<_>>();
+
+    // phred of the posterior error, computed from the likelihood sums to keep precision
+    let not_best = (tot - likes[best]).max(f64::MIN_POSITIVE);
+    let gq = (-10.0 * (not_best / tot).log10()).round().min(MAX_GQ as f64) as u8;
+    let qual = (-10.0 * (log_likes[0] - max_ll - tot.log10())) as f32;
+
+    Genotyping {
+        genotype: genotypes[best].clone(),
+        gq,
+        qual,
+        pl,
+    }
+}
+
+/// call SNVs, insertions and deletions from the columns of a pileup.
+/// ref_seq is the whole contig, indexed by the ref positions of plp.
+/// only the most supported alt of each position is reported and masked columns are ignored
+pub fn call_variants(
+    plp: &PlpCnts,
+    ref_seq: &[u8],
+    params: &VariantCallerParams,
+) -> Vec<PlpVariant> {
+    let major = plp.get_major();
+    let minor = plp.get_minor();
+    let masked = plp.get_masked();
+    let err = phreq2err(params.error_phreq);
+
+    let mut candidates = vec![];
+    // (ref_pos, depth, gap_fwd, gap_rev) of consecutive deletion columns
+    let mut del_run: Vec<(usize, u32, u32, u32)> = vec![];
+    let mut ref_depth = 0;
+
+    for tt in 0..major.len() {
+        let ref_pos = major[tt];
+        if minor[tt] == 0 {
+            let is_del_candidate = !masked[tt] && ref_pos < ref_seq.len() && {
+                let (fwd, rev) = column_cnts(plp, tt);
+                ref_depth = fwd.iter().chain(rev.iter()).sum::<u32>();
+                let gap_fwd = fwd[GAP_IDX];
+                let gap_rev = rev[GAP_IDX];
+
+                let ref_idx = BASES.iter().position(|b| b.eq_ignore_ascii_case(&ref_seq[ref_pos]));
+                let alt = (0..4)
+                    .filter(|&i| Some(i) != ref_idx)
+                    .max_by_key(|&i| fwd[i] + rev[i]);
+                if let Some(alt) = alt {
+                    candidates.push(Candidate {
+                        pos: ref_pos,
+                        ref_bases: vec![ref_seq[ref_pos].to_ascii_uppercase()],
+                        alt_bases: vec![BASES[alt]],
+                        depth: ref_depth,
+                        fwd_alt: fwd[alt],
+                        rev_alt: rev[alt],
+                    });
+                }
+                if passes_frac(ref_depth, gap_fwd + gap_rev, params) {
+                    del_run.push((ref_pos, ref_depth, gap_fwd, gap_rev));
+                    true
+                } else {
+                    false
+                }
+            };
+            if !is_del_candidate {
+                flush_del_run(&mut del_run, ref_seq, &mut candidates);
+            } else if del_run.len() > 1 && del_run[del_run.len() - 2].0 + 1 != ref_pos {
+                let last = del_run.pop().unwrap();
+                flush_del_run(&mut del_run, ref_seq, &mut candidates);
+                del_run.push(last);
+            }
+        } else if minor[tt] == 1 && !masked[tt] && ref_pos < ref_seq.len() {
+            let (fwd, rev) = column_cnts(plp, tt);
+            let fwd_alt = fwd[..4].iter().sum::<u32>();
+            let rev_alt = rev[..4].iter().sum::<u32>();
+            let inserted = insertion_consensus(plp, tt, fwd_alt + rev_alt);
+            if !inserted.is_empty() {
+                let mut alt_bases = vec![ref_seq[ref_pos].to_ascii_uppercase()];
+                alt_bases.extend(inserted);
+                candidates.push(Candidate {
+                    pos: ref_pos,
+                    ref_bases: vec![ref_seq[ref_pos].to_ascii_uppercase()],
+                    alt_bases,
+                    depth: ref_depth.max(fwd_alt + rev_alt),
+                    fwd_alt,
+                    rev_alt,
+                });
+            }
+        }
+    }
+    flush_del_run(&mut del_run, ref_seq, &mut candidates);
+
+    let mut variants = candidates
+        .into_iter()
+        .filter(|c| c.depth >= params.min_depth)
+        .filter(|c| c.fwd_alt + c.rev_alt >= params.min_alt_cnt)
+        .filter(|c| passes_frac(c.depth, c.fwd_alt + c.rev_alt, params))
+        .map(|c| {
+            let alt_cnt = c.fwd_alt + c.rev_alt;
+            let gt = genotyping(c.depth, alt_cnt, params.ploidy, err);
+            let mut filters = vec![];
+            if (c.fwd_alt.min(c.rev_alt) as f32) < params.min_strand_frac * alt_cnt as f32 {
+                filters.push("strand_bias");
+            }
+            if gt.gq < params.min_gq {
+                filters.push("low_gq");
+            }
+            let ref_bases = String::from_utf8(c.ref_bases).unwrap();
+            let alt_bases = String::from_utf8(c.alt_bases).unwrap();
+            PlpVariant {
+                pos: c.pos,
+                variant_type: classify_variant(&ref_bases, &alt_bases),
+                ref_bases,
+                alt_bases,
+                depth: c.depth,
+                alt_cnt,
+                fwd_alt_cnt: c.fwd_alt,
+                rev_alt_cnt: c.rev_alt,
+                genotype: gt.genotype,
+                gq: gt.gq,
+                qual: gt.qual,
+                pl: gt.pl,
+                filters,
+            }
+        })
+        .filter(|v| v.genotype.iter().any(|&a| a > 0))
+        .collect::<Vec<_>>();
+    variants.sort_by_key(|v| (v.pos, v.ref_bases.len(), v.alt_bases.len()));
+    variants
+}
+
+struct Candidate {
+    pos: usize,
+    ref_bases: Vec<u8>,
+    alt_bases: Vec<u8>,
+    depth: u32,
+    fwd_alt: u32,
+    rev_alt: u32,
+}
+
+fn passes_frac(depth: u32, alt_cnt: u32, params: &VariantCallerParams) -> bool {
+    depth > 0 && alt_cnt > 0 && alt_cnt as f32 >= params.min_alt_frac * depth as f32
+}
+
+/// majority inserted base of each insertion column, until less than half of the inserting reads
+/// carry a base
+fn insertion_consensus(plp: &PlpCnts, first_tt: usize, n_ins_reads: u32) -> Vec<u8> {
+    let major = plp.get_major();
+    let minor = plp.get_minor();
+    let mut inserted = vec![];
+    let mut tt = first_tt;
+    while tt < major.len() && major[tt] == major[first_tt] && minor[tt] > 0 {
+        let (fwd, rev) = column_cnts(plp, tt);
+        let (best, cnt) = (0..4)
+            .map(|i| (i, fwd[i] + rev[i]))
+            .max_by_key(|v| v.1)
+            .unwrap();
+        if cnt == 0 || cnt * 2 < n_ins_reads {
+            break;
+        }
+        inserted.push(BASES[best]);
+        tt += 1;
+    }
+    inserted
+}
+
+/// consecutive deleted ref positions become anchored deletions.
+/// the deletions of a run are assumed to share the first position (left aligned), so the reads
+/// deleting exactly len bases are the gaps at position len - 1 minus the gaps at position len.
+/// every length with such reads is a candidate, e.g. 6 reads deleting 2 bases and 6 reads
+/// deleting 3 bases give a 2bp and a 3bp deletion with 6 supporting reads each
+fn flush_del_run(
+    del_run: &mut Vec<(usize, u32, u32, u32)>,
+    ref_seq: &[u8],
+    candidates: &mut Vec<Candidate>,
+) {
+    if del_run.is_empty() {
+        return;
+    }
+    let first = del_run[0].0;
+    for (idx, &(_, _, gap_fwd, gap_rev)) in del_run.iter().enumerate() {
+        let (next_fwd, next_rev) = del_run.get(idx + 1).map(|v| (v.2, v.3)).unwrap_or((0, 0));
+        let fwd_alt = gap_fwd.saturating_sub(next_fwd);
+        let rev_alt = gap_rev.saturating_sub(next_rev);
+        if fwd_alt + rev_alt == 0 {
+            continue;
+        }
+        let last = first + idx;
+        let depth = del_run[..=idx].iter().map(|v| v.1).max().unwrap();
+
+        // anchor on the base before the deletion, or the base after it at the contig start
+        let (pos, ref_bases, alt_bases) = if first > 0 {
+            (
+                first - 1,
+                ref_seq[first - 1..=last].to_ascii_uppercase(),
+                vec![ref_seq[first - 1].to_ascii_uppercase()],
+            )
+        } else if last + 1 < ref_seq.len() {
+            (
+                0,
+                ref_seq[0..=last + 1].to_ascii_uppercase(),
+                vec![ref_seq[last + 1].to_ascii_uppercase()],
+            )
+        } else {
+            continue;
+        };
+        candidates.push(Candidate {
+            pos,
+            ref_bases,
+            alt_bases,
+            depth,
+            fwd_alt,
+            rev_alt,
+        });
+    }
+    del_run.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        file_reader::{
+            vcf_reader::{VariantType, VcfReader},
+            vcf_writer::VcfWriter,
+        },
+        gsbam::{
+            bam_header_ext::HeaderSQ, bam_record_ext::BamRecord, plp_counts_from_records::PlpCnts,
+            test_utils::RecordBuilder,
+        },
+    };
+
+    use super::{call_variants, caller_vcf_header, genotyping, Ploidy, VariantCallerParams};
+
+    fn build_records(items: &[(&str, &str, bool, usize)]) -> Vec<BamRecord> {
+        let mut records = vec![];
+        for (idx, &(seq, cigar, reverse, n)) in items.iter().enumerate() {
+            let builder = RecordBuilder::new(cigar, seq)
+                .qual(&vec![30; seq.len()])
+                .pos(0)
+                .reverse(reverse);
+            for i in 0..n {
+                records.push(builder.clone().qname(&format!("read_{}_{}", idx, i)).build());
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn test_genotyping() {
+        let gt = genotyping(30, 15, Ploidy::Diploid, 0.01);
+        assert_eq!(gt.genotype, vec![0, 1]);
+        assert!(gt.gq >= 90);
+        assert!(gt.qual > 50.0);
+        assert_eq!(gt.pl[1], 0);
+
+        let gt = genotyping(30, 29, Ploidy::Diploid, 0.01);
+        assert_eq!(gt.genotype, vec![1, 1]);
+        let gt = genotyping(30, 1, Ploidy::Haploid, 0.01);
+        assert_eq!(gt.genotype, vec![0]);
+        assert!(gt.qual < 10.0);
+    }
+
+    #[test]
+    fn test_call_variants() {
+        let ref_seq = b"ACGTACGTACGTAC";
+        // ref: ACGTACGTACGT--AC
+        // alt: ACGTTCGTA--TGGAC. snv at 4, CG deleted at 9-10, GG inserted after 11
+        let records = build_records(&[
+            ("ACGTTCGTATGGAC", "4=1X4=2D1=2I2=", false, 6),
+            ("ACGTTCGTATGGAC", "4=1X4=2D1=2I2=", true, 6),
+            ("ACGTACGTACGTAC", "14=", false, 2),
+            ("ACGTACGTACGTAC", "14=", true, 2),
+        ]);
+        let plp = PlpCnts::from_records(&records, None, None, None);
+        let params = VariantCallerParams::default();
+        let variants = call_variants(&plp, ref_seq, &params);
+
+        let summary = variants
+            .iter()
+            .map(|v| (v.pos, v.ref_bases.as_str(), v.alt_bases.as_str(), v.variant_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (4, "A", "T", VariantType::Snv),
+                (8, "ACG", "A", VariantType::Deletion),
+                (11, "T", "TGG", VariantType::Insertion),
+            ]
+        );
+        for v in &variants {
+            assert_eq!(v.depth, 16);
+            assert_eq!(v.alt_cnt, 12);
+            assert_eq!(v.genotype, vec![1]);
+            assert!(v.is_pass());
+        }
+
+        let diploid = VariantCallerParams {
+            ploidy: Ploidy::Diploid,
+            ..Default::default()
+        };
+        let variants = call_variants(&plp, ref_seq, &diploid);
+        assert!(variants.iter().all(|v| v.genotype == vec![0, 1]));
+
+        let row = variants[0].to_vcf_row_data("chr1");
+        assert_eq!((row.pos, row.ref_bases.as_str(), row.alt_bases.as_str()), (4, "A", "T"));
+        let record = variants[1].to_vcf_record("chr1");
+        assert_eq!(record.samples[0].genotype.as_ref().unwrap().to_string(), "0/1");
+
+        let seqs = vec![HeaderSQ::new(0, "chr1".to_string(), ref_seq.len())];
+        let path = std::env::temp_dir()
+            .join(format!("gskits_caller_test_{}.vcf", std::process::id()));
+        {
+            let mut writer = VcfWriter::from_path(&path, &caller_vcf_header(&seqs, "s1")).unwrap();
+            for v in &variants {
+                writer.write(&v.to_vcf_record("chr1")).unwrap();
+            }
+        }
+        let read_back = VcfReader::from_path(&path)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_back.len(), 3);
+        assert_eq!(read_back[1].ref_allele, "ACG");
+        assert_eq!(read_back[1].samples[0].fields["AD"], record.samples[0].fields["AD"]);
+    }
+
+    #[test]
+    fn test_call_variants_touching_deletions() {
+        let ref_seq = b"ACGTACGTACGTAC";
+        // CG deleted at 5-6 by 6 reads, CGT deleted at 5-7 by 6 reads
+        let records = build_records(&[
+            ("ACGTATACGTAC", "5=2D7=", false, 3),
+            ("ACGTATACGTAC", "5=2D7=", true, 3),
+            ("ACGTAACGTAC", "5=3D6=", false, 3),
+            ("ACGTAACGTAC", "5=3D6=", true, 3),
+            ("ACGTACGTACGTAC", "14=", false, 2),
+            ("ACGTACGTACGTAC", "14=", true, 2),
+        ]);
+        let plp = PlpCnts::from_records(&records, None, None, None);
+        let params = VariantCallerParams {
+            ploidy: Ploidy::Diploid,
+            ..Default::default()
+        };
+        let variants = call_variants(&plp, ref_seq, &params);
+        let summary = variants
+            .iter()
+            .map(|v| (v.pos, v.ref_bases.as_str(), v.alt_bases.as_str(), v.alt_cnt))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![(4, "ACG", "A", 6), (4, "ACGT", "A", 6)]);
+        assert!(variants.iter().all(|v| v.depth == 16));
+    }
+
+    #[test]
+    fn test_call_variants_strand_bias() {
+        let ref_seq = b"ACGTACGTAC";
+        let records = build_records(&[
+            ("ACGTTCGTAC", "4=1X5=", false, 8),
+            ("ACGTACGTAC", "10=", true, 8),
+        ]);
+        let plp = PlpCnts::from_records(&records, None, None, None);
+        let params = VariantCallerParams {
+            ploidy: Ploidy::Diploid,
+            ..Default::default()
+        };
+        let variants = call_variants(&plp, ref_seq, &params);
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].filters, vec!["strand_bias"]);
+        assert_eq!((variants[0].fwd_alt_cnt, variants[0].rev_alt_cnt), (8, 0));
+
+        let strict = VariantCallerParams {
+            min_depth: 20,
+            ..Default::default()
+        };
+        assert!(call_variants(&plp, ref_seq, &strict).is_empty());
+    }
+}
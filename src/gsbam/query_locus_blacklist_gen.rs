@@ -1,14 +1,162 @@
 use std::collections::HashSet;
 
-use crate::itertools::sliding_window;
+use rust_htslib::bam::record::Cigar;
+
+use crate::{
+    ds::region::{Region, Regions},
+    itertools::sliding_window,
+    phreq::{phreq_list_2_quality, quality_2_phreq_f32},
+    poly_n::find_poly_n_regions,
+};
 
 use super::{
-    bam_record_ext::BamRecord,
+    bam_record_ext::{BamRecord, BamRecordExt},
     cigar_ext::{long_ins_regions_in_query, RangeIdentityCalculator},
 };
 
 pub trait TQueryLocusBlacklist: Send + Sync {
     fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize>;
+
+    /// the same locus as get_blacklist_locus, but as sorted merged intervals.
+    /// the generators of this module build the intervals directly
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        QueryLocusIntervals::from_locus(&self.get_blacklist_locus(record))
+    }
+}
+
+/// sorted and merged query intervals [start, end).
+/// `contains` is O(log n), use `cursor` for the sequential queries
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryLocusIntervals {
+    intervals: Vec<(usize, usize)>,
+}
+
+impl QueryLocusIntervals {
+    /// the intervals can be unsorted and overlapped, empty intervals are dropped
+    pub fn new(intervals: Vec<(usize, usize)>) -> Self {
+        let regions = Regions::new(
+            intervals
+                .into_iter()
+                .filter(|&(start, end)| start < end)
+                .map(|(start, end)| Region::new(start, end))
+                .collect(),
+        );
+        Self {
+            intervals: regions
+                .merge_regions()
+                .iter()
+                .map(|region| (region.start(), region.end()))
+                .collect(),
+        }
+    }
+
+    pub fn from_locus(locus: &HashSet<usize>) -> Self {
+        let mut locus = locus.iter().copied().collect::<Vec<_>>();
+        locus.sort_unstable();
+        let mut intervals: Vec<(usize, usize)> = vec![];
+        for pos in locus {
+            match intervals.last_mut() {
+                Some(last) if last.1 == pos => last.1 += 1,
+                _ => intervals.push((pos, pos + 1)),
+            }
+        }
+        Self { intervals }
+    }
+
+    pub fn intervals(&self) -> &Vec<(usize, usize)> {
+        &self.intervals
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// number of the blacklisted positions
+    pub fn total_length(&self) -> usize {
+        self.intervals.iter().map(|(start, end)| end - start).sum()
+    }
+
+    pub fn contains(&self, pos: usize) -> bool {
+        let idx = self.intervals.partition_point(|&(_, end)| end <= pos);
+        idx < self.intervals.len() && self.intervals[idx].0 <= pos
+    }
+
+    pub fn cursor(&self) -> QueryLocusCursor<'_> {
+        QueryLocusCursor {
+            intervals: &self.intervals,
+            idx: 0,
+            last_pos: 0,
+        }
+    }
+
+    pub fn union(&self, other: &QueryLocusIntervals) -> Self {
+        Self::new(
+            self.intervals
+                .iter()
+                .chain(other.intervals.iter())
+                .copied()
+                .collect(),
+        )
+    }
+
+    pub fn intersection(&self, other: &QueryLocusIntervals) -> Self {
+        let mut intervals = vec![];
+        let (mut i, mut j) = (0, 0);
+        while i < self.intervals.len() && j < other.intervals.len() {
+            let (a_start, a_end) = self.intervals[i];
+            let (b_start, b_end) = other.intervals[j];
+            let start = a_start.max(b_start);
+            let end = a_end.min(b_end);
+            if start < end {
+                intervals.push((start, end));
+            }
+            if a_end < b_end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        Self { intervals }
+    }
+
+    /// extend every interval by k on both sides, the result is clipped to [0, seq_len)
+    pub fn dilate(&self, k: usize, seq_len: usize) -> Self {
+        Self::new(
+            self.intervals
+                .iter()
+                .map(|&(start, end)| (start.saturating_sub(k), (end + k).min(seq_len)))
+                .collect(),
+        )
+    }
+
+    pub fn to_locus(&self) -> HashSet<usize> {
+        self.intervals
+            .iter()
+            .flat_map(|&(start, end)| start..end)
+            .collect()
+    }
+}
+
+/// membership queries with non-decreasing positions are amortized O(1).
+/// a smaller position than the last query falls back to the binary search
+pub struct QueryLocusCursor<'a> {
+    intervals: &'a [(usize, usize)],
+    idx: usize,
+    last_pos: usize,
+}
+
+impl QueryLocusCursor<'_> {
+    pub fn contains(&mut self, pos: usize) -> bool {
+        if pos < self.last_pos {
+            self.idx = self.intervals.partition_point(|&(_, end)| end <= pos);
+        } else {
+            while self.idx < self.intervals.len() && self.intervals[self.idx].1 <= pos {
+                self.idx += 1;
+            }
+        }
+        self.last_pos = pos;
+        self.idx < self.intervals.len() && self.intervals[self.idx].0 <= pos
+    }
 }
 
 /// treat the ins >= ins_thr as blacklist locus
@@ -24,11 +172,14 @@ impl LongInsBlacklist {
 
 impl TQueryLocusBlacklist for LongInsBlacklist {
     fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
-        let regions = long_ins_regions_in_query(&record.cigar().take(), self.ins_thr);
-        regions
-            .into_iter()
-            .flat_map(|(start, end)| (start..end).into_iter())
-            .collect()
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        QueryLocusIntervals::new(long_ins_regions_in_query(
+            &record.cigar().take(),
+            self.ins_thr,
+        ))
     }
 }
 
@@ -56,26 +207,273 @@ impl LowIdentityBlacklist {
 
 impl TQueryLocusBlacklist for LowIdentityBlacklist {
     fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
         let identity_calc = RangeIdentityCalculator::new(&record.cigar().take());
         let seq_len = record.seq_len();
-        sliding_window(seq_len, self.win_size, self.win_ovlp, true)
-            .into_iter()
-            .flat_map(|(start, end)| {
-                let (identity_start, identity_end, identity) =
-                    identity_calc.compute_range_identity(start as u32, end as u32);
-                // println!("{}, {}, {}", identity_start, identity_end, identity);
-                
-                let (identity_start, identity_end) =
-                    (identity_start as usize, identity_end as usize);
-                if (identity_end - identity_start) > self.win_size / 2
-                    && identity < self.identity_thr
-                {
-                    (identity_start..identity_end).into_iter()
-                } else {
-                    (0..0).into_iter()
+        QueryLocusIntervals::new(
+            sliding_window(seq_len, self.win_size, self.win_ovlp, true)
+                .filter_map(|(start, end)| {
+                    let (identity_start, identity_end, identity) =
+                        identity_calc.compute_range_identity(start as u32, end as u32);
+                    let (identity_start, identity_end) =
+                        (identity_start as usize, identity_end as usize);
+                    if (identity_end - identity_start) > self.win_size / 2
+                        && identity < self.identity_thr
+                    {
+                        Some((identity_start, identity_end))
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+/// the windows whose empirical phreq (phreq of the mean error rate) < phreq_thr.
+/// records without base quality get no blacklist locus
+pub struct LowQualBlacklist {
+    phreq_thr: f32,
+    win_size: usize,
+    win_ovlp: usize,
+}
+
+impl LowQualBlacklist {
+    pub fn new(phreq_thr: f32, win_size: usize, win_ovlp: usize) -> Self {
+        assert!(
+            win_size > win_ovlp,
+            "win_size > win_ovlp, but got {} <= {}",
+            win_size,
+            win_ovlp
+        );
+        Self {
+            phreq_thr,
+            win_size,
+            win_ovlp,
+        }
+    }
+}
+
+impl TQueryLocusBlacklist for LowQualBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        let qual = record.qual();
+        // 255: quality is absent
+        if qual.first().map(|&q| q == 255).unwrap_or(true) {
+            return QueryLocusIntervals::default();
+        }
+        QueryLocusIntervals::new(
+            sliding_window(qual.len(), self.win_size, self.win_ovlp, false)
+                .filter(|&(start, end)| {
+                    phreq_list_2_quality(&qual[start..end])
+                        .map(|quality| quality_2_phreq_f32(quality, None) < self.phreq_thr)
+                        .unwrap_or(false)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// the homopolymers in the query whose length >= min_len
+pub struct HomopolymerBlacklist {
+    min_len: usize,
+}
+
+impl HomopolymerBlacklist {
+    pub fn new(min_len: usize) -> Self {
+        Self { min_len }
+    }
+}
+
+impl TQueryLocusBlacklist for HomopolymerBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        QueryLocusIntervals::new(
+            find_poly_n_regions(&record.seq().as_bytes())
+                .into_iter()
+                .filter(|&(start, end, _)| end - start >= self.min_len)
+                .map(|(start, end, _)| (start, end))
+                .collect(),
+        )
+    }
+}
+
+/// the N bases in the query
+pub struct NBaseBlacklist;
+
+impl TQueryLocusBlacklist for NBaseBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        QueryLocusIntervals::new(
+            record
+                .seq()
+                .as_bytes()
+                .iter()
+                .enumerate()
+                .filter(|(_, base)| base.eq_ignore_ascii_case(&b'N'))
+                .map(|(idx, _)| (idx, idx + 1))
+                .collect(),
+        )
+    }
+}
+
+/// the first k and the last k aligned query bases, they are next to the clipped ends
+pub struct ClipEndBlacklist {
+    k: usize,
+}
+
+impl ClipEndBlacklist {
+    pub fn new(k: usize) -> Self {
+        Self { k }
+    }
+}
+
+impl TQueryLocusBlacklist for ClipEndBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        let record_ext = BamRecordExt::new(record);
+        let qstart = record_ext.query_alignment_start();
+        let qend = record_ext.query_alignment_end();
+        QueryLocusIntervals::new(vec![
+            (qstart, (qstart + self.k).min(qend)),
+            (qend.saturating_sub(self.k).max(qstart), qend),
+        ])
+    }
+}
+
+/// flank query bases of the deletions >= del_thr. the flank_size bases on each side of the deletion
+pub struct LongDelFlankBlacklist {
+    del_thr: usize,
+    flank_size: usize,
+}
+
+impl LongDelFlankBlacklist {
+    pub fn new(del_thr: usize, flank_size: usize) -> Self {
+        Self {
+            del_thr,
+            flank_size,
+        }
+    }
+}
+
+impl TQueryLocusBlacklist for LongDelFlankBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        let seq_len = record.seq_len();
+        let mut qpos = 0_usize;
+        let mut intervals = vec![];
+        for cigar in record.cigar().iter() {
+            match *cigar {
+                Cigar::Del(n) if n as usize >= self.del_thr => {
+                    // the deletion is between qpos - 1 and qpos
+                    intervals.push((
+                        qpos.saturating_sub(self.flank_size),
+                        (qpos + self.flank_size).min(seq_len),
+                    ));
                 }
-            })
-            .collect()
+                Cigar::Match(n)
+                | Cigar::Equal(n)
+                | Cigar::Diff(n)
+                | Cigar::Ins(n)
+                | Cigar::SoftClip(n) => qpos += n as usize,
+                _ => {}
+            }
+        }
+        QueryLocusIntervals::new(intervals)
+    }
+}
+
+/// locus that in any of the blacklists
+pub struct UnionBlacklist {
+    blacklists: Vec<Box<dyn TQueryLocusBlacklist>>,
+}
+
+impl UnionBlacklist {
+    pub fn new(blacklists: Vec<Box<dyn TQueryLocusBlacklist>>) -> Self {
+        Self { blacklists }
+    }
+}
+
+impl TQueryLocusBlacklist for UnionBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        get_query_locus_blacklist_intervals(record, Some(&self.blacklists))
+    }
+}
+
+/// locus that in all of the blacklists. empty if no blacklist is provided
+pub struct IntersectionBlacklist {
+    blacklists: Vec<Box<dyn TQueryLocusBlacklist>>,
+}
+
+impl IntersectionBlacklist {
+    pub fn new(blacklists: Vec<Box<dyn TQueryLocusBlacklist>>) -> Self {
+        Self { blacklists }
+    }
+}
+
+impl TQueryLocusBlacklist for IntersectionBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        let mut blacklists = self.blacklists.iter();
+        let first = match blacklists.next() {
+            Some(first) => first.get_blacklist_intervals(record),
+            None => return QueryLocusIntervals::default(),
+        };
+        blacklists.fold(first, |acc, blacklist| {
+            if acc.is_empty() {
+                return acc;
+            }
+            acc.intersection(&blacklist.get_blacklist_intervals(record))
+        })
+    }
+}
+
+/// extend every locus of the blacklist by k bases on both sides
+pub struct DilatedBlacklist {
+    blacklist: Box<dyn TQueryLocusBlacklist>,
+    k: usize,
+}
+
+impl DilatedBlacklist {
+    pub fn new(blacklist: Box<dyn TQueryLocusBlacklist>, k: usize) -> Self {
+        Self { blacklist, k }
+    }
+}
+
+impl TQueryLocusBlacklist for DilatedBlacklist {
+    fn get_blacklist_locus(&self, record: &BamRecord) -> HashSet<usize> {
+        self.get_blacklist_intervals(record).to_locus()
+    }
+
+    fn get_blacklist_intervals(&self, record: &BamRecord) -> QueryLocusIntervals {
+        self.blacklist
+            .get_blacklist_intervals(record)
+            .dilate(self.k, record.seq_len())
     }
 }
 
@@ -92,3 +490,195 @@ pub fn get_query_locus_blacklist(
         .unwrap_or(HashSet::new());
     query_locus_blacklist
 }
+
+/// union of the intervals of all the generators
+pub fn get_query_locus_blacklist_intervals(
+    record: &BamRecord,
+    query_locus_blacklist_gen: Option<&Vec<Box<dyn TQueryLocusBlacklist>>>,
+) -> QueryLocusIntervals {
+    query_locus_blacklist_gen
+        .map(|gens| {
+            QueryLocusIntervals::new(
+                gens.iter()
+                    .flat_map(|gen| gen.get_blacklist_intervals(record).intervals)
+                    .collect(),
+            )
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::gsbam::test_utils::RecordBuilder;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{
+        ClipEndBlacklist, DilatedBlacklist, HomopolymerBlacklist, IntersectionBlacklist,
+        LongDelFlankBlacklist, LowQualBlacklist, NBaseBlacklist, QueryLocusIntervals,
+        TQueryLocusBlacklist, UnionBlacklist,
+    };
+
+    fn sorted(locus: HashSet<usize>) -> Vec<usize> {
+        let mut locus = locus.into_iter().collect::<Vec<_>>();
+        locus.sort();
+        locus
+    }
+
+    #[test]
+    fn test_blacklist_generators() {
+        let seq = "ACGTTTTANCGA";
+        let qual = [30, 30, 30, 30, 5, 5, 5, 5, 30, 30, 30, 30];
+        let record = RecordBuilder::new("2S3=5D5=2S", seq).qual(&qual).pos(100).build();
+
+        assert_eq!(
+            sorted(LowQualBlacklist::new(20.0, 4, 0).get_blacklist_locus(&record)),
+            vec![4, 5, 6, 7]
+        );
+        let no_qual = RecordBuilder::new("2S3=5D5=2S", seq).pos(100).build();
+        assert!(LowQualBlacklist::new(20.0, 4, 0)
+            .get_blacklist_locus(&no_qual)
+            .is_empty());
+
+        assert_eq!(
+            sorted(HomopolymerBlacklist::new(4).get_blacklist_locus(&record)),
+            vec![3, 4, 5, 6]
+        );
+        assert!(HomopolymerBlacklist::new(5)
+            .get_blacklist_locus(&record)
+            .is_empty());
+
+        assert_eq!(
+            sorted(NBaseBlacklist.get_blacklist_locus(&record)),
+            vec![8]
+        );
+
+        assert_eq!(
+            sorted(ClipEndBlacklist::new(2).get_blacklist_locus(&record)),
+            vec![2, 3, 8, 9]
+        );
+        assert_eq!(
+            sorted(ClipEndBlacklist::new(20).get_blacklist_locus(&record)),
+            (2..10).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            sorted(LongDelFlankBlacklist::new(5, 2).get_blacklist_locus(&record)),
+            vec![3, 4, 5, 6]
+        );
+        assert!(LongDelFlankBlacklist::new(6, 2)
+            .get_blacklist_locus(&record)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_blacklist_combinators() {
+        let seq = "ACGTTTTANCGA";
+        let record = RecordBuilder::new("2S3=5D5=2S", seq).qual(&[30; 12]).pos(100).build();
+
+        let union = UnionBlacklist::new(vec![
+            Box::new(NBaseBlacklist),
+            Box::new(HomopolymerBlacklist::new(4)),
+        ]);
+        assert_eq!(
+            sorted(union.get_blacklist_locus(&record)),
+            vec![3, 4, 5, 6, 8]
+        );
+
+        let intersection = IntersectionBlacklist::new(vec![
+            Box::new(ClipEndBlacklist::new(2)),
+            Box::new(HomopolymerBlacklist::new(4)),
+        ]);
+        assert_eq!(sorted(intersection.get_blacklist_locus(&record)), vec![3]);
+        assert!(IntersectionBlacklist::new(vec![])
+            .get_blacklist_locus(&record)
+            .is_empty());
+
+        let dilated = DilatedBlacklist::new(Box::new(NBaseBlacklist), 2);
+        assert_eq!(
+            sorted(dilated.get_blacklist_locus(&record)),
+            vec![6, 7, 8, 9, 10]
+        );
+        let dilated = DilatedBlacklist::new(Box::new(ClipEndBlacklist::new(1)), 3);
+        assert_eq!(
+            sorted(dilated.get_blacklist_locus(&record)),
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]
+        );
+    }
+
+    #[test]
+    fn test_query_locus_intervals() {
+        let intervals = QueryLocusIntervals::new(vec![(8, 10), (1, 3), (2, 5), (5, 6), (12, 12)]);
+        assert_eq!(intervals.intervals(), &vec![(1, 6), (8, 10)]);
+        assert_eq!(intervals.total_length(), 7);
+        assert_eq!(
+            QueryLocusIntervals::from_locus(&HashSet::from([1, 2, 3, 4, 5, 8, 9])),
+            intervals
+        );
+
+        let other = QueryLocusIntervals::new(vec![(0, 2), (4, 9)]);
+        assert_eq!(
+            intervals.union(&other).intervals(),
+            &vec![(0, 10)]
+        );
+        assert_eq!(
+            intervals.intersection(&other).intervals(),
+            &vec![(1, 2), (4, 6), (8, 9)]
+        );
+        assert_eq!(intervals.dilate(1, 10).intervals(), &vec![(0, 10)]);
+        assert_eq!(intervals.dilate(1, 8).intervals(), &vec![(0, 8)]);
+
+        let mut rng = StdRng::seed_from_u64(35);
+        for _ in 0..100 {
+            let locus = (0..rng.gen_range(0..50))
+                .map(|_| rng.gen_range(0..100))
+                .collect::<HashSet<usize>>();
+            let intervals = QueryLocusIntervals::from_locus(&locus);
+            assert_eq!(intervals.to_locus(), locus);
+
+            let mut cursor = intervals.cursor();
+            let mut pos = 0_usize;
+            for _ in 0..200 {
+                // mostly increasing, sometimes step back
+                pos = if rng.gen_bool(0.1) {
+                    pos.saturating_sub(rng.gen_range(0..5))
+                } else {
+                    pos + rng.gen_range(0..3)
+                };
+                assert_eq!(intervals.contains(pos), locus.contains(&pos));
+                assert_eq!(cursor.contains(pos), locus.contains(&pos), "{}", pos);
+            }
+        }
+    }
+
+    #[test]
+    fn test_blacklist_intervals() {
+        let seq = "ACGTTTTANCGA";
+        let qual = [30, 30, 30, 30, 5, 5, 5, 5, 30, 30, 30, 30];
+        let record = RecordBuilder::new("2S3=5D5=2S", seq).qual(&qual).pos(100).build();
+
+        let union = UnionBlacklist::new(vec![
+            Box::new(NBaseBlacklist),
+            Box::new(HomopolymerBlacklist::new(4)),
+            Box::new(LongDelFlankBlacklist::new(5, 1)),
+        ]);
+        assert_eq!(
+            union.get_blacklist_intervals(&record).intervals(),
+            &vec![(3, 7), (8, 9)]
+        );
+        assert_eq!(
+            ClipEndBlacklist::new(2)
+                .get_blacklist_intervals(&record)
+                .intervals(),
+            &vec![(2, 4), (8, 10)]
+        );
+        assert_eq!(
+            LowQualBlacklist::new(20.0, 4, 2)
+                .get_blacklist_intervals(&record)
+                .intervals(),
+            &vec![(2, 10)]
+        );
+    }
+}
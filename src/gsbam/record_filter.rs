@@ -0,0 +1,542 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+
+use super::{
+    bam_record_ext::{BamRecord, BamRecordExt},
+    cigar_ext::IdentityMetric,
+};
+
+/// decide whether a record should be kept during bam iteration
+pub trait RecordFilter: Send + Sync {
+    fn keep(&self, record: &BamRecord) -> bool;
+}
+
+impl<F> RecordFilter for F
+where
+    F: Fn(&BamRecord) -> bool + Send + Sync,
+{
+    fn keep(&self, record: &BamRecord) -> bool {
+        self(record)
+    }
+}
+
+/// numeric values that can be used in the filter expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordField {
+    /// rq tag
+    Rq,
+    /// np tag
+    Np,
+    /// ch tag
+    Ch,
+    /// query length
+    Len,
+    Mapq,
+    /// blast identity, see BamRecordExt::identity_counts. missing if the cigar has M ops
+    Identity,
+    /// BamRecordExt::compute_query_coverage
+    QueryCoverage,
+}
+
+impl RecordField {
+    /// None if the tag is missing or the value can't be computed
+    pub fn value(&self, record: &BamRecord) -> Option<f64> {
+        let record_ext = BamRecordExt::new(record);
+        match self {
+            RecordField::Rq => record_ext.get_rq().map(|v| v as f64),
+            RecordField::Np => record_ext.get_np().map(|v| v as f64),
+            RecordField::Ch => record_ext.get_ch().map(|v| v as f64),
+            RecordField::Len => Some(record.seq_len() as f64),
+            RecordField::Mapq => Some(record.mapq() as f64),
+            RecordField::Identity => {
+                let counts = record_ext.identity_counts(None);
+                (counts.mat == 0).then(|| counts.identity(IdentityMetric::Blast) as f64)
+            }
+            RecordField::QueryCoverage => Some(record_ext.compute_query_coverage() as f64),
+        }
+    }
+}
+
+impl FromStr for RecordField {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "rq" => RecordField::Rq,
+            "np" => RecordField::Np,
+            "ch" => RecordField::Ch,
+            "len" => RecordField::Len,
+            "mapq" => RecordField::Mapq,
+            "identity" | "iy" => RecordField::Identity,
+            "qcov" | "query_coverage" => RecordField::QueryCoverage,
+            _ => return Err(anyhow!("unknown record field: '{}'", s)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFlag {
+    Secondary,
+    Supplementary,
+    Unmapped,
+    Reverse,
+    Duplicate,
+    QcFail,
+}
+
+impl RecordFlag {
+    pub fn is_set(&self, record: &BamRecord) -> bool {
+        match self {
+            RecordFlag::Secondary => record.is_secondary(),
+            RecordFlag::Supplementary => record.is_supplementary(),
+            RecordFlag::Unmapped => record.is_unmapped(),
+            RecordFlag::Reverse => record.is_reverse(),
+            RecordFlag::Duplicate => record.is_duplicate(),
+            RecordFlag::QcFail => record.is_quality_check_failed(),
+        }
+    }
+}
+
+impl FromStr for RecordFlag {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "secondary" => RecordFlag::Secondary,
+            "supplementary" => RecordFlag::Supplementary,
+            "unmapped" => RecordFlag::Unmapped,
+            "reverse" => RecordFlag::Reverse,
+            "duplicate" => RecordFlag::Duplicate,
+            "qcfail" => RecordFlag::QcFail,
+            _ => return Err(anyhow!("unknown record flag: '{}'", s)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Ge,
+    Gt,
+    Le,
+    Lt,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    fn compare(&self, a: f64, b: f64) -> bool {
+        match self {
+            CmpOp::Ge => a >= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Le => a <= b,
+            CmpOp::Lt => a < b,
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+        }
+    }
+}
+
+/// parsed filter expression.
+///
+/// grammar:
+///     expr  := and ('||' and)*
+///     and   := unary ('&&' unary)*
+///     unary := '!' unary | '(' expr ')' | flag | field op number | field 'in' range
+///
+/// fields: rq, np, ch, len, mapq, identity(iy), qcov(query_coverage)
+/// flags: secondary, supplementary, unmapped, reverse, duplicate, qcfail
+/// op: >= > <= < == !=
+/// range: the fmt of utils::range_parser, e.g. 1000:20000 or 1:10,20:30. both side inclusive.
+/// a missing side is unbounded, e.g. 1000: is >= 1000
+///
+/// a comparison on a missing tag (e.g. rq of a subread, identity of a non eqx record) is false
+///
+/// ```ignore
+/// let filter: FilterExpr = "rq>=0.99 && !secondary && len in 1000:20000 && ch in 1:5000".parse()?;
+/// let kept = records.iter().filter(|record| filter.keep(record));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterExpr {
+    Flag(RecordFlag),
+    Cmp(RecordField, CmpOp, f64),
+    In(RecordField, Vec<(f64, f64)>),
+    Not(Box<FilterExpr>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+impl RecordFilter for FilterExpr {
+    fn keep(&self, record: &BamRecord) -> bool {
+        match self {
+            FilterExpr::Flag(flag) => flag.is_set(record),
+            FilterExpr::Cmp(field, op, thr) => field
+                .value(record)
+                .map(|v| op.compare(v, *thr))
+                .unwrap_or(false),
+            FilterExpr::In(field, ranges) => field
+                .value(record)
+                .map(|v| ranges.iter().any(|&(b, e)| b <= v && v <= e))
+                .unwrap_or(false),
+            FilterExpr::Not(expr) => !expr.keep(record),
+            FilterExpr::And(exprs) => exprs.iter().all(|expr| expr.keep(record)),
+            FilterExpr::Or(exprs) => exprs.iter().any(|expr| expr.keep(record)),
+        }
+    }
+}
+
+impl FromStr for FilterExpr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expr = parser.parse_or()?;
+        if parser.pos != tokens.len() {
+            return Err(anyhow!(
+                "invalid filter expression '{}', unexpected token: {:?}",
+                s,
+                tokens[parser.pos]
+            ));
+        }
+        Ok(expr)
+    }
+}
+
+/// all filters need to be passed
+pub struct AllFilters(pub Vec<Box<dyn RecordFilter>>);
+
+impl RecordFilter for AllFilters {
+    fn keep(&self, record: &BamRecord) -> bool {
+        self.0.iter().all(|filter| filter.keep(record))
+    }
+}
+
+/// at least one filter need to be passed
+pub struct AnyFilters(pub Vec<Box<dyn RecordFilter>>);
+
+impl RecordFilter for AnyFilters {
+    fn keep(&self, record: &BamRecord) -> bool {
+        self.0.iter().any(|filter| filter.keep(record))
+    }
+}
+
+pub struct NotFilter(pub Box<dyn RecordFilter>);
+
+impl RecordFilter for NotFilter {
+    fn keep(&self, record: &BamRecord) -> bool {
+        !self.0.keep(record)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    /// number or range literal
+    Literal(String),
+    Op(CmpOp),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+fn tokenize(expr: &str) -> anyhow::Result<Vec<Token>> {
+    let chars = expr.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        let next = chars.get(idx + 1).copied();
+        match c {
+            ' ' | '\t' | '\n' => idx += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                idx += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                idx += 1;
+            }
+            '&' if next == Some('&') => {
+                tokens.push(Token::And);
+                idx += 2;
+            }
+            '|' if next == Some('|') => {
+                tokens.push(Token::Or);
+                idx += 2;
+            }
+            '!' if next == Some('=') => {
+                tokens.push(Token::Op(CmpOp::Ne));
+                idx += 2;
+            }
+            '!' => {
+                tokens.push(Token::Not);
+                idx += 1;
+            }
+            '=' if next == Some('=') => {
+                tokens.push(Token::Op(CmpOp::Eq));
+                idx += 2;
+            }
+            '>' | '<' => {
+                let op = match (c, next == Some('=')) {
+                    ('>', true) => CmpOp::Ge,
+                    ('>', false) => CmpOp::Gt,
+                    ('<', true) => CmpOp::Le,
+                    _ => CmpOp::Lt,
+                };
+                tokens.push(Token::Op(op));
+                idx += if next == Some('=') { 2 } else { 1 };
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let start = idx;
+                while idx < chars.len() && (chars[idx].is_ascii_alphanumeric() || chars[idx] == '_')
+                {
+                    idx += 1;
+                }
+                tokens.push(Token::Ident(chars[start..idx].iter().collect()));
+            }
+            c if c.is_ascii_digit() || c == '.' || c == '-' || c == ':' => {
+                let start = idx;
+                while idx < chars.len()
+                    && (chars[idx].is_ascii_digit() || ".-+:,eE".contains(chars[idx]))
+                {
+                    idx += 1;
+                }
+                tokens.push(Token::Literal(chars[start..idx].iter().collect()));
+            }
+            _ => {
+                return Err(anyhow!(
+                    "invalid filter expression '{}', unexpected char '{}' at {}",
+                    expr,
+                    c,
+                    idx
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn advance(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> anyhow::Result<FilterExpr> {
+        let mut exprs = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            exprs.push(self.parse_and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            FilterExpr::Or(exprs)
+        })
+    }
+
+    fn parse_and(&mut self) -> anyhow::Result<FilterExpr> {
+        let mut exprs = vec![self.parse_unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            exprs.push(self.parse_unary()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            FilterExpr::And(exprs)
+        })
+    }
+
+    fn parse_unary(&mut self) -> anyhow::Result<FilterExpr> {
+        match self.advance().cloned() {
+            Some(Token::Not) => Ok(FilterExpr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                match self.advance() {
+                    Some(Token::RParen) => Ok(expr),
+                    other => Err(anyhow!("expect ')', but got {:?}", other)),
+                }
+            }
+            Some(Token::Ident(name)) => self.parse_predicate(&name),
+            other => Err(anyhow!("expect predicate, but got {:?}", other)),
+        }
+    }
+
+    fn parse_predicate(&mut self, name: &str) -> anyhow::Result<FilterExpr> {
+        match self.peek().cloned() {
+            Some(Token::Op(op)) => {
+                self.pos += 1;
+                let field = name.parse::<RecordField>()?;
+                match self.advance() {
+                    Some(Token::Literal(v)) => {
+                        let v = v
+                            .parse::<f64>()
+                            .map_err(|e| anyhow!("invalid number '{}', {}", v, e))?;
+                        Ok(FilterExpr::Cmp(field, op, v))
+                    }
+                    other => Err(anyhow!("expect number after {}, but got {:?}", name, other)),
+                }
+            }
+            Some(Token::Ident(kw)) if kw == "in" => {
+                self.pos += 1;
+                let field = name.parse::<RecordField>()?;
+                match self.advance() {
+                    Some(Token::Literal(range_str)) => {
+                        Ok(FilterExpr::In(field, parse_ranges(range_str)?))
+                    }
+                    other => Err(anyhow!("expect range after 'in', but got {:?}", other)),
+                }
+            }
+            _ => Ok(FilterExpr::Flag(name.parse::<RecordFlag>()?)),
+        }
+    }
+}
+
+/// same as utils::range_parser, but a missing side is unbounded instead of being
+/// the other side, so 1000: is [1000, inf) rather than [1000, 1000]
+fn parse_ranges(range_str: &str) -> anyhow::Result<Vec<(f64, f64)>> {
+    let parse = |item: &str, unbounded: f64| {
+        let item = item.trim();
+        if item.is_empty() {
+            Ok(unbounded)
+        } else {
+            item.parse::<f64>()
+                .map_err(|_| anyhow!("invalid range: '{}'", range_str))
+        }
+    };
+    range_str
+        .trim()
+        .split(",")
+        .map(|single_range| {
+            let items = single_range.split(":").collect::<Vec<_>>();
+            match items[..] {
+                [v] if !v.trim().is_empty() => {
+                    let v = parse(v, 0.)?;
+                    Ok((v, v))
+                }
+                [lo, hi] if !(lo.trim().is_empty() && hi.trim().is_empty()) => {
+                    Ok((parse(lo, f64::NEG_INFINITY)?, parse(hi, f64::INFINITY)?))
+                }
+                _ => Err(anyhow!("invalid range: '{}'", range_str)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gsbam::{bam_record_ext::BamRecord, test_utils::RecordBuilder};
+
+    use super::{AllFilters, CmpOp, FilterExpr, NotFilter, RecordField, RecordFilter, RecordFlag};
+
+    #[test]
+    fn test_parse_filter_expr() {
+        let expr = "rq>=0.99 && !secondary && len in 1000:20000"
+            .parse::<FilterExpr>()
+            .unwrap();
+        assert_eq!(
+            expr,
+            FilterExpr::And(vec![
+                FilterExpr::Cmp(RecordField::Rq, CmpOp::Ge, 0.99),
+                FilterExpr::Not(Box::new(FilterExpr::Flag(RecordFlag::Secondary))),
+                FilterExpr::In(RecordField::Len, vec![(1000., 20000.)]),
+            ])
+        );
+
+        let expr = "(rq > 0.9 || np>=3) && ch != 7".parse::<FilterExpr>().unwrap();
+        assert_eq!(
+            expr,
+            FilterExpr::And(vec![
+                FilterExpr::Or(vec![
+                    FilterExpr::Cmp(RecordField::Rq, CmpOp::Gt, 0.9),
+                    FilterExpr::Cmp(RecordField::Np, CmpOp::Ge, 3.),
+                ]),
+                FilterExpr::Cmp(RecordField::Ch, CmpOp::Ne, 7.),
+            ])
+        );
+
+        assert!("rq >= ".parse::<FilterExpr>().is_err());
+        assert!("foo >= 1".parse::<FilterExpr>().is_err());
+        assert!("rq >= 0.9 &&".parse::<FilterExpr>().is_err());
+        assert!("(rq >= 0.9".parse::<FilterExpr>().is_err());
+        assert!("len in a:b".parse::<FilterExpr>().is_err());
+        assert!("len in 1:2:3".parse::<FilterExpr>().is_err());
+        assert!("len in 1-:2".parse::<FilterExpr>().is_err());
+        assert!("len in :".parse::<FilterExpr>().is_err());
+        assert!("len in 1,,2".parse::<FilterExpr>().is_err());
+
+        // a missing side is unbounded
+        assert_eq!(
+            "len in 1000:".parse::<FilterExpr>().unwrap(),
+            FilterExpr::In(RecordField::Len, vec![(1000., f64::INFINITY)])
+        );
+        assert_eq!(
+            "len in :10,20".parse::<FilterExpr>().unwrap(),
+            FilterExpr::In(RecordField::Len, vec![(f64::NEG_INFINITY, 10.), (20., 20.)])
+        );
+        assert!("rq >= 0.9 $".parse::<FilterExpr>().is_err());
+    }
+
+    #[test]
+    fn test_filter_expr_keep() {
+        let filter = "rq>=0.99 && !secondary && len in 5:10 && ch in 1:5000"
+            .parse::<FilterExpr>()
+            .unwrap();
+        for (len, rq, ch, keep) in [
+            (6, Some(0.995), 10, true),
+            (6, Some(0.98), 10, false),
+            (6, None, 10, false),
+            (11, Some(0.995), 10, false),
+            (6, Some(0.995), 5001, false),
+        ] {
+            let record = RecordBuilder::eqx("A".repeat(len)).rq(rq).ch(Some(ch)).build();
+            assert_eq!(filter.keep(&record), keep, "{} {:?} {}", len, rq, ch);
+        }
+
+        let mut record = RecordBuilder::eqx("A".repeat(6)).rq(Some(0.995)).ch(Some(10)).build();
+        record.set_secondary();
+        assert!(!filter.keep(&record));
+
+        let filter = "identity >= 1.0 && qcov == 1".parse::<FilterExpr>().unwrap();
+        assert!(filter.keep(&RecordBuilder::eqx("A".repeat(6)).ch(Some(10)).build()));
+        // identity of a non eqx record is missing
+        let record = RecordBuilder::new("6M", "AAAAAA").ch(Some(10)).build();
+        assert!(!filter.keep(&record));
+        assert!("!(identity < 0.9)".parse::<FilterExpr>().unwrap().keep(&record));
+
+        let filter = "len in 1:2,5:6".parse::<FilterExpr>().unwrap();
+        assert!(filter.keep(&RecordBuilder::eqx("A".repeat(5)).ch(Some(10)).build()));
+        assert!(!filter.keep(&RecordBuilder::eqx("A".repeat(3)).ch(Some(10)).build()));
+
+        let filter = "len in 5:".parse::<FilterExpr>().unwrap();
+        assert!(filter.keep(&RecordBuilder::eqx("A".repeat(11)).build()));
+        assert!(!filter.keep(&RecordBuilder::eqx("A".repeat(4)).build()));
+    }
+
+    #[test]
+    fn test_filter_combinators() {
+        let filter = AllFilters(vec![
+            Box::new("rq >= 0.9".parse::<FilterExpr>().unwrap()),
+            Box::new(NotFilter(Box::new(|record: &BamRecord| record.seq_len() > 5))),
+        ]);
+        for (len, rq, keep) in [(5, 0.95, true), (6, 0.95, false), (5, 0.85, false)] {
+            let record = RecordBuilder::eqx("A".repeat(len)).rq(Some(rq)).build();
+            assert_eq!(filter.keep(&record), keep, "{} {}", len, rq);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use rust_htslib::bam::record::Aux;
+
+use super::{
+    bam_record_ext::BamRecord,
+    utils::{qname_channel, qname_field_channel},
+};
+
+/// value of the group key. int tags are grouped by value, string/char tags by text
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum GroupKey {
+    Int(i64),
+    Str(String),
+}
+
+impl Display for GroupKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupKey::Int(v) => write!(f, "{}", v),
+            GroupKey::Str(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl GroupKey {
+    /// the key as a channel. Int keys must be non negative, Str keys must be numbers
+    pub fn channel(&self) -> Option<usize> {
+        match self {
+            GroupKey::Int(v) => usize::try_from(*v).ok(),
+            GroupKey::Str(v) => v.parse::<usize>().ok(),
+        }
+    }
+}
+
+pub type GroupKeyFn = Arc<dyn Fn(&BamRecord) -> Option<GroupKey> + Send + Sync>;
+
+/// where the group key (e.g. the channel) of a record comes from
+#[derive(Clone)]
+pub enum GroupKeySource {
+    /// aux tag, e.g. b"ch". the bam should be sorted by `samtools::sort_by_tag`
+    Tag(Vec<u8>),
+    /// aux tag, if the record doesn't have the tag, fallback to the channel in qname (movie/ch/...)
+    TagOrQnameChannel(Vec<u8>),
+    /// channel in qname (movie/ch/...). the bam should be sorted by `samtools sort -n`
+    QnameChannel,
+    /// the idx-th field of qname splitted by '/' as a number. movie/ch/ccs -> QnameField(1)
+    QnameField(usize),
+    Custom(GroupKeyFn),
+}
+
+impl fmt::Debug for GroupKeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupKeySource::Tag(tag) => {
+                write!(f, "Tag({:?})", String::from_utf8_lossy(tag))
+            }
+            GroupKeySource::TagOrQnameChannel(tag) => {
+                write!(f, "TagOrQnameChannel({:?})", String::from_utf8_lossy(tag))
+            }
+            GroupKeySource::QnameChannel => write!(f, "QnameChannel"),
+            GroupKeySource::QnameField(idx) => write!(f, "QnameField({})", idx),
+            GroupKeySource::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// ch tag, fallback to the channel in qname
+impl Default for GroupKeySource {
+    fn default() -> Self {
+        GroupKeySource::TagOrQnameChannel(b"ch".to_vec())
+    }
+}
+
+impl GroupKeySource {
+    pub fn extract(&self, record: &BamRecord) -> Option<GroupKey> {
+        match self {
+            GroupKeySource::Tag(tag) => tag_group_key(record, tag),
+            GroupKeySource::TagOrQnameChannel(tag) => {
+                tag_group_key(record, tag).or_else(|| qname_group_key(record))
+            }
+            GroupKeySource::QnameChannel => qname_group_key(record),
+            GroupKeySource::QnameField(idx) => std::str::from_utf8(record.qname())
+                .ok()
+                .and_then(|qname| qname_field_channel(qname, *idx))
+                .map(|ch| GroupKey::Int(ch as i64)),
+            GroupKeySource::Custom(func) => func(record),
+        }
+    }
+}
+
+fn tag_group_key(record: &BamRecord, tag: &[u8]) -> Option<GroupKey> {
+    record.aux(tag).ok().and_then(|aux| match aux {
+        Aux::I8(v) => Some(GroupKey::Int(v as i64)),
+        Aux::U8(v) => Some(GroupKey::Int(v as i64)),
+        Aux::I16(v) => Some(GroupKey::Int(v as i64)),
+        Aux::U16(v) => Some(GroupKey::Int(v as i64)),
+        Aux::I32(v) => Some(GroupKey::Int(v as i64)),
+        Aux::U32(v) => Some(GroupKey::Int(v as i64)),
+        Aux::Char(v) => Some(GroupKey::Str((v as char).to_string())),
+        Aux::String(v) => Some(GroupKey::Str(v.to_string())),
+        _ => None,
+    })
+}
+
+fn qname_group_key(record: &BamRecord) -> Option<GroupKey> {
+    std::str::from_utf8(record.qname())
+        .ok()
+        .and_then(qname_channel)
+        .map(|ch| GroupKey::Int(ch as i64))
+}
+
+/// group the consecutive records with the same key. `(key, records)` is yielded for every group.
+///
+/// the input must be grouped by key (e.g. the output of `samtools::sort_by_tag`),
+/// if a key shows up again after its group is closed, an error is yielded and the iteration stops.
+/// records without the key also stop the iteration with an error.
+///
+/// ```ignore
+/// let mut reader = BamReader::from_path(&sorted_bam).unwrap();
+/// for group in RecordGroupIter::new(reader.records(), GroupKeySource::Tag(b"ch".to_vec())) {
+///     let (ch, subreads) = group.unwrap();
+/// }
+/// ```
+pub struct RecordGroupIter<I>
+where
+    I: Iterator<Item = Result<BamRecord, rust_htslib::errors::Error>>,
+{
+    records: I,
+    key_source: GroupKeySource,
+    pending: Option<(GroupKey, BamRecord)>,
+    finished_keys: HashSet<GroupKey>,
+    done: bool,
+}
+
+impl<I> RecordGroupIter<I>
+where
+    I: Iterator<Item = Result<BamRecord, rust_htslib::errors::Error>>,
+{
+    pub fn new(records: I, key_source: GroupKeySource) -> Self {
+        Self {
+            records,
+            key_source,
+            pending: None,
+            finished_keys: HashSet::new(),
+            done: false,
+        }
+    }
+
+    fn next_keyed_record(&mut self) -> Option<anyhow::Result<(GroupKey, BamRecord)>> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(anyhow!("read record error, {:?}", e))),
+        };
+
+        Some(match self.key_source.extract(&record) {
+            Some(key) => Ok((key, record)),
+            None => Err(anyhow!(
+                "can't extract group key from record: {}, key_source: {:?}",
+                String::from_utf8_lossy(record.qname()),
+                self.key_source
+            )),
+        })
+    }
+}
+
+impl<I> Iterator for RecordGroupIter<I>
+where
+    I: Iterator<Item = Result<BamRecord, rust_htslib::errors::Error>>,
+{
+    type Item = anyhow::Result<(GroupKey, Vec<BamRecord>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let (cur_key, first_record) = match self.pending.take() {
+            Some(pending) => pending,
+            None => match self.next_keyed_record()? {
+                Ok(keyed) => keyed,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            },
+        };
+
+        if self.finished_keys.contains(&cur_key) {
+            self.done = true;
+            return Some(Err(anyhow!(
+                "records are not grouped by key, key '{}' shows up again. sort the bam first",
+                cur_key
+            )));
+        }
+
+        let mut group = vec![first_record];
+        loop {
+            match self.next_keyed_record() {
+                Some(Ok((key, record))) => {
+                    if key == cur_key {
+                        group.push(record);
+                    } else {
+                        self.pending = Some((key, record));
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        self.finished_keys.insert(cur_key.clone());
+        Some(Ok((cur_key, group)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gsbam::test_utils::RecordBuilder;
+
+    use super::{GroupKey, GroupKeySource, RecordGroupIter};
+
+    #[test]
+    fn test_group_by_tag() {
+        let records = vec![
+            RecordBuilder::eqx("ACGT").qname("m/1/0_4").ch(Some(1)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/1/4_8").ch(Some(1)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/2/0_4").ch(Some(2)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/5/0_4").ch(Some(5)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/5/4_8").ch(Some(5)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/5/8_12").ch(Some(5)).build(),
+        ];
+        let groups = RecordGroupIter::new(
+            records.into_iter().map(Ok),
+            GroupKeySource::Tag(b"ch".to_vec()),
+        )
+        .map(|group| group.unwrap())
+        .map(|(key, records)| (key, records.len()))
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            groups,
+            vec![
+                (GroupKey::Int(1), 2),
+                (GroupKey::Int(2), 1),
+                (GroupKey::Int(5), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_by_qname_channel() {
+        let records = vec![
+            RecordBuilder::eqx("ACGT").qname("m/10/ccs").build(),
+            RecordBuilder::eqx("ACGT").qname("m/10/ccs/fwd").ch(Some(10)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/11/ccs").build(),
+        ];
+        let groups = RecordGroupIter::new(
+            records.into_iter().map(Ok),
+            GroupKeySource::TagOrQnameChannel(b"ch".to_vec()),
+        )
+        .map(|group| group.unwrap())
+        .map(|(key, records)| (key, records.len()))
+        .collect::<Vec<_>>();
+        assert_eq!(groups, vec![(GroupKey::Int(10), 2), (GroupKey::Int(11), 1)]);
+    }
+
+    #[test]
+    fn test_group_unsorted() {
+        let records = vec![
+            RecordBuilder::eqx("ACGT").qname("m/1/0_4").ch(Some(1)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/2/0_4").ch(Some(2)).build(),
+            RecordBuilder::eqx("ACGT").qname("m/1/4_8").ch(Some(1)).build(),
+        ];
+        let groups = RecordGroupIter::new(
+            records.into_iter().map(Ok),
+            GroupKeySource::Tag(b"ch".to_vec()),
+        )
+        .collect::<Vec<_>>();
+        assert_eq!(groups.len(), 3);
+        assert!(groups[0].is_ok());
+        assert!(groups[1].is_ok());
+        assert!(groups[2].is_err());
+
+        let records = vec![RecordBuilder::eqx("ACGT").qname("no_channel").build()];
+        let mut iter = RecordGroupIter::new(
+            records.into_iter().map(Ok),
+            GroupKeySource::QnameChannel,
+        );
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+}
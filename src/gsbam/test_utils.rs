@@ -0,0 +1,101 @@
+//! record fixtures shared by the gsbam test modules
+
+use rust_htslib::bam::record::Aux;
+
+use super::{bam_record_ext::BamRecord, cigar_ext::parse_cigar_string};
+
+/// builds an in-memory BamRecord. qual defaults to 255 for every base.
+/// the record stays unmapped (pos -1) unless `pos` is set
+#[derive(Clone)]
+pub struct RecordBuilder {
+    qname: Vec<u8>,
+    cigar: String,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+    pos: Option<i64>,
+    reverse: bool,
+    ch: Option<u32>,
+    rq: Option<f32>,
+}
+
+impl RecordBuilder {
+    pub fn new(cigar: &str, seq: impl AsRef<[u8]>) -> Self {
+        Self {
+            qname: b"qname".to_vec(),
+            cigar: cigar.to_string(),
+            seq: seq.as_ref().to_vec(),
+            qual: None,
+            pos: None,
+            reverse: false,
+            ch: None,
+            rq: None,
+        }
+    }
+
+    /// all bases aligned with `{len}=`
+    pub fn eqx(seq: impl AsRef<[u8]>) -> Self {
+        let seq = seq.as_ref();
+        Self::new(&format!("{}=", seq.len()), seq)
+    }
+
+    pub fn qname(mut self, qname: &str) -> Self {
+        self.qname = qname.as_bytes().to_vec();
+        self
+    }
+
+    pub fn qual(mut self, qual: &[u8]) -> Self {
+        self.qual = Some(qual.to_vec());
+        self
+    }
+
+    /// also marks the record as mapped
+    pub fn pos(mut self, pos: i64) -> Self {
+        self.pos = Some(pos);
+        self
+    }
+
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    pub fn ch(mut self, ch: Option<u32>) -> Self {
+        self.ch = ch;
+        self
+    }
+
+    pub fn rq(mut self, rq: Option<f32>) -> Self {
+        self.rq = rq;
+        self
+    }
+
+    pub fn build(&self) -> BamRecord {
+        let mut record = BamRecord::new();
+        let qual = self.qual.clone().unwrap_or_else(|| vec![255; self.seq.len()]);
+        record.set(
+            &self.qname,
+            Some(&parse_cigar_string(&self.cigar).unwrap()),
+            &self.seq,
+            &qual,
+        );
+        if let Some(pos) = self.pos {
+            record.set_pos(pos);
+            record.unset_unmapped();
+        }
+        if self.reverse {
+            record.set_reverse();
+        }
+        if let Some(ch) = self.ch {
+            record.push_aux(b"ch", Aux::U32(ch)).unwrap();
+        }
+        if let Some(rq) = self.rq {
+            record.push_aux(b"rq", Aux::Float(rq)).unwrap();
+        }
+        record
+    }
+}
+
+/// a mapped forward record with the default qname and qual
+pub fn build_record(cigar: &str, seq: &str, pos: i64) -> BamRecord {
+    RecordBuilder::new(cigar, seq).pos(pos).build()
+}
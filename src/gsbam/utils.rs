@@ -1,40 +1,43 @@
-use std::collections::{HashSet, HashMap};
-use rust_htslib::bam::{self, Read};
-
-use super::bam_record_ext::BamRecordExt;
-
-
-
-
-#[allow(unused)]
-pub fn seperate_dup_nondup_channels(bam_filepath: &str) -> (HashSet<usize>, HashSet<usize>) {
-    let mut bam_file = bam::Reader::from_path(bam_filepath).unwrap();
-    bam_file.set_threads(16).unwrap();
-    let mut counter = HashMap::new();
-    for record in bam_file.records() {
-        let record = record.unwrap();
-        let record_ext = BamRecordExt::new(&record);
-        
-        let ch = if let Some(n) = record_ext.get_ch() {
-            n as usize
-        } else {
-            // 20240402_Sync_Y0003_02_H01_Run0002_called_subreads/85550/ccs  get ch from qname
-            record_ext.get_qname().split("/")
-                .collect::<Vec<&str>>()[1].parse::<usize>().unwrap()
-        };
-
-        * counter.entry(ch).or_insert(0) += 1;
+use std::collections::HashSet;
+
+use anyhow::Context;
+
+use super::{channel_stats::channel_stats_from_bam, record_group::GroupKeySource};
+
+/// 20240402_Sync_Y0003_02_H01_Run0002_called_subreads/85550/ccs -> 85550
+pub fn qname_channel(qname: &str) -> Option<usize> {
+    qname_field_channel(qname, 1)
+}
+
+/// the idx-th field of qname splitted by '/' as a number
+pub fn qname_field_channel(qname: &str, idx: usize) -> Option<usize> {
+    qname.split("/").nth(idx).and_then(|ch| ch.parse::<usize>().ok())
+}
+
+/// (dup_channels, nondup_channels). channel from the ch tag, fallback to the qname (movie/ch/...).
+/// records without channel are ignored
+pub fn seperate_dup_nondup_channels(
+    bam_filepath: &str,
+) -> anyhow::Result<(HashSet<usize>, HashSet<usize>)> {
+    let stats = channel_stats_from_bam(bam_filepath, &GroupKeySource::default(), None)
+        .with_context(|| format!("read {} error", bam_filepath))?;
+    Ok((stats.dup_channels(), stats.nondup_channels()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{qname_channel, qname_field_channel};
+
+    #[test]
+    fn test_qname_channel() {
+        assert_eq!(
+            qname_channel("20240402_Sync_Y0003_02_H01_Run0002_called_subreads/85550/ccs"),
+            Some(85550)
+        );
+        assert_eq!(qname_channel("m/12/100_200"), Some(12));
+        assert_eq!(qname_channel("read_without_channel"), None);
+        assert_eq!(qname_channel("m/not_a_channel/ccs"), None);
+        assert_eq!(qname_field_channel("m/12/100_200", 0), None);
+        assert_eq!(qname_field_channel("m/12/100", 2), Some(100));
     }
-
-    let nondup_chs = counter.iter()
-        .filter(|(_, v)| **v == 1)
-        .map(|(k, _)| *k)
-        .collect::<HashSet<usize>>();
-
-    let dup_chs = counter.iter()
-        .filter(|(_, v)| **v > 1)
-        .map(|(k, _)| *k)
-        .collect::<HashSet<usize>>();
-
-    (dup_chs, nondup_chs)
-}
\ No newline at end of file
+}
@@ -6,6 +6,7 @@ pub mod samtools;
 pub mod utils;
 pub mod ds;
 pub mod dna;
+pub mod packed_seq;
 pub mod file_reader;
 pub mod phreq;
 pub mod matrix;
@@ -0,0 +1,489 @@
+use std::{
+    arch::x86_64::{
+        __m256i, _mm256_and_si256, _mm256_castsi256_si128, _mm256_cmpeq_epi8, _mm256_loadu_si256,
+        _mm256_madd_epi16, _mm256_maddubs_epi16, _mm256_movemask_epi8, _mm256_or_si256,
+        _mm256_permute4x64_epi64, _mm256_permutevar8x32_epi32, _mm256_set1_epi16,
+        _mm256_set1_epi32, _mm256_set1_epi8, _mm256_setr_epi32, _mm256_setr_epi8,
+        _mm256_shuffle_epi8, _mm256_slli_epi64, _mm256_srli_epi16, _mm256_srli_epi64,
+        _mm256_storeu_si256, _mm256_xor_si256, _mm_cvtsi128_si64,
+    },
+    fmt::Display,
+};
+
+use crate::dna::{kmer_mask, nt4_code, NT4_BASES};
+
+const BASES_PER_WORD: usize = 32;
+
+/// 2-bit packed DNA sequence. A C G T are 0 1 2 3 (SEQ_NT4_TABLE), base i is at bits 2 * (i % 32)
+/// of words[i / 32]. any other base is stored as A and flagged in the N mask (1 bit per base),
+/// it is decoded as N. lowercase bases are decoded as uppercase.
+/// the bits after len and the codes of N are always 0, so the derived Eq and Hash only see
+/// the sequence
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PackedSeq {
+    words: Vec<u64>,
+    n_mask: Vec<u64>,
+    len: usize,
+}
+
+impl PackedSeq {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_ascii(seq: &[u8]) -> Self {
+        let mut words = vec![0u64; seq.len().div_ceil(BASES_PER_WORD)];
+        let mut n_mask = vec![0u64; seq.len().div_ceil(64)];
+        if is_x86_feature_detected!("avx2") {
+            unsafe { encode_avx2(seq, &mut words, &mut n_mask) };
+        } else {
+            encode_scalar(seq, 0, &mut words, &mut n_mask);
+        }
+        Self {
+            words,
+            n_mask,
+            len: seq.len(),
+        }
+    }
+
+    pub fn to_ascii(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.len);
+        for (idx, &word) in self.words.iter().enumerate() {
+            let n = (self.len - idx * BASES_PER_WORD).min(BASES_PER_WORD);
+            (0..n).for_each(|i| res.push(NT4_BASES[((word >> (2 * i)) & 3) as usize]));
+        }
+        for (idx, &mask) in self.n_mask.iter().enumerate() {
+            let mut mask = mask;
+            while mask != 0 {
+                res[idx * 64 + mask.trailing_zeros() as usize] = b'N';
+                mask &= mask - 1;
+            }
+        }
+        res
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// the packed words, BASES_PER_WORD bases each
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    pub fn push(&mut self, base: u8) {
+        if self.len.is_multiple_of(BASES_PER_WORD) {
+            self.words.push(0);
+        }
+        if self.len.is_multiple_of(64) {
+            self.n_mask.push(0);
+        }
+        let code = nt4_code(base);
+        if code > 3 {
+            self.n_mask[self.len / 64] |= 1 << (self.len % 64);
+        } else {
+            self.words[self.len / BASES_PER_WORD] |= (code as u64) << (2 * (self.len % 32));
+        }
+        self.len += 1;
+    }
+
+    pub fn is_n(&self, i: usize) -> bool {
+        (self.n_mask[i / 64] >> (i % 64)) & 1 == 1
+    }
+
+    /// 2-bit code of base i, None for N
+    pub fn code(&self, i: usize) -> Option<u8> {
+        assert!(i < self.len, "index {} out of range, len {}", i, self.len);
+        self.code_in_range(i)
+    }
+
+    #[inline]
+    fn code_in_range(&self, i: usize) -> Option<u8> {
+        if self.is_n(i) {
+            None
+        } else {
+            Some(((self.words[i / BASES_PER_WORD] >> (2 * (i % 32))) & 3) as u8)
+        }
+    }
+
+    /// ascii base i
+    pub fn get(&self, i: usize) -> u8 {
+        self.code(i).map(|code| NT4_BASES[code as usize]).unwrap_or(b'N')
+    }
+
+    pub fn n_count(&self) -> usize {
+        self.n_mask.iter().map(|v| v.count_ones() as usize).sum()
+    }
+
+    /// the bases in [start, end)
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        assert!(
+            start <= end && end <= self.len,
+            "invalid slice {}..{} of len {}",
+            start,
+            end,
+            self.len
+        );
+        let len = end - start;
+        Self {
+            words: extract_bits(&self.words, 2 * start, 2 * len),
+            n_mask: extract_bits(&self.n_mask, start, len),
+            len,
+        }
+    }
+
+    pub fn reverse_complement(&self) -> Self {
+        let mut words = vec![0u64; self.words.len()];
+        if is_x86_feature_detected!("avx2") {
+            unsafe { reverse_complement_words_avx2(&self.words, &mut words) };
+        } else {
+            reverse_complement_words_scalar(&self.words, 0, &mut words);
+        }
+        // the padding bases are at the beginning now
+        let padding = self.words.len() * BASES_PER_WORD - self.len;
+        let mut words = extract_bits(&words, 2 * padding, 2 * self.len);
+
+        let n_mask = self.n_mask.iter().rev().map(|v| v.reverse_bits()).collect::<Vec<_>>();
+        let n_mask = extract_bits(&n_mask, self.n_mask.len() * 64 - self.len, self.len);
+        // N is stored as A, the complement turned it into T
+        clear_n_codes(&mut words, &n_mask);
+
+        Self {
+            words,
+            n_mask,
+            len: self.len,
+        }
+    }
+
+    /// (start, kmer) of the k-mers without N. the first base is at the highest bits of the kmer.
+    /// k must be in 1..=32
+    pub fn kmers(&self, k: usize) -> KmerIter<'_> {
+        assert!((1..=32).contains(&k), "invalid k {}", k);
+        KmerIter {
+            seq: self,
+            k,
+            mask: kmer_mask(k),
+            pos: 0,
+            kmer: 0,
+            valid: 0,
+        }
+    }
+}
+
+impl From<&[u8]> for PackedSeq {
+    fn from(value: &[u8]) -> Self {
+        Self::from_ascii(value)
+    }
+}
+
+impl Display for PackedSeq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8(self.to_ascii()).unwrap())
+    }
+}
+
+pub struct KmerIter<'a> {
+    seq: &'a PackedSeq,
+    k: usize,
+    mask: u64,
+    pos: usize,
+    kmer: u64,
+    /// number of valid bases at the end of kmer
+    valid: usize,
+}
+
+impl Iterator for KmerIter<'_> {
+    type Item = (usize, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.seq.len() {
+            let pos = self.pos;
+            self.pos += 1;
+            match self.seq.code_in_range(pos) {
+                Some(code) => {
+                    self.kmer = ((self.kmer << 2) | code as u64) & self.mask;
+                    self.valid += 1;
+                    if self.valid >= self.k {
+                        return Some((pos + 1 - self.k, self.kmer));
+                    }
+                }
+                None => {
+                    self.kmer = 0;
+                    self.valid = 0;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// set the 2-bit codes of the N bases to 0
+fn clear_n_codes(words: &mut [u64], n_mask: &[u64]) {
+    for (idx, &mask) in n_mask.iter().enumerate() {
+        let mut mask = mask;
+        while mask != 0 {
+            let i = idx * 64 + mask.trailing_zeros() as usize;
+            words[i / BASES_PER_WORD] &= !(3u64 << (2 * (i % 32)));
+            mask &= mask - 1;
+        }
+    }
+}
+
+/// n_bits bits starting at bit_start, as a new bit vector. the bits after n_bits are 0
+fn extract_bits(words: &[u64], bit_start: usize, n_bits: usize) -> Vec<u64> {
+    let (offset, shift) = (bit_start / 64, bit_start % 64);
+    let mut res = (0..n_bits.div_ceil(64))
+        .map(|i| {
+            let lo = words[offset + i] >> shift;
+            let hi = match words.get(offset + i + 1) {
+                Some(v) if shift > 0 => v << (64 - shift),
+                _ => 0,
+            };
+            lo | hi
+        })
+        .collect::<Vec<_>>();
+    if !n_bits.is_multiple_of(64) {
+        if let Some(last) = res.last_mut() {
+            *last &= (1u64 << (n_bits % 64)) - 1;
+        }
+    }
+    res
+}
+
+/// encode seq[start..] into words / n_mask, start must be a multiple of 64
+fn encode_scalar(seq: &[u8], start: usize, words: &mut [u64], n_mask: &mut [u64]) {
+    for (i, &base) in seq.iter().enumerate().skip(start) {
+        let code = nt4_code(base);
+        if code > 3 {
+            n_mask[i / 64] |= 1 << (i % 64);
+        } else {
+            words[i / BASES_PER_WORD] |= (code as u64) << (2 * (i % 32));
+        }
+    }
+}
+
+/// 32 bases per iteration. the code is ((b >> 1) ^ (b >> 2)) & 3 for both cases of ACGT,
+/// 4 codes are packed into a byte by two multiply-adds
+#[target_feature(enable = "avx2")]
+unsafe fn encode_avx2(seq: &[u8], words: &mut [u64], n_mask: &mut [u64]) {
+    let n_chunks = seq.len() / 32;
+    let case_mask = _mm256_set1_epi8(0xDFu8 as i8);
+    let three = _mm256_set1_epi8(3);
+    let (a, c, g, t) = (
+        _mm256_set1_epi8(b'A' as i8),
+        _mm256_set1_epi8(b'C' as i8),
+        _mm256_set1_epi8(b'G' as i8),
+        _mm256_set1_epi8(b'T' as i8),
+    );
+    let pair_weights = _mm256_set1_epi16(0x0401);
+    // (1, 16) in each pair of i16
+    let quad_weights = _mm256_set1_epi32(0x0010_0001);
+    let gather_bytes = _mm256_setr_epi8(
+        0, 4, 8, 12, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, 0, 4, 8, 12, -1, -1, -1, -1,
+        -1, -1, -1, -1, -1, -1, -1, -1,
+    );
+    let gather_dwords = _mm256_setr_epi32(0, 4, 1, 1, 1, 1, 1, 1);
+
+    for chunk in 0..n_chunks {
+        let bases = _mm256_loadu_si256(seq.as_ptr().add(chunk * 32) as *const __m256i);
+        let upper = _mm256_and_si256(bases, case_mask);
+        let valid = _mm256_or_si256(
+            _mm256_or_si256(_mm256_cmpeq_epi8(upper, a), _mm256_cmpeq_epi8(upper, c)),
+            _mm256_or_si256(_mm256_cmpeq_epi8(upper, g), _mm256_cmpeq_epi8(upper, t)),
+        );
+        let codes = _mm256_and_si256(
+            _mm256_xor_si256(_mm256_srli_epi16(bases, 1), _mm256_srli_epi16(bases, 2)),
+            three,
+        );
+        let codes = _mm256_and_si256(codes, valid);
+
+        let pairs = _mm256_maddubs_epi16(codes, pair_weights);
+        let quads = _mm256_madd_epi16(pairs, quad_weights);
+        let packed =
+            _mm256_permutevar8x32_epi32(_mm256_shuffle_epi8(quads, gather_bytes), gather_dwords);
+        words[chunk] = _mm_cvtsi128_si64(_mm256_castsi256_si128(packed)) as u64;
+
+        let n_bits = !(_mm256_movemask_epi8(valid) as u32) as u64;
+        n_mask[chunk / 2] |= n_bits << (32 * (chunk % 2));
+    }
+    encode_scalar(seq, n_chunks * 32, words, n_mask);
+}
+
+/// reverse the 2-bit groups of a word and complement them
+#[inline]
+fn reverse_complement_word(word: u64) -> u64 {
+    let word = word.swap_bytes();
+    let word = ((word >> 4) & 0x0F0F_0F0F_0F0F_0F0F) | ((word & 0x0F0F_0F0F_0F0F_0F0F) << 4);
+    let word = ((word >> 2) & 0x3333_3333_3333_3333) | ((word & 0x3333_3333_3333_3333) << 2);
+    !word
+}
+
+/// out[out.len() - 1 - i] = reverse_complement_word(words[i]), for i >= start
+fn reverse_complement_words_scalar(words: &[u64], start: usize, out: &mut [u64]) {
+    let n = words.len();
+    for (i, &word) in words.iter().enumerate().skip(start) {
+        out[n - 1 - i] = reverse_complement_word(word);
+    }
+}
+
+/// 4 words per iteration: bytes reversed by a shuffle in each 64-bit lane,
+/// the lanes reversed by a permute
+#[target_feature(enable = "avx2")]
+unsafe fn reverse_complement_words_avx2(words: &[u64], out: &mut [u64]) {
+    let n = words.len();
+    let n_chunks = n / 4;
+    let reverse_bytes = _mm256_setr_epi8(
+        7, 6, 5, 4, 3, 2, 1, 0, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 15, 14,
+        13, 12, 11, 10, 9, 8,
+    );
+    let mask4 = _mm256_set1_epi8(0x0F);
+    let mask2 = _mm256_set1_epi8(0x33);
+    let ones = _mm256_set1_epi8(-1);
+    for chunk in 0..n_chunks {
+        let v = _mm256_loadu_si256(words.as_ptr().add(chunk * 4) as *const __m256i);
+        let v = _mm256_shuffle_epi8(v, reverse_bytes);
+        let v = _mm256_or_si256(
+            _mm256_and_si256(_mm256_srli_epi64(v, 4), mask4),
+            _mm256_slli_epi64(_mm256_and_si256(v, mask4), 4),
+        );
+        let v = _mm256_or_si256(
+            _mm256_and_si256(_mm256_srli_epi64(v, 2), mask2),
+            _mm256_slli_epi64(_mm256_and_si256(v, mask2), 2),
+        );
+        let v = _mm256_xor_si256(v, ones);
+        let v = _mm256_permute4x64_epi64::<0b00_01_10_11>(v);
+        _mm256_storeu_si256(out.as_mut_ptr().add(n - 4 - chunk * 4) as *mut __m256i, v);
+    }
+    reverse_complement_words_scalar(words, n_chunks * 4, out);
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::dna::{hash64, kmer_mask, reverse_complement};
+
+    use super::{
+        encode_scalar, reverse_complement_words_avx2, reverse_complement_words_scalar, PackedSeq,
+    };
+
+    fn random_seq(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| b"ACGTacgtNR"[rng.gen_range(0..10)]).collect()
+    }
+
+    fn upper_n(seq: &[u8]) -> Vec<u8> {
+        seq.iter()
+            .map(|v| match v.to_ascii_uppercase() {
+                b @ (b'A' | b'C' | b'G' | b'T') => b,
+                _ => b'N',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_packed_seq() {
+        let seq = PackedSeq::from_ascii(b"ACGTNacgtn");
+        assert_eq!(seq.len(), 10);
+        assert_eq!(seq.to_string(), "ACGTNACGTN");
+        assert_eq!(seq.code(1), Some(1));
+        assert_eq!(seq.code(4), None);
+        assert_eq!(seq.n_count(), 2);
+        assert_eq!(seq.words(), &[0b11_10_01_00_00_11_10_01_00]);
+        assert_eq!(seq.slice(2, 7).to_string(), "GTNAC");
+        assert_eq!(seq.reverse_complement().to_string(), "NACGTNACGT");
+
+        let mut pushed = PackedSeq::new();
+        b"ACGTNacgtn".iter().for_each(|&v| pushed.push(v));
+        assert_eq!(pushed, seq);
+        assert_eq!(PackedSeq::from_ascii(b"").reverse_complement(), PackedSeq::new());
+        assert_eq!(
+            PackedSeq::from_ascii(b"AN").reverse_complement(),
+            PackedSeq::from_ascii(b"NT")
+        );
+
+        // raw bytes 0..=3 are not bases, same on the scalar and avx2 paths
+        let raw = [0u8, 1, 2, 3].repeat(10);
+        assert_eq!(PackedSeq::from_ascii(&raw).n_count(), 40);
+        let mut words = vec![0; 2];
+        let mut n_mask = vec![0; 1];
+        encode_scalar(&raw, 0, &mut words, &mut n_mask);
+        assert_eq!(n_mask, PackedSeq::from_ascii(&raw).n_mask);
+    }
+
+    #[test]
+    fn test_packed_seq_randomized() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for len in (0..300).chain([1000, 4097]) {
+            let raw = random_seq(&mut rng, len);
+            let expected = upper_n(&raw);
+            let seq = PackedSeq::from_ascii(&raw);
+            assert_eq!(seq.to_ascii(), expected);
+
+            let mut words = vec![0; seq.words().len()];
+            let mut n_mask = vec![0; len.div_ceil(64)];
+            encode_scalar(&raw, 0, &mut words, &mut n_mask);
+            assert_eq!(words.as_slice(), seq.words());
+            assert_eq!(n_mask, seq.n_mask);
+
+            let rc = seq.reverse_complement();
+            assert_eq!(rc.to_ascii(), reverse_complement(&expected));
+            assert_eq!(rc.reverse_complement(), seq);
+
+            // N codes must be cleared, or Eq and Hash see them
+            assert_eq!(rc, PackedSeq::from_ascii(&rc.to_ascii()));
+
+            if is_x86_feature_detected!("avx2") {
+                let mut avx2_out = vec![0; words.len()];
+                let mut scalar_out = vec![0; words.len()];
+                unsafe { reverse_complement_words_avx2(&words, &mut avx2_out) };
+                reverse_complement_words_scalar(&words, 0, &mut scalar_out);
+                assert_eq!(avx2_out, scalar_out);
+            }
+
+            if len > 0 {
+                let start = rng.gen_range(0..len);
+                let end = rng.gen_range(start..=len);
+                let sliced = seq.slice(start, end);
+                assert_eq!(sliced.to_ascii(), &expected[start..end]);
+                assert_eq!(sliced, PackedSeq::from_ascii(&expected[start..end]));
+                let rc_sliced = rc.slice(len - end, len - start);
+                assert_eq!(rc_sliced, sliced.reverse_complement());
+            }
+        }
+    }
+
+    #[test]
+    fn test_kmers() {
+        let seq = PackedSeq::from_ascii(b"ACGTNACGTA");
+        let kmers = seq.kmers(3).collect::<Vec<_>>();
+        assert_eq!(
+            kmers,
+            vec![
+                (0, 0b00_01_10),
+                (1, 0b01_10_11),
+                (5, 0b00_01_10),
+                (6, 0b01_10_11),
+                (7, 0b10_11_00)
+            ]
+        );
+        assert_eq!(seq.kmers(5).count(), 1);
+        assert_eq!(seq.kmers(11).count(), 0);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let raw = (0..100).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect::<Vec<_>>();
+        let kmers = PackedSeq::from_ascii(&raw).kmers(32).collect::<Vec<_>>();
+        assert_eq!(kmers.len(), 69);
+        assert_eq!(kmers[10].1, PackedSeq::from_ascii(&raw[10..42]).kmers(32).next().unwrap().1);
+
+        let mask = kmer_mask(15);
+        let hashes = (0..1000u64)
+            .map(|v| hash64(v, mask))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(hashes.len(), 1000);
+        assert!(hashes.iter().all(|&v| v <= mask));
+    }
+}
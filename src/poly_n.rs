@@ -1,6 +1,21 @@
-use rust_htslib::bam::ext::BamRecordExtensions;
-
-use crate::gsbam::bam_record_ext::{BamRecord, BamRecordExt};
+use std::{
+    cmp,
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use rust_htslib::{bgzf, faidx};
+
+use crate::{
+    file_reader::bed_reader::{build_bed_tabix_index, BedReader, BedRecord},
+    gsbam::{
+        bam_record_ext::{BamRecord, BamRecordExt},
+        cigar_ext::RefPosMapping,
+    },
+};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct RefPolyLocusInfo {
@@ -45,9 +60,51 @@ impl RefPolyLocusInfo {
     }
 }
 
+/// cursor over the sorted, non-overlapping poly regions (find_poly_n_regions).
+/// it only moves forward, so each region is visited once along the alignment
+#[derive(Debug, Clone)]
+pub struct PolyRegionCursor<'a> {
+    regions: &'a [(usize, usize, u8)],
+    idx: usize,
+}
+
+impl<'a> PolyRegionCursor<'a> {
+    pub fn new(regions: &'a [(usize, usize, u8)]) -> Self {
+        Self { regions, idx: 0 }
+    }
+
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    /// None if all the regions are visited
+    pub fn current(&self) -> Option<&'a (usize, usize, u8)> {
+        self.regions.get(self.idx)
+    }
+
+    pub fn advance(&mut self) -> Option<&'a (usize, usize, u8)> {
+        self.idx = (self.idx + 1).min(self.regions.len());
+        self.current()
+    }
+
+    /// move to the first region whose end > pos, the region contains pos or is on the right of pos.
+    /// never moves back, a pos before the current region keeps the cursor
+    pub fn seek(&mut self, pos: usize) -> Option<&'a (usize, usize, u8)> {
+        self.idx += self.regions[self.idx..].partition_point(|region| region.1 <= pos);
+        self.current()
+    }
+
+    pub fn relation(&self, pos: usize) -> Option<PosRelation> {
+        self.current().map(|region| position_relation(region, pos))
+    }
+}
+
+/// the query sequence of each poly region fully covered by the record.
+/// the insertions right before a region and inside it are included.
+/// None if no region starts at or after the alignment start
 pub fn extract_poly_locus_info_from_record(
     record: &BamRecord,
-    ref_poly_region: &Vec<(usize, usize, u8)>,
+    ref_poly_region: &[(usize, usize, u8)],
 ) -> Option<Vec<RefPolyLocusInfo>> {
     // if record.is_secondary() || record.is_unmapped() || record.is_supplementary() {
     //     continue;
@@ -59,38 +116,29 @@ pub fn extract_poly_locus_info_from_record(
 
     let query_end = record_ext.query_alignment_end() as i64;
 
-    let mut rpos_cursor = None;
-    let mut qpos_cursor = None;
-
     let query_seq = record.seq().as_bytes();
     let query_str = unsafe { String::from_utf8_unchecked(query_seq.clone()) };
 
     let mut poly_info = vec![];
 
-    let aligned_pairs_full: Vec<[Option<i64>; 2]> =
-        record.aligned_pairs_full().into_iter().collect::<Vec<_>>();
-
-    let mut cur_poly_region_idx =
-        if let Some(poly_idx) = move_poly_idx(ref_poly_region, 0, ref_start as usize) {
-            if (ref_start as usize) > ref_poly_region[poly_idx].0 {
-                poly_idx + 1
-            } else {
-                poly_idx
-            }
-        } else {
-            return None;
-        };
-
-    if cur_poly_region_idx >= ref_poly_region.len() {
-        return None;
+    let mut cursor = PolyRegionCursor::new(ref_poly_region);
+    // the region containing ref_start is not fully covered
+    if cursor.seek(ref_start as usize)?.0 < ref_start as usize {
+        cursor.advance();
     }
+    let first_region = cursor.current()?;
 
     let mut query_poly_seq = String::new();
 
-    for [qpos, rpos] in aligned_pairs_full.iter() {
-        let qpos = *qpos;
-        let rpos = *rpos;
+    // the pairs before the first poly region are useless, except the insertion right before the region
+    let cigar_index = record_ext.cigar_index();
+    let first_rpos = cmp::max(ref_start as usize, first_region.0.saturating_sub(1));
+    let mut rpos_cursor = None;
+    let mut qpos_cursor = cigar_index
+        .query_pos_before_ref(first_rpos)
+        .map(|v| v as i64);
 
+    for [qpos, rpos] in cigar_index.aligned_pairs_from_ref(first_rpos) {
         if qpos.is_some() {
             qpos_cursor = qpos;
         }
@@ -119,7 +167,7 @@ pub fn extract_poly_locus_info_from_record(
 
         // 收尾
         if let Some(rpos_) = rpos.map(|v| v as usize) {
-            let cur_poly_region = unsafe { ref_poly_region.get_unchecked(cur_poly_region_idx) };
+            let cur_poly_region = cursor.current().unwrap();
             if rpos_ == (cur_poly_region.1 - 1) {
                 if let Some(qpos_) = qpos.map(|v| v as usize) {
                     query_poly_seq.push_str(&query_str[qpos_..qpos_ + 1]);
@@ -139,8 +187,7 @@ pub fn extract_poly_locus_info_from_record(
                 ));
 
                 // move
-                cur_poly_region_idx += 1;
-                if cur_poly_region_idx >= ref_poly_region.len() {
+                if cursor.advance().is_none() {
                     break;
                 }
 
@@ -148,7 +195,7 @@ pub fn extract_poly_locus_info_from_record(
             }
         }
 
-        let cur_region = unsafe { ref_poly_region.get_unchecked(cur_poly_region_idx) };
+        let cur_region = cursor.current().unwrap();
         // region 开始 & 持续
         let rpos_cur_or_pre = rpos_cursor.unwrap() as usize;
 
@@ -173,22 +220,6 @@ pub fn extract_poly_locus_info_from_record(
     Some(poly_info)
 }
 
-fn move_poly_idx(
-    ref_poly_region: &Vec<(usize, usize, u8)>,
-    cur_idx: usize,
-    cur_pos: usize,
-) -> Option<usize> {
-    for idx in cur_idx..ref_poly_region.len() {
-        match position_relation(&ref_poly_region[cur_idx], cur_pos) {
-            PosRelation::Left => return Some(idx),
-            PosRelation::Middle => return Some(idx),
-            PosRelation::Right => (),
-        }
-    }
-
-    return None;
-}
-
 pub fn find_poly_n_regions(sequence: &[u8]) -> Vec<(usize, usize, u8)> {
     if sequence.is_empty() {
         return Vec::new();
@@ -218,6 +249,147 @@ pub fn find_poly_n_regions(sequence: &[u8]) -> Vec<(usize, usize, u8)> {
     regions
 }
 
+/// configurable homopolymer finder.
+/// find_poly_n_regions reports every run of any byte with length >= 2
+#[derive(Debug, Clone)]
+pub struct PolyNParams {
+    /// runs shorter than this are not reported
+    pub min_len: usize,
+    /// only the runs of these bases are reported
+    pub bases: Vec<u8>,
+    /// soft masked (lowercase) bases join the runs of the uppercase base.
+    /// the reported base is uppercase
+    pub case_insensitive: bool,
+    /// report the runs of N. assembly gaps are long N runs, so it is off by default
+    pub include_n: bool,
+}
+
+impl Default for PolyNParams {
+    fn default() -> Self {
+        Self {
+            min_len: 3,
+            bases: b"ACGT".to_vec(),
+            case_insensitive: true,
+            include_n: false,
+        }
+    }
+}
+
+impl PolyNParams {
+    fn normalize(&self, base: u8) -> u8 {
+        if self.case_insensitive {
+            base.to_ascii_uppercase()
+        } else {
+            base
+        }
+    }
+
+    fn is_reported_base(&self, base: u8) -> bool {
+        if base.eq_ignore_ascii_case(&b'N') {
+            self.include_n
+        } else {
+            self.bases.iter().any(|&v| self.normalize(v) == base)
+        }
+    }
+
+    /// (start, end, base) of the runs, sorted by start
+    pub fn find_regions(&self, sequence: &[u8]) -> Vec<(usize, usize, u8)> {
+        let mut regions = vec![];
+        let mut start = 0;
+        while start < sequence.len() {
+            let base = self.normalize(sequence[start]);
+            let mut end = start + 1;
+            while end < sequence.len() && self.normalize(sequence[end]) == base {
+                end += 1;
+            }
+            if end - start >= self.min_len.max(1) && self.is_reported_base(base) {
+                regions.push((start, end, base));
+            }
+            start = end;
+        }
+        regions
+    }
+}
+
+/// (start, end, base), 0-based half open
+pub type PolyRegion = (usize, usize, u8);
+
+/// homopolymers of every contig of the fasta, in the order of the fasta index.
+/// the .fai index is built if it does not exist
+pub fn poly_n_catalog_from_fasta(
+    ref_fasta: &str,
+    params: &PolyNParams,
+) -> anyhow::Result<Vec<(String, Vec<PolyRegion>)>> {
+    if !Path::new(&format!("{}.fai", ref_fasta)).exists() {
+        faidx::build(ref_fasta)
+            .map_err(|e| anyhow::anyhow!("build fasta index for {} error: {}", ref_fasta, e))?;
+    }
+    let reader = faidx::Reader::from_path(ref_fasta)
+        .with_context(|| format!("open fasta {} error", ref_fasta))?;
+    let mut catalog = vec![];
+    for name in reader.seq_names()? {
+        let len = reader.fetch_seq_len(&name) as usize;
+        let regions = if len == 0 {
+            vec![]
+        } else {
+            params.find_regions(&reader.fetch_seq(&name, 0, len - 1)?)
+        };
+        catalog.push((name, regions));
+    }
+    Ok(catalog)
+}
+
+/// write the catalog as BED5: chrom start end base length.
+/// a path ending with .gz is bgzip compressed and tabix indexed
+pub fn write_poly_n_catalog<P: AsRef<Path>>(
+    path: P,
+    catalog: &[(String, Vec<PolyRegion>)],
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let compressed = path.extension().is_some_and(|ext| ext == "gz");
+    {
+        let mut writer: Box<dyn Write> = if compressed {
+            Box::new(bgzf::Writer::from_path(path)?)
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        for (chrom, regions) in catalog {
+            for &(start, end, base) in regions {
+                let mut record = BedRecord::new(chrom.clone(), start, end);
+                record.name = Some((base as char).to_string());
+                record.score = Some((end - start) as f64);
+                record.n_columns = 5;
+                writeln!(writer, "{}", record)?;
+            }
+        }
+        writer.flush()?;
+    }
+    if compressed {
+        build_bed_tabix_index(path)?;
+    }
+    Ok(())
+}
+
+/// read a catalog written by write_poly_n_catalog. contig -> regions sorted by start
+pub fn read_poly_n_catalog<P: AsRef<Path>>(
+    path: P,
+) -> anyhow::Result<HashMap<String, Vec<PolyRegion>>> {
+    let mut catalog: HashMap<String, Vec<PolyRegion>> = HashMap::new();
+    for record in BedReader::from_path(path)? {
+        let record = record?;
+        let base = match record.name.as_deref().map(|v| v.as_bytes()) {
+            Some(&[base]) => base,
+            _ => bail!("invalid poly n catalog record: {}", record),
+        };
+        catalog
+            .entry(record.chrom)
+            .or_default()
+            .push((record.start, record.end, base));
+    }
+    catalog.values_mut().for_each(|v| v.sort_unstable());
+    Ok(catalog)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum PosRelation {
     Left,
@@ -235,9 +407,173 @@ pub fn position_relation(seb: &(usize, usize, u8), pos: usize) -> PosRelation {
     };
 }
 
+/// a primitive motif is not a repeat of a shorter motif. ATAT is not primitive
+fn is_primitive_motif(motif: &[u8]) -> bool {
+    (1..motif.len())
+        .filter(|period| motif.len().is_multiple_of(*period))
+        .all(|period| motif.chunks(period).any(|chunk| chunk != &motif[..period]))
+}
+
+/// tandem repeats of period in [min_period, max_period] with at least min_copies full copies.
+/// returns (start, end, motif, copies) sorted by start, end = start + copies * period.
+/// the partial copy at the end is not included. motifs containing non-ACGT bases are ignored,
+/// and a region is only reported at its smallest period (ATATAT is (AT)x3, not (ATAT)x1).
+/// the motif is in the phase of the leftmost copy, GCAGCAGCAG is (GCA)x3.
+/// repeats of different periods may overlap
+pub fn find_tandem_repeats(
+    sequence: &[u8],
+    min_period: usize,
+    max_period: usize,
+    min_copies: usize,
+) -> Vec<(usize, usize, String, usize)> {
+    let sequence = sequence.to_ascii_uppercase();
+    let same = |i: usize, j: usize| {
+        sequence[i] == sequence[j] && matches!(sequence[i], b'A' | b'C' | b'G' | b'T')
+    };
+
+    let mut repeats = vec![];
+    for period in min_period.max(1)..=max_period {
+        let mut j = period;
+        while j < sequence.len() {
+            if !same(j, j - period) {
+                j += 1;
+                continue;
+            }
+            let start = j - period;
+            let mut end = j + 1;
+            while end < sequence.len() && same(end, end - period) {
+                end += 1;
+            }
+            let copies = (end - start) / period;
+            let motif = &sequence[start..start + period];
+            if copies >= min_copies.max(2) && is_primitive_motif(motif) {
+                repeats.push((
+                    start,
+                    start + copies * period,
+                    String::from_utf8(motif.to_vec()).unwrap(),
+                    copies,
+                ));
+            }
+            j = end + 1;
+        }
+    }
+    repeats.sort_by_key(|v| (v.0, v.1));
+    repeats
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RefTandemRepeatInfo {
+    pub rstart: usize,
+    pub rend: usize,
+    pub qstart: usize,
+    pub qend: usize,
+    pub motif: String,
+    pub ref_copies: usize,
+    /// non-overlapping occurrences of the motif in qseq
+    pub query_copies: usize,
+    pub qseq: String,
+    /// qseq is exactly the motif repeated query_copies times
+    pub query_clean: bool,
+}
+
+/// the query sequence of each tandem repeat (find_tandem_repeats) covered by the record.
+/// qseq includes the insertions right before and right after the repeat.
+/// only the repeats with an aligned or deleted base at each side are reported
+pub fn extract_tandem_repeat_info_from_record(
+    record: &BamRecord,
+    repeats: &[(usize, usize, String, usize)],
+) -> Vec<RefTandemRepeatInfo> {
+    let record_ext = BamRecordExt::new(record);
+    let ref_start = record_ext.reference_start();
+    let ref_end = record_ext.reference_end();
+    let cigar_index = record_ext.cigar_index();
+    let query_seq = record.seq().as_bytes();
+
+    let first = repeats.partition_point(|v| v.0 <= ref_start);
+    repeats[first..]
+        .iter()
+        .take_while(|v| v.0 < ref_end)
+        .filter(|v| v.1 < ref_end)
+        .filter_map(|(rstart, rend, motif, copies)| {
+            let qstart = match cigar_index.ref_pos_to_query_pos(rstart - 1) {
+                RefPosMapping::Aligned { qpos, .. } => qpos + 1,
+                RefPosMapping::Deleted { qpos } => qpos,
+                RefPosMapping::Outside => return None,
+            };
+            let qend = match cigar_index.ref_pos_to_query_pos(*rend) {
+                RefPosMapping::Aligned { qpos, .. } | RefPosMapping::Deleted { qpos } => qpos,
+                RefPosMapping::Outside => return None,
+            };
+            let qseq = String::from_utf8_lossy(&query_seq[qstart..qend]).to_string();
+            let query_copies = qseq.matches(motif.as_str()).count();
+            Some(RefTandemRepeatInfo {
+                rstart: *rstart,
+                rend: *rend,
+                qstart,
+                qend,
+                motif: motif.clone(),
+                ref_copies: *copies,
+                query_copies,
+                query_clean: query_copies * motif.len() == qseq.len(),
+                qseq,
+            })
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    use crate::poly_n::find_poly_n_regions;
+    use std::collections::{HashMap, HashSet};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        gsbam::{bam_record_ext::BamRecord, test_utils::RecordBuilder},
+        poly_n::{
+            extract_poly_locus_info_from_record, extract_tandem_repeat_info_from_record,
+            find_poly_n_regions, find_tandem_repeats, is_primitive_motif,
+            poly_n_catalog_from_fasta, read_poly_n_catalog, write_poly_n_catalog, PolyNParams,
+            PolyRegionCursor, PosRelation, RefPolyLocusInfo,
+        },
+    };
+
+    #[test]
+    fn test_extract_poly_locus_info_from_record() {
+        // ref:   ACCCGT-TA
+        // query: ACC-GTTTA
+        let ref_seq = b"ACCCGTTA";
+        let record = RecordBuilder::new("3=1D2=1I2=", "ACCGTTTA").pos(0).build();
+
+        let poly_regions = find_poly_n_regions(ref_seq);
+        let infos = extract_poly_locus_info_from_record(&record, &poly_regions).unwrap();
+        assert_eq!(
+            infos,
+            vec![
+                RefPolyLocusInfo {
+                    rstart: 1,
+                    rend: 4,
+                    qstart: 1,
+                    qend: 3,
+                    ref_base: 'C',
+                    ref_repeats: 3,
+                    query_repeats: 2,
+                    qseq: "CC".to_string(),
+                    query_clean: true
+                },
+                RefPolyLocusInfo {
+                    rstart: 5,
+                    rend: 7,
+                    qstart: 4,
+                    qend: 7,
+                    ref_base: 'T',
+                    ref_repeats: 2,
+                    query_repeats: 3,
+                    qseq: "TTT".to_string(),
+                    query_clean: true
+                }
+            ]
+        );
+    }
 
     #[test]
     fn test_find_homopolymer_regions() {
@@ -258,4 +594,294 @@ mod test {
         // println!("{:?}", res);
         assert_eq!(res, vec![(0, 2, 65), (3, 5, 71)]);
     }
+
+    #[test]
+    fn test_find_tandem_repeats() {
+        assert!(is_primitive_motif(b"AC"));
+        assert!(is_primitive_motif(b"AAC"));
+        assert!(!is_primitive_motif(b"ATAT"));
+        assert!(!is_primitive_motif(b"AA"));
+
+        //          0         1         2
+        //          012345678901234567890123456
+        let seq = b"GATATATCAGCAGCAGCTTTTACACNAC";
+        let res = find_tandem_repeats(seq, 2, 4, 3);
+        assert_eq!(
+            res,
+            vec![(1, 7, "AT".to_string(), 3), (7, 16, "CAG".to_string(), 3)]
+        );
+
+        // homopolymers with period 1, the partial copy is dropped
+        let res = find_tandem_repeats(seq, 1, 2, 2);
+        assert_eq!(res[0], (1, 7, "AT".to_string(), 3));
+        assert!(res.contains(&(17, 21, "T".to_string(), 4)));
+        assert!(res.contains(&(21, 25, "AC".to_string(), 2)));
+
+        assert_eq!(find_tandem_repeats(b"ATATCGCG", 2, 2, 2).len(), 2);
+        assert!(find_tandem_repeats(b"", 2, 4, 2).is_empty());
+    }
+
+    #[test]
+    fn test_extract_tandem_repeat_info_from_record() {
+        // ref:   TCAG--CAGCAGTC
+        // query: TCAGCAGCAG-AGTC
+        let ref_seq = b"TCAGCAGCAGTC";
+        let repeats = find_tandem_repeats(ref_seq, 2, 3, 3);
+        assert_eq!(repeats, vec![(1, 10, "CAG".to_string(), 3)]);
+
+        let record = RecordBuilder::new("4=3I8=", "TCAGCAGCAGCAGTC")
+            .pos(0)
+            .build();
+        let infos = extract_tandem_repeat_info_from_record(&record, &repeats);
+        assert_eq!(infos.len(), 1);
+        assert_eq!((infos[0].qstart, infos[0].qend), (1, 13));
+        assert_eq!(infos[0].qseq, "CAGCAGCAGCAG");
+        assert_eq!(infos[0].query_copies, 4);
+        assert!(infos[0].query_clean);
+
+        let mut record = RecordBuilder::new("7=1D4=", "TCAGCAGAGTC").pos(0).build();
+        let infos = extract_tandem_repeat_info_from_record(&record, &repeats);
+        assert_eq!(infos[0].qseq, "CAGCAGAG");
+        assert_eq!(infos[0].query_copies, 2);
+        assert!(!infos[0].query_clean);
+
+        // the repeat starts at the first aligned base, no flank
+        record.set_pos(1);
+        assert!(extract_tandem_repeat_info_from_record(&record, &repeats).is_empty());
+    }
+
+    #[test]
+    fn test_poly_region_cursor() {
+        let regions = vec![(2, 4, b'A'), (6, 9, b'C'), (12, 14, b'G')];
+        let mut cursor = PolyRegionCursor::new(&regions);
+        assert_eq!(cursor.seek(0), Some(&(2, 4, b'A')));
+        assert!(matches!(cursor.relation(0), Some(PosRelation::Left)));
+        assert_eq!(cursor.seek(3), Some(&(2, 4, b'A')));
+        assert_eq!(cursor.seek(4), Some(&(6, 9, b'C')));
+        // never moves back
+        assert_eq!(cursor.seek(1), Some(&(6, 9, b'C')));
+        assert_eq!(cursor.seek(13), Some(&(12, 14, b'G')));
+        assert!(matches!(cursor.relation(13), Some(PosRelation::Middle)));
+        assert_eq!(cursor.idx(), 2);
+        assert_eq!(cursor.advance(), None);
+        assert_eq!(cursor.advance(), None);
+        assert_eq!(cursor.idx(), 3);
+        assert_eq!(cursor.seek(20), None);
+        assert!(cursor.relation(20).is_none());
+    }
+
+    /// runs of 1-6 bases, adjacent runs have different bases
+    fn random_ref_seq(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        let mut seq = vec![];
+        while seq.len() < len {
+            let base = loop {
+                let base = b"ACGT"[rng.gen_range(0..4)];
+                if seq.last() != Some(&base) {
+                    break base;
+                }
+            };
+            let run = rng.gen_range(1..=6).min(len - seq.len());
+            seq.extend(std::iter::repeat_n(base, run));
+        }
+        seq
+    }
+
+    /// a read of ref_seq[rstart..rend] with a random insertion or deletion in the poly regions
+    /// that are not at the read ends. returns the record and the expected loci
+    fn simulate_poly_read(
+        rng: &mut StdRng,
+        ref_seq: &[u8],
+        regions: &[(usize, usize, u8)],
+        rstart: usize,
+        rend: usize,
+    ) -> (BamRecord, Vec<(RefPolyLocusInfo, usize)>) {
+        // rpos -> (n, inserted base)
+        let mut ins_before = HashMap::new();
+        let mut ins_after = HashMap::new();
+        let mut deleted = HashSet::new();
+        let mut substituted = HashMap::new();
+        // region start -> number of non region bases put into the query
+        let mut n_foreign = HashMap::new();
+        for region in regions.iter().filter(|r| r.0 > rstart && r.1 < rend) {
+            let foreign = loop {
+                let base = b"ACGT"[rng.gen_range(0..4)];
+                if base != region.2 {
+                    break base;
+                }
+            };
+            match rng.gen_range(0..6) {
+                0 => (),
+                1 => {
+                    ins_before.insert(region.0, (rng.gen_range(1..=3), region.2));
+                }
+                2 => {
+                    let rpos = rng.gen_range(region.0..region.1 - 1);
+                    ins_after.insert(rpos, (rng.gen_range(1..=3), region.2));
+                }
+                3 => {
+                    let n = rng.gen_range(1..=region.1 - region.0);
+                    let start = rng.gen_range(region.0..=region.1 - n);
+                    deleted.extend(start..start + n);
+                }
+                4 => {
+                    substituted.insert(rng.gen_range(region.0..region.1), foreign);
+                    n_foreign.insert(region.0, 1);
+                }
+                _ => {
+                    let n = rng.gen_range(1..=3);
+                    ins_after.insert(rng.gen_range(region.0..region.1 - 1), (n, foreign));
+                    n_foreign.insert(region.0, n);
+                }
+            }
+        }
+
+        let mut seq = vec![];
+        let mut cigar: Vec<(usize, char)> = vec![];
+        let push_op = |cigar: &mut Vec<(usize, char)>, op: char, n: usize| match cigar.last_mut() {
+            Some(last) if last.1 == op => last.0 += n,
+            _ => cigar.push((n, op)),
+        };
+        let mut expected = vec![];
+        let mut qstart = 0;
+        for (rpos, &base) in ref_seq.iter().enumerate().take(rend).skip(rstart) {
+            let region = regions
+                .iter()
+                .find(|r| r.0 <= rpos && rpos < r.1 && r.0 >= rstart && r.1 <= rend);
+            if region.is_some_and(|r| r.0 == rpos) {
+                qstart = seq.len();
+            }
+            if let Some(&(n, ins_base)) = ins_before.get(&rpos) {
+                seq.extend(std::iter::repeat_n(ins_base, n));
+                push_op(&mut cigar, 'I', n);
+            }
+            if deleted.contains(&rpos) {
+                push_op(&mut cigar, 'D', 1);
+            } else if let Some(&sub_base) = substituted.get(&rpos) {
+                seq.push(sub_base);
+                push_op(&mut cigar, 'X', 1);
+            } else {
+                seq.push(base);
+                push_op(&mut cigar, '=', 1);
+            }
+            if let Some(&(n, ins_base)) = ins_after.get(&rpos) {
+                seq.extend(std::iter::repeat_n(ins_base, n));
+                push_op(&mut cigar, 'I', n);
+            }
+            if let Some(r) = region.filter(|r| r.1 == rpos + 1) {
+                let info = RefPolyLocusInfo::new(
+                    r.0,
+                    r.1,
+                    qstart,
+                    seq.len(),
+                    r.2 as char,
+                    r.1 - r.0,
+                    String::from_utf8(seq[qstart..].to_vec()).unwrap(),
+                );
+                expected.push((info, n_foreign.get(&r.0).copied().unwrap_or(0)));
+            }
+        }
+
+        let cigar = cigar
+            .iter()
+            .map(|(n, op)| format!("{}{}", n, op))
+            .collect::<String>();
+        let record = RecordBuilder::new(&cigar, &seq).pos(rstart as i64).build();
+        (record, expected)
+    }
+
+    #[test]
+    fn test_extract_poly_locus_info_randomized() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..500 {
+            let ref_seq = random_ref_seq(&mut rng, 120);
+            let regions = find_poly_n_regions(&ref_seq);
+            let rstart = rng.gen_range(0..30);
+            let rend = rng.gen_range(90..=ref_seq.len());
+            let (record, expected) = simulate_poly_read(&mut rng, &ref_seq, &regions, rstart, rend);
+            let (expected, n_foreign): (Vec<_>, Vec<_>) = expected.into_iter().unzip();
+
+            let infos = extract_poly_locus_info_from_record(&record, &regions).unwrap_or_default();
+            assert_eq!(
+                infos,
+                expected,
+                "ref:{} rstart:{} cigar:{}",
+                String::from_utf8_lossy(&ref_seq),
+                rstart,
+                record.cigar()
+            );
+            for (info, n) in infos.iter().zip(n_foreign) {
+                assert_eq!(info.query_clean, n == 0, "{:?}", info);
+                assert_eq!(info.query_repeats + n, info.qseq.len(), "{:?}", info);
+            }
+        }
+    }
+
+    #[test]
+    fn test_poly_n_params() {
+        let seq = b"AACCCgggGTTNNNNaaa";
+        let params = PolyNParams::default();
+        assert_eq!(
+            params.find_regions(seq),
+            vec![(2, 5, b'C'), (5, 9, b'G'), (15, 18, b'A')]
+        );
+
+        let params = PolyNParams {
+            min_len: 2,
+            bases: b"AT".to_vec(),
+            case_insensitive: false,
+            include_n: true,
+        };
+        assert_eq!(
+            params.find_regions(seq),
+            vec![(0, 2, b'A'), (9, 11, b'T'), (11, 15, b'N')]
+        );
+
+        let params = PolyNParams {
+            bases: b"a".to_vec(),
+            case_insensitive: false,
+            ..Default::default()
+        };
+        assert_eq!(params.find_regions(seq), vec![(15, 18, b'a')]);
+        assert!(PolyNParams::default().find_regions(b"").is_empty());
+    }
+
+    #[test]
+    fn test_poly_n_catalog() {
+        let dir = std::env::temp_dir();
+        let fasta = dir.join(format!("gskits_poly_test_{}.fa", std::process::id()));
+        std::fs::write(
+            &fasta,
+            ">chr1\nACCCGTTTTA\nAAAG\n>chr2\nNNNNNacgt\n>chr3\nGGGG\n",
+        )
+        .unwrap();
+        let fasta = fasta.to_str().unwrap().to_string();
+
+        let catalog = poly_n_catalog_from_fasta(&fasta, &PolyNParams::default()).unwrap();
+        assert_eq!(
+            catalog,
+            vec![
+                (
+                    "chr1".to_string(),
+                    vec![(1, 4, b'C'), (5, 9, b'T'), (9, 13, b'A')]
+                ),
+                ("chr2".to_string(), vec![]),
+                ("chr3".to_string(), vec![(0, 4, b'G')]),
+            ]
+        );
+
+        for suffix in ["bed", "bed.gz"] {
+            let bed = format!("{}.poly.{}", fasta, suffix);
+            write_poly_n_catalog(&bed, &catalog).unwrap();
+            let read_back = read_poly_n_catalog(&bed).unwrap();
+            assert_eq!(read_back.len(), 2);
+            assert_eq!(read_back["chr1"], catalog[0].1);
+            assert_eq!(read_back["chr3"], catalog[2].1);
+            std::fs::remove_file(&bed).unwrap();
+            if suffix == "bed.gz" {
+                std::fs::remove_file(format!("{}.tbi", bed)).unwrap();
+            }
+        }
+        std::fs::remove_file(&fasta).unwrap();
+        std::fs::remove_file(format!("{}.fai", fasta)).unwrap();
+    }
 }